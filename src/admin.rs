@@ -0,0 +1,389 @@
+use std::sync::Arc;
+
+use axum::{
+   Json,
+   Router,
+   extract::{
+      Path,
+      State,
+   },
+   http::{
+      HeaderMap,
+      StatusCode,
+      header,
+   },
+   response::IntoResponse,
+   routing::{
+      delete,
+      get,
+      post,
+   },
+};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{
+   error,
+   info,
+};
+
+use crate::{
+   api::StatusResponse,
+   crypto,
+   db::{
+      Db,
+      UserStatus,
+      UserSummary,
+   },
+   http_client::HttpClient,
+   metrics::Metrics,
+   twitter::Notification,
+   txid::TxIdGenerator,
+   unified_push,
+   vapid::Vapid,
+};
+
+pub struct AdminState {
+   pub db:             Arc<Db>,
+   pub client:         Arc<HttpClient>,
+   pub vapid:          Arc<Vapid>,
+   pub metrics:        Arc<Metrics>,
+   /// Read for the age of its keys in the metrics
+   pub txid_generator: Arc<TxIdGenerator>,
+   /// Required as bearer token when set
+   pub token:          Option<String>,
+   /// Asks the poller to poll a user by ID right away
+   pub force_poll:     mpsc::UnboundedSender<i64>,
+}
+
+#[derive(Serialize)]
+pub struct UsersResponse {
+   users: Vec<UserInfo>,
+}
+
+/// A user as shown to operators. Credentials and push endpoints are left
+/// out, since either is enough to act as the user.
+#[derive(Serialize)]
+pub struct UserInfo {
+   id:                   i64,
+   twitter_user_id:      String,
+   status:               &'static str,
+   auth_failures:        u32,
+   last_poll_at:         Option<i64>,
+   last_poll_error:      Option<String>,
+   device_count:         u32,
+   enabled_device_count: u32,
+   created_at:           i64,
+}
+
+impl From<UserSummary> for UserInfo {
+   fn from(user: UserSummary) -> Self {
+      Self {
+         id:                   user.id,
+         twitter_user_id:      user.twitter_user_id,
+         status:               user.status.as_str(),
+         auth_failures:        user.auth_failures,
+         last_poll_at:         user.last_poll_at,
+         last_poll_error:      user.last_poll_error,
+         device_count:         user.device_count,
+         enabled_device_count: user.enabled_device_count,
+         created_at:           user.created_at,
+      }
+   }
+}
+
+#[derive(Serialize)]
+pub struct TestPushResponse {
+   devices: Vec<TestPushResult>,
+}
+
+#[derive(Serialize)]
+pub struct TestPushResult {
+   device_id: i64,
+   delivered: bool,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   error:     Option<String>,
+}
+
+type AdminError = (StatusCode, Json<StatusResponse>);
+
+pub fn router(state: Arc<AdminState>) -> Router {
+   Router::new()
+      .route("/admin/users", get(list_users))
+      .route("/admin/users/{twitter_user_id}", delete(delete_user))
+      .route("/admin/users/{twitter_user_id}/disable", post(disable_user))
+      .route("/admin/users/{twitter_user_id}/enable", post(enable_user))
+      .route("/admin/users/{twitter_user_id}/poll", post(force_poll))
+      .route("/admin/users/{twitter_user_id}/test-push", post(test_push))
+      .with_state(state)
+}
+
+/// Metrics for monitoring to scrape. Served on their own listener without
+/// the admin token, since scrapers rarely send one.
+pub fn metrics_router(state: Arc<AdminState>) -> Router {
+   Router::new()
+      .route("/metrics", get(metrics))
+      .with_state(state)
+}
+
+/// Check the admin token, if one is configured
+fn authorize(state: &AdminState, headers: &HeaderMap) -> Result<(), AdminError> {
+   let Some(expected) = &state.token else {
+      return Ok(());
+   };
+
+   let token = headers
+      .get(header::AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("Bearer "))
+      .unwrap_or("");
+
+   if !crypto::constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+      return Err((
+         StatusCode::UNAUTHORIZED,
+         Json(StatusResponse::error("Invalid admin token")),
+      ));
+   }
+
+   Ok(())
+}
+
+fn database_error(e: impl std::fmt::Display) -> AdminError {
+   error!("Database error: {e}");
+   (
+      StatusCode::INTERNAL_SERVER_ERROR,
+      Json(StatusResponse::error("Database error")),
+   )
+}
+
+fn find_user(state: &AdminState, twitter_user_id: &str) -> Result<UserSummary, AdminError> {
+   state
+      .db
+      .get_user_summary(twitter_user_id)
+      .map_err(database_error)?
+      .ok_or_else(|| {
+         (
+            StatusCode::NOT_FOUND,
+            Json(StatusResponse::error("User not found")),
+         )
+      })
+}
+
+async fn list_users(State(state): State<Arc<AdminState>>, headers: HeaderMap) -> impl IntoResponse {
+   if let Err(e) = authorize(&state, &headers) {
+      return e.into_response();
+   }
+
+   match state.db.get_user_summaries() {
+      Ok(users) => {
+         let users = users.into_iter().map(UserInfo::from).collect();
+         (StatusCode::OK, Json(UsersResponse { users })).into_response()
+      },
+      Err(e) => database_error(e).into_response(),
+   }
+}
+
+async fn disable_user(
+   State(state): State<Arc<AdminState>>,
+   headers: HeaderMap,
+   Path(twitter_user_id): Path<String>,
+) -> Result<Json<UserInfo>, AdminError> {
+   set_status(&state, &headers, &twitter_user_id, UserStatus::Disabled)
+}
+
+/// Resume polling, also of users whose session was rejected, for when an
+/// operator knows it works again
+async fn enable_user(
+   State(state): State<Arc<AdminState>>,
+   headers: HeaderMap,
+   Path(twitter_user_id): Path<String>,
+) -> Result<Json<UserInfo>, AdminError> {
+   set_status(&state, &headers, &twitter_user_id, UserStatus::Active)
+}
+
+fn set_status(
+   state: &AdminState,
+   headers: &HeaderMap,
+   twitter_user_id: &str,
+   status: UserStatus,
+) -> Result<Json<UserInfo>, AdminError> {
+   authorize(state, headers)?;
+   let user = find_user(state, twitter_user_id)?;
+
+   state
+      .db
+      .set_user_status(user.id, status)
+      .map_err(database_error)?;
+   if status == UserStatus::Active {
+      state
+         .db
+         .reset_auth_failures(user.id)
+         .map_err(database_error)?;
+   }
+
+   info!(user = user.id, "Set status to {}", status.as_str());
+
+   let user = find_user(state, twitter_user_id)?;
+   Ok(Json(user.into()))
+}
+
+async fn force_poll(
+   State(state): State<Arc<AdminState>>,
+   headers: HeaderMap,
+   Path(twitter_user_id): Path<String>,
+) -> impl IntoResponse {
+   if let Err(e) = authorize(&state, &headers) {
+      return e;
+   }
+   let user = match find_user(&state, &twitter_user_id) {
+      Ok(user) => user,
+      Err(e) => return e,
+   };
+
+   if user.status != UserStatus::Active {
+      return (
+         StatusCode::CONFLICT,
+         Json(StatusResponse::error(format!(
+            "User is {}, not polled",
+            user.status.as_str()
+         ))),
+      );
+   }
+
+   if state.force_poll.send(user.id).is_err() {
+      return (
+         StatusCode::SERVICE_UNAVAILABLE,
+         Json(StatusResponse::error("Poller isn't running")),
+      );
+   }
+
+   info!(user = user.id, "Forced a poll");
+   (StatusCode::ACCEPTED, Json(StatusResponse::ok()))
+}
+
+/// Push a test notification to each of the user's enabled devices
+async fn test_push(
+   State(state): State<Arc<AdminState>>,
+   headers: HeaderMap,
+   Path(twitter_user_id): Path<String>,
+) -> impl IntoResponse {
+   if let Err(e) = authorize(&state, &headers) {
+      return e.into_response();
+   }
+   let user = match find_user(&state, &twitter_user_id) {
+      Ok(user) => user,
+      Err(e) => return e.into_response(),
+   };
+
+   let devices = match state.db.get_devices(user.id) {
+      Ok(devices) => devices,
+      Err(e) => return database_error(e).into_response(),
+   };
+
+   let notif = Notification::test();
+   let mut results = Vec::new();
+
+   for device in devices.iter().filter(|d| d.is_enabled()) {
+      let result = unified_push::send(&state.client, &state.vapid, device, &notif).await;
+
+      results.push(TestPushResult {
+         device_id: device.id,
+         delivered: result.is_ok(),
+         error:     result.err().map(|e| e.to_string()),
+      });
+   }
+
+   info!(
+      user = user.id,
+      "Sent test push to {} devices",
+      results.len()
+   );
+   (StatusCode::OK, Json(TestPushResponse { devices: results })).into_response()
+}
+
+async fn delete_user(
+   State(state): State<Arc<AdminState>>,
+   headers: HeaderMap,
+   Path(twitter_user_id): Path<String>,
+) -> impl IntoResponse {
+   if let Err(e) = authorize(&state, &headers) {
+      return e;
+   }
+   let user = match find_user(&state, &twitter_user_id) {
+      Ok(user) => user,
+      Err(e) => return e,
+   };
+
+   match state.db.unregister_user(&twitter_user_id) {
+      Ok(_) => {
+         info!(user = user.id, "Deleted user");
+         (StatusCode::OK, Json(StatusResponse::ok()))
+      },
+      Err(e) => database_error(e),
+   }
+}
+
+/// Metrics in the Prometheus text format
+async fn metrics(State(state): State<Arc<AdminState>>) -> impl IntoResponse {
+   match state.db.get_metric_counts() {
+      Ok(counts) => {
+         let body = state.metrics.render(&counts, state.txid_generator.age());
+         (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            body,
+         )
+            .into_response()
+      },
+      Err(e) => database_error(e).into_response(),
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      crypto::KeyRing,
+      homepage::Homepage,
+      http_client::HttpError,
+      test_server,
+   };
+
+   #[tokio::test]
+   async fn metrics_are_served_without_the_admin_token() {
+      let dir = tempfile::tempdir().unwrap();
+      let keys = KeyRing::from_keys(&[vec![1; 32]]).unwrap();
+      let metrics = Arc::new(Metrics::new());
+      let homepage = Arc::new(Homepage::new(HttpClient::new()));
+
+      let state = Arc::new(AdminState {
+         db:             Arc::new(Db::open(dir.path().join("db.sqlite"), keys).unwrap()),
+         client:         Arc::new(HttpClient::new()),
+         vapid:          Arc::new(Vapid::new(&Vapid::generate_pkcs8().unwrap(), None).unwrap()),
+         metrics:        metrics.clone(),
+         txid_generator: Arc::new(TxIdGenerator::new(HttpClient::new(), homepage, metrics)),
+         token:          Some("secret".to_string()),
+         force_poll:     mpsc::unbounded_channel().0,
+      });
+      let client = HttpClient::allowing_http();
+      let no_headers: [(&str, &str); 0] = [];
+
+      let metrics_url = test_server::serve(metrics_router(state.clone())).await;
+      let body = client
+         .get(&format!("{metrics_url}/metrics"), &no_headers)
+         .await
+         .unwrap();
+      let body = String::from_utf8(body).unwrap();
+      assert!(body.contains("# TYPE xitter_notify_polls_total counter"));
+      assert!(body.contains("xitter_notify_users{status=\"active\"} 0"));
+
+      // Only on their own listener, not with the admin API
+      let admin_url = test_server::serve(router(state)).await;
+      for path in ["/metrics", "/admin/metrics"] {
+         let headers = [("authorization", "Bearer secret")];
+         match client.get(&format!("{admin_url}{path}"), &headers).await {
+            Err(HttpError::Status(code, _)) => assert_eq!(code, StatusCode::NOT_FOUND),
+            result => panic!("expected 404 for {path}, got {result:?}"),
+         }
+      }
+   }
+}
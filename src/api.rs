@@ -8,7 +8,11 @@ use axum::{
       Query,
       State,
    },
-   http::StatusCode,
+   http::{
+      HeaderMap,
+      StatusCode,
+      header,
+   },
    response::IntoResponse,
    routing::{
       delete,
@@ -20,30 +24,133 @@ use serde::{
    Deserialize,
    Serialize,
 };
+use tracing::{
+   error,
+   info,
+   warn,
+};
 
 use crate::{
-   db::Db,
+   crypto,
+   db::{
+      Db,
+      Device,
+      PushKeys,
+      User,
+   },
+   http_client::HttpClient,
+   metrics::{
+      Limited,
+      Metrics,
+   },
    rate_limit::RateLimiters,
+   twitter::{
+      self,
+      Notification,
+      TwitterAuth,
+      TwitterError,
+   },
    txid::TxIdGenerator,
+   unified_push,
+   vapid::Vapid,
+   web_push::ClientKeys,
 };
 
 pub struct AppState {
    pub db:             Arc<Db>,
+   pub client:         Arc<HttpClient>,
    pub rate_limiters:  Arc<RateLimiters>,
    pub txid_generator: Arc<TxIdGenerator>,
+   pub vapid:          Arc<Vapid>,
+   pub metrics:        Arc<Metrics>,
 }
 
 #[derive(Deserialize)]
 pub struct RegisterRequest {
-   twitter_user_id: String,
-   auth_token:      String,
-   csrf_token:      String,
-   up_endpoint:     String,
+   twitter_user_id:      String,
+   auth_token:           String,
+   csrf_token:           String,
+   up_endpoint:          String,
+   /// Web Push subscription keys; payloads are encrypted when both are given
+   #[serde(default)]
+   p256dh:               Option<String>,
+   #[serde(default)]
+   auth:                 Option<String>,
+   /// Whether to push direct messages; left as is when absent
+   #[serde(default)]
+   dm_notifications:     Option<bool>,
+   /// Whether to collapse similar notifications; left as is when absent
+   #[serde(default)]
+   group_notifications:  Option<bool>,
+   /// Minutes between digests, 0 to push right away; left as is when absent
+   #[serde(default)]
+   digest_interval_mins: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub struct UnregisterRequest {
    twitter_user_id: String,
+   /// Remove only this device; all devices are removed when absent
+   #[serde(default)]
+   up_endpoint:     Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UserQuery {
+   twitter_user_id: String,
+}
+
+#[derive(Serialize)]
+pub struct DevicesResponse {
+   devices: Vec<DeviceInfo>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+   up_endpoint:          String,
+   enabled:              bool,
+   consecutive_failures: u32,
+   last_error:           Option<String>,
+   disabled_at:          Option<i64>,
+   disabled_reason:      Option<String>,
+}
+
+/// Most history entries returned in one page
+const MAX_HISTORY_LIMIT: u32 = 200;
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+   twitter_user_id: String,
+   /// Only notifications after this sort index, for paging or syncing
+   #[serde(default)]
+   since:           Option<String>,
+   /// Comma-separated types to return; all when absent
+   #[serde(default)]
+   types:           Option<String>,
+   #[serde(default = "default_history_limit")]
+   limit:           u32,
+}
+
+fn default_history_limit() -> u32 {
+   50
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+   notifications: Vec<HistoryItem>,
+   /// `since` of the next page; absent on the last one
+   #[serde(skip_serializing_if = "Option::is_none")]
+   next_since:    Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryItem {
+   sort_index:        String,
+   notification_type: String,
+   status:            &'static str,
+   created_at:        i64,
+   updated_at:        i64,
+   notification:      serde_json::Value,
 }
 
 #[derive(Deserialize)]
@@ -59,37 +166,52 @@ pub struct TxIdResponse {
    txid: String,
 }
 
+#[derive(Serialize)]
+pub struct VapidResponse {
+   public_key: String,
+}
+
 #[derive(Serialize)]
 pub struct StatusResponse {
-   status: &'static str,
+   status:           &'static str,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   users:            Option<i64>,
+   /// Bearer token for managing the registration, only shown when issued
    #[serde(skip_serializing_if = "Option::is_none")]
-   users:  Option<i64>,
+   management_token: Option<String>,
    #[serde(skip_serializing_if = "Option::is_none")]
-   error:  Option<String>,
+   error:            Option<String>,
 }
 
 impl StatusResponse {
-   fn ok() -> Self {
+   pub fn ok() -> Self {
       Self {
-         status: "ok",
-         users:  None,
-         error:  None,
+         status:           "ok",
+         users:            None,
+         management_token: None,
+         error:            None,
       }
    }
 
    fn ok_with_users(users: i64) -> Self {
       Self {
-         status: "ok",
-         users:  Some(users),
-         error:  None,
+         users: Some(users),
+         ..Self::ok()
       }
    }
 
-   fn error(msg: impl Into<String>) -> Self {
+   fn ok_with_token(management_token: String) -> Self {
+      Self {
+         management_token: Some(management_token),
+         ..Self::ok()
+      }
+   }
+
+   pub fn error(msg: impl Into<String>) -> Self {
       Self {
          status: "error",
-         users:  None,
-         error:  Some(msg.into()),
+         error: Some(msg.into()),
+         ..Self::ok()
       }
    }
 }
@@ -98,8 +220,14 @@ pub fn router(state: Arc<AppState>) -> Router {
    Router::new()
       .route("/register", post(register))
       .route("/unregister", delete(unregister))
+      .route("/devices", get(devices))
+      .route("/preferences", get(get_preferences).put(put_preferences))
+      .route("/notifications", get(history))
+      .route("/test-push", post(test_push))
+      .route("/token/rotate", post(rotate_token))
       .route("/health", get(health))
       .route("/txid", get(generate_txid))
+      .route("/vapid", get(vapid_public_key))
       .with_state(state)
 }
 
@@ -112,6 +240,7 @@ async fn register(
 
    // Check rate limit
    if !state.rate_limiters.register.check(ip) {
+      state.metrics.record_rate_limited(Limited::Register);
       return (
          StatusCode::TOO_MANY_REQUESTS,
          Json(StatusResponse::error("Rate limit exceeded")),
@@ -152,18 +281,116 @@ async fn register(
       );
    }
 
+   let push_keys = match (req.p256dh, req.auth) {
+      (Some(p256dh), Some(auth)) => {
+         if let Err(e) = ClientKeys::from_base64(&p256dh, &auth) {
+            return (
+               StatusCode::BAD_REQUEST,
+               Json(StatusResponse::error(format!("Invalid push keys: {e}"))),
+            );
+         }
+         Some(PushKeys { p256dh, auth })
+      },
+      (None, None) => None,
+      _ => {
+         return (
+            StatusCode::BAD_REQUEST,
+            Json(StatusResponse::error(
+               "p256dh and auth must be given together",
+            )),
+         );
+      },
+   };
+
+   // The session has to belong to the account, or anyone could take over
+   // another user's registration with their own cookies
+   let auth = TwitterAuth {
+      auth_token: req.auth_token.clone(),
+      csrf_token: req.csrf_token.clone(),
+   };
+   match twitter::verify_credentials(&state.client, &state.txid_generator, &state.metrics, &auth)
+      .await
+   {
+      Ok(account_id) if account_id == req.twitter_user_id => {},
+      Ok(_) => {
+         warn!("Rejected a registration with the session of another account");
+         return (
+            StatusCode::FORBIDDEN,
+            Json(StatusResponse::error(
+               "Session belongs to a different account",
+            )),
+         );
+      },
+      Err(TwitterError::Auth(e)) => {
+         warn!("Twitter rejected the session of a registration: {e}");
+         return (
+            StatusCode::UNAUTHORIZED,
+            Json(StatusResponse::error("Twitter rejected the session")),
+         );
+      },
+      Err(e) => {
+         error!("Failed to verify session: {e}");
+         return (
+            StatusCode::BAD_GATEWAY,
+            Json(StatusResponse::error(
+               "Failed to verify session with Twitter",
+            )),
+         );
+      },
+   }
+
+   let management_token = match crypto::generate_token() {
+      Ok(token) => token,
+      Err(e) => {
+         error!("Failed to generate management token: {e}");
+         return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StatusResponse::error("Failed to register")),
+         );
+      },
+   };
+
    match state.db.register_user(
       &req.twitter_user_id,
       &req.auth_token,
       &req.csrf_token,
       &req.up_endpoint,
+      push_keys.as_ref(),
+      &crypto::hash_token(&management_token),
    ) {
-      Ok(_) => {
-         eprintln!("[api] Registered user {}", req.twitter_user_id);
-         (StatusCode::OK, Json(StatusResponse::ok()))
+      Ok(user_id) => {
+         let has_preferences = req.dm_notifications.is_some()
+            || req.group_notifications.is_some()
+            || req.digest_interval_mins.is_some();
+
+         if has_preferences
+            && let Err(e) = state.db.update_preferences(user_id, |preferences| {
+               if let Some(dm_notifications) = req.dm_notifications {
+                  preferences.dm_notifications = dm_notifications;
+               }
+               if let Some(group_notifications) = req.group_notifications {
+                  preferences.group_notifications = group_notifications;
+               }
+               if let Some(digest_interval_mins) = req.digest_interval_mins {
+                  preferences.digest_interval_mins = digest_interval_mins;
+               }
+            })
+         {
+            error!(user = user_id, "Failed to update preferences: {e}");
+            return (
+               StatusCode::INTERNAL_SERVER_ERROR,
+               Json(StatusResponse::error("Failed to register")),
+            );
+         }
+
+         info!(user = user_id, "Registered");
+         (
+            StatusCode::OK,
+            Json(StatusResponse::ok_with_token(management_token)),
+         )
       },
       Err(e) => {
-         eprintln!("[api] Failed to register user: {e}");
+         error!("Failed to register user: {e}");
          (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(StatusResponse::error("Failed to register")),
@@ -175,12 +402,14 @@ async fn register(
 async fn unregister(
    State(state): State<Arc<AppState>>,
    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
+   headers: HeaderMap,
    Json(req): Json<UnregisterRequest>,
 ) -> impl IntoResponse {
    let ip = addr.ip();
 
    // Check rate limit
    if !state.rate_limiters.unregister.check(ip) {
+      state.metrics.record_rate_limited(Limited::Unregister);
       return (
          StatusCode::TOO_MANY_REQUESTS,
          Json(StatusResponse::error("Rate limit exceeded")),
@@ -194,20 +423,34 @@ async fn unregister(
       );
    }
 
-   match state.db.unregister_user(&req.twitter_user_id) {
+   let user = match authenticate(&state, &headers, &req.twitter_user_id) {
+      Ok((user, _)) => user,
+      Err(e) => return e,
+   };
+
+   let result = match &req.up_endpoint {
+      Some(endpoint) => state.db.unregister_device(&req.twitter_user_id, endpoint),
+      None => state.db.unregister_user(&req.twitter_user_id),
+   };
+
+   match result {
       Ok(deleted) => {
          if deleted {
-            eprintln!("[api] Unregistered user {}", req.twitter_user_id);
+            if req.up_endpoint.is_some() {
+               info!(user = user.id, "Unregistered a device");
+            } else {
+               info!(user = user.id, "Unregistered");
+            }
             (StatusCode::OK, Json(StatusResponse::ok()))
          } else {
             (
                StatusCode::NOT_FOUND,
-               Json(StatusResponse::error("User not found")),
+               Json(StatusResponse::error("User or device not found")),
             )
          }
       },
       Err(e) => {
-         eprintln!("[api] Failed to unregister user: {e}");
+         error!(user = user.id, "Failed to unregister: {e}");
          (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(StatusResponse::error("Failed to unregister")),
@@ -216,11 +459,282 @@ async fn unregister(
    }
 }
 
+/// Authenticate a request for a user's own data by its bearer token, which
+/// has to be the management token of one of the user's devices. Returns the
+/// user and that device.
+fn authenticate(
+   state: &AppState,
+   headers: &HeaderMap,
+   twitter_user_id: &str,
+) -> Result<(User, Device), (StatusCode, Json<StatusResponse>)> {
+   let unauthorized = || {
+      (
+         StatusCode::UNAUTHORIZED,
+         Json(StatusResponse::error("Invalid credentials")),
+      )
+   };
+
+   let token = headers
+      .get(header::AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("Bearer "))
+      .ok_or_else(unauthorized)?;
+
+   let database_error = |e| {
+      error!("Failed to authenticate: {e}");
+      (
+         StatusCode::INTERNAL_SERVER_ERROR,
+         Json(StatusResponse::error("Database error")),
+      )
+   };
+
+   // Tokens are looked up by hash, so the lookup reveals nothing about them
+   let device = state
+      .db
+      .get_device_by_token(&crypto::hash_token(token))
+      .map_err(database_error)?
+      .ok_or_else(unauthorized)?;
+
+   let user = state
+      .db
+      .get_user(twitter_user_id)
+      .map_err(database_error)?
+      .ok_or_else(unauthorized)?;
+
+   if device.user_id != user.id {
+      return Err(unauthorized());
+   }
+
+   Ok((user, device))
+}
+
+async fn devices(
+   State(state): State<Arc<AppState>>,
+   headers: HeaderMap,
+   Query(query): Query<UserQuery>,
+) -> impl IntoResponse {
+   let (user, _) = match authenticate(&state, &headers, &query.twitter_user_id) {
+      Ok(authenticated) => authenticated,
+      Err(e) => return e.into_response(),
+   };
+
+   match state.db.get_devices(user.id) {
+      Ok(devices) => {
+         let devices = devices
+            .into_iter()
+            .map(|d| {
+               DeviceInfo {
+                  enabled:              d.is_enabled(),
+                  up_endpoint:          d.up_endpoint,
+                  consecutive_failures: d.consecutive_failures,
+                  last_error:           d.last_error,
+                  disabled_at:          d.disabled_at,
+                  disabled_reason:      d.disabled_reason,
+               }
+            })
+            .collect();
+
+         (StatusCode::OK, Json(DevicesResponse { devices })).into_response()
+      },
+      Err(e) => {
+         error!("Failed to list devices: {e}");
+         (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StatusResponse::error("Database error")),
+         )
+            .into_response()
+      },
+   }
+}
+
+async fn get_preferences(
+   State(state): State<Arc<AppState>>,
+   headers: HeaderMap,
+   Query(query): Query<UserQuery>,
+) -> impl IntoResponse {
+   match authenticate(&state, &headers, &query.twitter_user_id) {
+      Ok((user, _)) => (StatusCode::OK, Json(user.preferences)).into_response(),
+      Err(e) => e.into_response(),
+   }
+}
+
+/// Change the given settings of a user's preferences. Settings left out stay
+/// as they are, like with `/register`.
+async fn put_preferences(
+   State(state): State<Arc<AppState>>,
+   headers: HeaderMap,
+   Query(query): Query<UserQuery>,
+   Json(update): Json<serde_json::Map<String, serde_json::Value>>,
+) -> impl IntoResponse {
+   let (user, _) = match authenticate(&state, &headers, &query.twitter_user_id) {
+      Ok(authenticated) => authenticated,
+      Err(e) => return e.into_response(),
+   };
+
+   let preferences = match user.preferences.merge(&update) {
+      Ok(preferences) => preferences,
+      Err(e) => {
+         return (StatusCode::BAD_REQUEST, Json(StatusResponse::error(e))).into_response();
+      },
+   };
+
+   // Merged again into what's stored by then, in case it changed since
+   match state.db.update_preferences(user.id, |stored| {
+      *stored = stored.merge(&update).unwrap_or(preferences);
+   }) {
+      Ok(preferences) => {
+         info!(user = user.id, "Updated preferences");
+         (StatusCode::OK, Json(preferences)).into_response()
+      },
+      Err(e) => {
+         error!(user = user.id, "Failed to update preferences: {e}");
+         (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StatusResponse::error("Database error")),
+         )
+            .into_response()
+      },
+   }
+}
+
+/// A page of the user's notification history, oldest first
+async fn history(
+   State(state): State<Arc<AppState>>,
+   headers: HeaderMap,
+   Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+   let (user, _) = match authenticate(&state, &headers, &query.twitter_user_id) {
+      Ok(authenticated) => authenticated,
+      Err(e) => return e.into_response(),
+   };
+
+   let types: Vec<String> = query
+      .types
+      .iter()
+      .flat_map(|types| types.split(','))
+      .map(|t| t.trim().to_lowercase())
+      .filter(|t| !t.is_empty())
+      .collect();
+   let limit = query.limit.clamp(1, MAX_HISTORY_LIMIT);
+
+   let entries = match state
+      .db
+      .get_history(user.id, query.since.as_deref(), &types, limit)
+   {
+      Ok(entries) => entries,
+      Err(e) => {
+         error!("Failed to read notification history: {e}");
+         return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StatusResponse::error("Database error")),
+         )
+            .into_response();
+      },
+   };
+
+   // A full page may have more after it
+   let next_since = (entries.len() == limit as usize)
+      .then(|| entries.last().map(|entry| entry.sort_index.clone()))
+      .flatten();
+
+   let notifications = entries
+      .into_iter()
+      .map(|entry| {
+         HistoryItem {
+            notification:      serde_json::from_str(&entry.notification)
+               .unwrap_or(serde_json::Value::Null),
+            sort_index:        entry.sort_index,
+            notification_type: entry.notification_type,
+            status:            entry.status.as_str(),
+            created_at:        entry.created_at,
+            updated_at:        entry.updated_at,
+         }
+      })
+      .collect();
+
+   (
+      StatusCode::OK,
+      Json(HistoryResponse {
+         notifications,
+         next_since,
+      }),
+   )
+      .into_response()
+}
+
+/// Push a test notification to the calling device, reporting whether the
+/// push service took it
+async fn test_push(
+   State(state): State<Arc<AppState>>,
+   headers: HeaderMap,
+   Query(query): Query<UserQuery>,
+) -> impl IntoResponse {
+   let (user, device) = match authenticate(&state, &headers, &query.twitter_user_id) {
+      Ok(authenticated) => authenticated,
+      Err(e) => return e,
+   };
+
+   match unified_push::send(&state.client, &state.vapid, &device, &Notification::test()).await {
+      Ok(()) => (StatusCode::OK, Json(StatusResponse::ok())),
+      Err(e) => {
+         warn!(user = user.id, device = device.id, "Test push failed: {e}");
+         (
+            StatusCode::BAD_GATEWAY,
+            Json(StatusResponse::error(format!("Push failed: {e}"))),
+         )
+      },
+   }
+}
+
+/// Replace the calling device's management token, returning the new one
+async fn rotate_token(
+   State(state): State<Arc<AppState>>,
+   headers: HeaderMap,
+   Query(query): Query<UserQuery>,
+) -> impl IntoResponse {
+   let (user, device) = match authenticate(&state, &headers, &query.twitter_user_id) {
+      Ok(authenticated) => authenticated,
+      Err(e) => return e,
+   };
+
+   let token = match crypto::generate_token() {
+      Ok(token) => token,
+      Err(e) => {
+         error!("Failed to generate management token: {e}");
+         return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StatusResponse::error("Failed to rotate token")),
+         );
+      },
+   };
+
+   match state
+      .db
+      .set_management_token(device.id, &crypto::hash_token(&token))
+   {
+      Ok(()) => {
+         info!(
+            user = user.id,
+            device = device.id,
+            "Rotated management token"
+         );
+         (StatusCode::OK, Json(StatusResponse::ok_with_token(token)))
+      },
+      Err(e) => {
+         error!("Failed to rotate management token: {e}");
+         (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StatusResponse::error("Failed to rotate token")),
+         )
+      },
+   }
+}
+
 async fn health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
    match state.db.user_count() {
       Ok(count) => (StatusCode::OK, Json(StatusResponse::ok_with_users(count))),
       Err(e) => {
-         eprintln!("[api] Health check failed: {e}");
+         error!("Health check failed: {e}");
          (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(StatusResponse::error("Database error")),
@@ -245,13 +759,13 @@ async fn generate_txid(
    if query.force
       && let Err(e) = state.txid_generator.invalidate_and_refresh().await
    {
-      eprintln!("[api] Failed to force refresh: {e}");
+      warn!("Failed to force refresh: {e}");
    }
 
    match state.txid_generator.generate("GET", &query.path).await {
       Ok(txid) => (StatusCode::OK, Json(TxIdResponse { txid })).into_response(),
       Err(e) => {
-         eprintln!("[api] Failed to generate transaction ID: {e}");
+         error!("Failed to generate transaction ID: {e}");
          (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(StatusResponse::error(format!("Failed to generate: {e}"))),
@@ -260,3 +774,313 @@ async fn generate_txid(
       },
    }
 }
+
+/// The server's VAPID public key, for clients subscribing with
+/// `applicationServerKey`
+async fn vapid_public_key(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+   Json(VapidResponse {
+      public_key: state.vapid.public_key(),
+   })
+}
+
+#[cfg(test)]
+mod tests {
+   use std::net::SocketAddr;
+
+   use tempfile::TempDir;
+
+   use super::*;
+   use crate::{
+      crypto::KeyRing,
+      homepage::Homepage,
+      http_client::HttpError,
+      test_server,
+   };
+
+   /// App state whose requests to x.com go to a stand-in answering session
+   /// checks with `status` and `body`
+   async fn state(dir: &TempDir, status: StatusCode, body: &'static str) -> Arc<AppState> {
+      let app = Router::new().route(
+         "/i/api/1.1/account/verify_credentials.json",
+         get(move || async move { (status, body) }),
+      );
+      let twitter = test_server::serve(app).await;
+
+      let keys = KeyRing::from_keys(&[vec![1; 32]]).unwrap();
+      let metrics = Arc::new(Metrics::new());
+      let client = || HttpClient::allowing_http().redirect("https://x.com", &twitter);
+      let homepage = Arc::new(Homepage::new(client()));
+
+      Arc::new(AppState {
+         db: Arc::new(Db::open(dir.path().join("db.sqlite"), keys).unwrap()),
+         client: Arc::new(client()),
+         rate_limiters: Arc::new(RateLimiters::new()),
+         txid_generator: Arc::new(TxIdGenerator::new(client(), homepage, metrics.clone())),
+         vapid: Arc::new(Vapid::new(&Vapid::generate_pkcs8().unwrap(), None).unwrap()),
+         metrics,
+      })
+   }
+
+   /// Register a user with a device at `endpoint`, returning the device's
+   /// management token
+   fn registered(state: &AppState, twitter_user_id: &str, endpoint: &str) -> String {
+      let token = crypto::generate_token().unwrap();
+      state
+         .db
+         .register_user(
+            twitter_user_id,
+            "auth",
+            "csrf",
+            endpoint,
+            None,
+            &crypto::hash_token(&token),
+         )
+         .unwrap();
+      token
+   }
+
+   fn bearer(token: &str) -> HeaderMap {
+      let mut headers = HeaderMap::new();
+      headers.insert(
+         header::AUTHORIZATION,
+         format!("Bearer {token}").parse().unwrap(),
+      );
+      headers
+   }
+
+   async fn json_body(response: axum::response::Response) -> serde_json::Value {
+      let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+         .await
+         .unwrap();
+      serde_json::from_slice(&body).unwrap()
+   }
+
+   async fn register_as(state: &Arc<AppState>, twitter_user_id: &str) -> StatusCode {
+      let req = serde_json::from_value(serde_json::json!({
+         "twitter_user_id": twitter_user_id,
+         "auth_token": "auth",
+         "csrf_token": "csrf",
+         "up_endpoint": "https://push.example/up",
+      }))
+      .unwrap();
+      let addr = SocketAddr::from(([127, 0, 0, 1], 1234));
+
+      register(State(state.clone()), ConnectInfo(addr), Json(req))
+         .await
+         .into_response()
+         .status()
+   }
+
+   #[tokio::test]
+   async fn registers_the_sessions_account() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, r#"{"id_str": "123"}"#).await;
+
+      assert_eq!(register_as(&state, "123").await, StatusCode::OK);
+      assert!(state.db.get_user("123").unwrap().is_some());
+   }
+
+   #[tokio::test]
+   async fn rejects_the_session_of_another_account() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, r#"{"id_str": "456"}"#).await;
+
+      assert_eq!(register_as(&state, "123").await, StatusCode::FORBIDDEN);
+      assert!(state.db.get_user("123").unwrap().is_none());
+   }
+
+   #[tokio::test]
+   async fn rejects_sessions_twitter_rejects() {
+      let dir = tempfile::tempdir().unwrap();
+      let body = r#"{"errors": [{"code": 32, "message": "Could not authenticate you."}]}"#;
+      let state = state(&dir, StatusCode::UNAUTHORIZED, body).await;
+
+      assert_eq!(register_as(&state, "123").await, StatusCode::UNAUTHORIZED);
+      assert!(state.db.get_user("123").unwrap().is_none());
+   }
+
+   #[tokio::test]
+   async fn metrics_are_not_public() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, "{}").await;
+      let url = format!("{}/metrics", test_server::serve(router(state)).await);
+
+      let headers: [(&str, &str); 0] = [];
+      match HttpClient::allowing_http().get(&url, &headers).await {
+         Err(HttpError::Status(code, _)) => assert_eq!(code, StatusCode::NOT_FOUND),
+         result => panic!("expected 404, got {result:?}"),
+      }
+   }
+
+   #[tokio::test]
+   async fn devices_show_why_they_were_disabled() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, "{}").await;
+      let token = registered(&state, "123", "https://push.example/up");
+      let user = state.db.get_user("123").unwrap().unwrap();
+      let device = state.db.get_devices(user.id).unwrap().remove(0);
+      state
+         .db
+         .disable_device(device.id, "Endpoint is gone: HTTP 410 Gone: ")
+         .unwrap();
+
+      let query = UserQuery {
+         twitter_user_id: "123".to_string(),
+      };
+      let response = devices(State(state.clone()), bearer(&token), Query(query))
+         .await
+         .into_response();
+      assert_eq!(response.status(), StatusCode::OK);
+
+      let body = json_body(response).await;
+      let device = &body["devices"][0];
+      assert_eq!(device["up_endpoint"], "https://push.example/up");
+      assert_eq!(device["enabled"], false);
+      assert_eq!(
+         device["disabled_reason"],
+         "Endpoint is gone: HTTP 410 Gone: "
+      );
+      assert!(device["disabled_at"].is_i64());
+   }
+
+   /// The status authenticating as `twitter_user_id` with `headers` results in
+   fn auth_status(state: &AppState, headers: &HeaderMap, twitter_user_id: &str) -> StatusCode {
+      match authenticate(state, headers, twitter_user_id) {
+         Ok(_) => StatusCode::OK,
+         Err((status, _)) => status,
+      }
+   }
+
+   #[tokio::test]
+   async fn tokens_only_authenticate_their_own_user() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, "{}").await;
+      let token = registered(&state, "123", "https://push.example/up/1");
+      let other_token = registered(&state, "456", "https://push.example/up/2");
+
+      assert_eq!(auth_status(&state, &bearer(&token), "123"), StatusCode::OK);
+      assert_eq!(
+         auth_status(&state, &bearer(&other_token), "123"),
+         StatusCode::UNAUTHORIZED
+      );
+      assert_eq!(
+         auth_status(&state, &bearer(&token), "789"),
+         StatusCode::UNAUTHORIZED
+      );
+   }
+
+   #[tokio::test]
+   async fn rotated_tokens_stop_working() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, "{}").await;
+      let token = registered(&state, "123", "https://push.example/up");
+
+      let query = UserQuery {
+         twitter_user_id: "123".to_string(),
+      };
+      let response = rotate_token(State(state.clone()), bearer(&token), Query(query))
+         .await
+         .into_response();
+      assert_eq!(response.status(), StatusCode::OK);
+      let body = json_body(response).await;
+      let new_token = body["management_token"].as_str().unwrap();
+      assert_ne!(new_token, token);
+
+      assert_eq!(
+         auth_status(&state, &bearer(&token), "123"),
+         StatusCode::UNAUTHORIZED
+      );
+      assert_eq!(
+         auth_status(&state, &bearer(new_token), "123"),
+         StatusCode::OK
+      );
+   }
+
+   #[tokio::test]
+   async fn missing_or_malformed_bearers_are_rejected() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, "{}").await;
+      let token = registered(&state, "123", "https://push.example/up");
+
+      assert_eq!(
+         auth_status(&state, &HeaderMap::new(), "123"),
+         StatusCode::UNAUTHORIZED
+      );
+      for value in [
+         token.clone(),
+         format!("Basic {token}"),
+         format!("bearer {token}"),
+         format!("Bearer  {token}"),
+         "Bearer ".to_string(),
+         "Bearer not-a-token".to_string(),
+      ] {
+         let mut headers = HeaderMap::new();
+         headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+         assert_eq!(
+            auth_status(&state, &headers, "123"),
+            StatusCode::UNAUTHORIZED,
+            "{value}"
+         );
+      }
+
+      // Header values that aren't text
+      let mut headers = HeaderMap::new();
+      headers.insert(
+         header::AUTHORIZATION,
+         axum::http::HeaderValue::from_bytes(b"Bearer \xff").unwrap(),
+      );
+      assert_eq!(
+         auth_status(&state, &headers, "123"),
+         StatusCode::UNAUTHORIZED
+      );
+   }
+
+   #[tokio::test]
+   async fn preferences_left_out_stay() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir, StatusCode::OK, "{}").await;
+      let token = registered(&state, "123", "https://push.example/up");
+      let user = state.db.get_user("123").unwrap().unwrap();
+      state
+         .db
+         .update_preferences(user.id, |prefs| prefs.dm_notifications = false)
+         .unwrap();
+
+      let put = |update: serde_json::Value| {
+         let state = state.clone();
+         let token = token.clone();
+         async move {
+            let serde_json::Value::Object(update) = update else {
+               unreachable!()
+            };
+            let query = UserQuery {
+               twitter_user_id: "123".to_string(),
+            };
+            put_preferences(State(state), bearer(&token), Query(query), Json(update))
+               .await
+               .into_response()
+         }
+      };
+
+      let response = put(serde_json::json!({ "group_notifications": true })).await;
+      assert_eq!(response.status(), StatusCode::OK);
+      let body = json_body(response).await;
+      assert_eq!(body["group_notifications"], true);
+      assert_eq!(body["dm_notifications"], false);
+
+      let stored = state.db.get_user("123").unwrap().unwrap().preferences;
+      assert!(stored.group_notifications);
+      assert!(!stored.dm_notifications);
+
+      // Bad updates change nothing
+      for update in [
+         serde_json::json!({ "group_notification": false }),
+         serde_json::json!({ "timezone": "Mars/Olympus_Mons" }),
+      ] {
+         assert_eq!(put(update).await.status(), StatusCode::BAD_REQUEST);
+      }
+      let stored = state.db.get_user("123").unwrap().unwrap().preferences;
+      assert!(stored.group_notifications);
+      assert_eq!(stored.timezone, None);
+   }
+}
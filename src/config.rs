@@ -3,11 +3,52 @@ use std::{
    path::PathBuf,
 };
 
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+   /// Human readable lines
+   Text,
+   /// One JSON object per line, for log shippers
+   Json,
+}
+
 pub struct Config {
-   pub db_path:            PathBuf,
-   pub listen_addr:        SocketAddr,
-   pub poll_interval_secs: u64,
-   pub max_concurrent:     usize,
+   pub db_path:                 PathBuf,
+   pub listen_addr:             SocketAddr,
+   /// Interval new users start at; active users speed up to the minimum and
+   /// quiet ones slow down to the maximum
+   pub poll_interval_secs:      u64,
+   pub min_poll_interval_secs:  u64,
+   pub max_poll_interval_secs:  u64,
+   pub max_concurrent:          usize,
+   /// Pages of the notifications timeline followed in one poll when
+   /// everything on them is new
+   pub max_notification_pages:  u32,
+   /// Consecutive transient push failures before a device is disabled
+   pub max_push_failures:       u32,
+   /// Consecutive polls rejected by Twitter before the user has to sign in
+   /// again
+   pub max_auth_failures:       u32,
+   /// How long a failed push keeps being retried before it's dropped
+   pub push_retry_max_age_secs: u64,
+   /// How long notifications are kept in the history
+   pub history_max_age_secs:    u64,
+   /// Most notifications kept in the history of each user
+   pub history_max_per_user:    u32,
+   /// Base64 master keys, comma-separated with the current key first
+   pub encryption_keys:         Option<String>,
+   pub encryption_key_file:     PathBuf,
+   /// Contact URI sent to push services in VAPID tokens
+   pub vapid_subject:           Option<String>,
+   /// Bearer token for the admin API
+   pub admin_token:             Option<String>,
+   /// Serves the admin API here instead of under `/admin` on `listen_addr`
+   pub admin_listen_addr:       Option<SocketAddr>,
+   /// Serves Prometheus metrics at `/metrics`, apart from the public API
+   pub metrics_listen_addr:     SocketAddr,
+   /// Level or filter directives like `info,xitter_notify_server::poller=debug`
+   pub log_level:               String,
+   pub log_format:              LogFormat,
 }
 
 impl Config {
@@ -21,27 +62,161 @@ impl Config {
          .and_then(|s| s.parse().ok())
          .unwrap_or_else(|| "127.0.0.1:3000".parse().unwrap());
 
-      let poll_interval_secs = std::env::var("XITTER_NOTIFY_POLL_INTERVAL")
-         .ok()
-         .and_then(|s| s.parse().ok())
-         .unwrap_or(15);
+      let (poll_interval_secs, min_poll_interval_secs, max_poll_interval_secs) = poll_intervals(
+         std::env::var("XITTER_NOTIFY_POLL_INTERVAL")
+            .ok()
+            .and_then(|s| s.parse().ok()),
+         std::env::var("XITTER_NOTIFY_MIN_POLL_INTERVAL")
+            .ok()
+            .and_then(|s| s.parse().ok()),
+         std::env::var("XITTER_NOTIFY_MAX_POLL_INTERVAL")
+            .ok()
+            .and_then(|s| s.parse().ok()),
+      );
 
       let max_concurrent = std::env::var("XITTER_NOTIFY_MAX_CONCURRENT")
          .ok()
          .and_then(|s| s.parse().ok())
          .unwrap_or(50);
 
+      let max_notification_pages = std::env::var("XITTER_NOTIFY_MAX_PAGES")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .filter(|&pages| pages > 0)
+         .unwrap_or(5);
+
+      let max_push_failures = std::env::var("XITTER_NOTIFY_MAX_PUSH_FAILURES")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .unwrap_or(5);
+
+      let max_auth_failures = std::env::var("XITTER_NOTIFY_MAX_AUTH_FAILURES")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .unwrap_or(3);
+
+      let push_retry_max_age_secs = std::env::var("XITTER_NOTIFY_PUSH_RETRY_MAX_AGE")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .unwrap_or(24 * 60 * 60);
+
+      let history_max_age_secs = std::env::var("XITTER_NOTIFY_HISTORY_MAX_AGE")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .unwrap_or(30 * 24 * 60 * 60);
+
+      let history_max_per_user = std::env::var("XITTER_NOTIFY_HISTORY_MAX_PER_USER")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .unwrap_or(1000);
+
+      let encryption_keys = std::env::var("XITTER_NOTIFY_ENCRYPTION_KEY").ok();
+
+      let encryption_key_file = std::env::var("XITTER_NOTIFY_ENCRYPTION_KEY_FILE")
+         .map(PathBuf::from)
+         .unwrap_or_else(|_| PathBuf::from("./xitter-notify-server.key"));
+
+      let vapid_subject = std::env::var("XITTER_NOTIFY_VAPID_SUBJECT").ok();
+
+      let admin_token = std::env::var("XITTER_NOTIFY_ADMIN_TOKEN")
+         .ok()
+         .filter(|token| !token.is_empty());
+
+      let admin_listen_addr = std::env::var("XITTER_NOTIFY_ADMIN_LISTEN_ADDR")
+         .ok()
+         .and_then(|s| s.parse().ok());
+
+      let metrics_listen_addr = std::env::var("XITTER_NOTIFY_METRICS_LISTEN_ADDR")
+         .ok()
+         .and_then(|s| s.parse().ok())
+         .unwrap_or_else(|| "127.0.0.1:9464".parse().unwrap());
+
+      let log_level =
+         std::env::var("XITTER_NOTIFY_LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+
+      let log_format = std::env::var("XITTER_NOTIFY_LOG_FORMAT")
+         .ok()
+         .and_then(|s| {
+            match s.as_str() {
+               "text" => Some(LogFormat::Text),
+               "json" => Some(LogFormat::Json),
+               _ => None,
+            }
+         })
+         .unwrap_or(LogFormat::Text);
+
       Self {
          db_path,
          listen_addr,
          poll_interval_secs,
+         min_poll_interval_secs,
+         max_poll_interval_secs,
          max_concurrent,
+         max_notification_pages,
+         max_push_failures,
+         max_auth_failures,
+         push_retry_max_age_secs,
+         history_max_age_secs,
+         history_max_per_user,
+         encryption_keys,
+         encryption_key_file,
+         vapid_subject,
+         admin_token,
+         admin_listen_addr,
+         metrics_listen_addr,
+         log_level,
+         log_format,
       }
    }
 }
 
+/// The base, minimum and maximum poll intervals from the configured ones,
+/// defaulting missing ones and adjusting the bounds so that
+/// `0 < min <= base <= max`
+fn poll_intervals(base: Option<u64>, min: Option<u64>, max: Option<u64>) -> (u64, u64, u64) {
+   let base = base.filter(|&secs| secs > 0).unwrap_or(15);
+   let min = min
+      .filter(|&secs| secs > 0)
+      .unwrap_or(base.min(10))
+      .min(base);
+   let max = max.unwrap_or(base.max(120)).max(base);
+
+   (base, min, max)
+}
+
 impl Default for Config {
    fn default() -> Self {
       Self::from_env()
    }
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn poll_interval_defaults() {
+      assert_eq!(poll_intervals(None, None, None), (15, 10, 120));
+      assert_eq!(poll_intervals(Some(5), None, None), (5, 5, 120));
+      assert_eq!(poll_intervals(Some(300), None, None), (300, 10, 300));
+      assert_eq!(poll_intervals(Some(0), Some(0), None), (15, 10, 120));
+   }
+
+   #[test]
+   fn poll_interval_bounds_are_ordered() {
+      // Bounds on the wrong side of the base interval move to it
+      assert_eq!(poll_intervals(Some(30), Some(60), None), (30, 30, 120));
+      assert_eq!(poll_intervals(Some(30), None, Some(20)), (30, 10, 30));
+      assert_eq!(poll_intervals(Some(30), Some(60), Some(20)), (30, 30, 30));
+      assert_eq!(poll_intervals(Some(30), Some(5), Some(600)), (30, 5, 600));
+
+      for base in [None, Some(0), Some(1), Some(30), Some(1000)] {
+         for min in [None, Some(0), Some(1), Some(40), Some(5000)] {
+            for max in [None, Some(0), Some(1), Some(40), Some(5000)] {
+               let (base, min, max) = poll_intervals(base, min, max);
+               assert!(0 < min && min <= base && base <= max);
+            }
+         }
+      }
+   }
+}
@@ -0,0 +1,390 @@
+use std::{
+   io::Write,
+   path::Path,
+};
+
+use data_encoding::{
+   BASE64,
+   BASE64URL_NOPAD,
+   HEXLOWER,
+};
+use ring::{
+   aead::{
+      AES_256_GCM,
+      Aad,
+      LessSafeKey,
+      NONCE_LEN,
+      Nonce,
+      UnboundKey,
+   },
+   digest,
+   rand::{
+      SecureRandom,
+      SystemRandom,
+   },
+};
+use tracing::info;
+
+const KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum CryptoError {
+   Key(String),
+   UnknownKey(String),
+   Format(String),
+   Encrypt,
+   Decrypt,
+   Random,
+}
+
+impl std::fmt::Display for CryptoError {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      match self {
+         CryptoError::Key(e) => write!(f, "Key error: {e}"),
+         CryptoError::UnknownKey(id) => write!(f, "Unknown master key: {id}"),
+         CryptoError::Format(e) => write!(f, "Format error: {e}"),
+         CryptoError::Encrypt => write!(f, "Encryption failed"),
+         CryptoError::Decrypt => write!(f, "Decryption failed"),
+         CryptoError::Random => write!(f, "Random number generation failed"),
+      }
+   }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Fill a buffer from the system CSPRNG
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], CryptoError> {
+   let mut buf = [0u8; N];
+   SystemRandom::new()
+      .fill(&mut buf)
+      .map_err(|_| CryptoError::Random)?;
+   Ok(buf)
+}
+
+/// A uniformly random number in `[0, 1)`, for jitter
+pub fn random_f64() -> f64 {
+   // If the system RNG fails, jitter degrades to a fixed midpoint
+   random_bytes::<8>().map_or(0.5, |b| {
+      (u64::from_le_bytes(b) >> 11) as f64 / (1u64 << 53) as f64
+   })
+}
+
+/// Compare secrets without leaking the position of the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+   a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A random bearer token, handed out once and only stored hashed
+pub fn generate_token() -> Result<String, CryptoError> {
+   Ok(BASE64URL_NOPAD.encode(&random_bytes::<32>()?))
+}
+
+/// SHA-256 of a bearer token as stored. Tokens are random, so they need
+/// neither salt nor a slow hash.
+pub fn hash_token(token: &str) -> String {
+   HEXLOWER.encode(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+struct MasterKey {
+   id:  String,
+   key: LessSafeKey,
+}
+
+impl MasterKey {
+   fn new(raw: &[u8]) -> Result<Self, CryptoError> {
+      if raw.len() != KEY_LEN {
+         return Err(CryptoError::Key(format!(
+            "master key must be {KEY_LEN} bytes, got {}",
+            raw.len()
+         )));
+      }
+
+      let unbound = UnboundKey::new(&AES_256_GCM, raw).map_err(|_| CryptoError::Encrypt)?;
+
+      // The ID is a short fingerprint so ciphertexts can name the key that wrapped
+      // them
+      let fingerprint = digest::digest(&digest::SHA256, raw);
+      let id = HEXLOWER.encode(&fingerprint.as_ref()[..4]);
+
+      Ok(Self {
+         id,
+         key: LessSafeKey::new(unbound),
+      })
+   }
+}
+
+/// Server-side master keys used to wrap the per-user data keys.
+///
+/// The first key is current and wraps all new data keys; any further keys are
+/// previous keys kept around only to unwrap data keys that haven't been
+/// rotated yet.
+pub struct KeyRing {
+   current:  MasterKey,
+   previous: Vec<MasterKey>,
+}
+
+impl KeyRing {
+   /// Load the key ring from `XITTER_NOTIFY_ENCRYPTION_KEY` if set, otherwise
+   /// from `key_file`, generating a fresh key file on first start.
+   ///
+   /// Both sources hold base64 keys, current first: comma-separated in the
+   /// environment variable, one per line in the file.
+   pub fn load(env_keys: Option<&str>, key_file: &Path) -> Result<Self, CryptoError> {
+      if let Some(keys) = env_keys {
+         return Self::parse(keys.split(','));
+      }
+
+      match std::fs::read_to_string(key_file) {
+         Ok(contents) => Self::parse(contents.lines()),
+         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let raw = random_bytes::<KEY_LEN>()?;
+            write_key_file(key_file, &BASE64.encode(&raw))?;
+            info!("Generated new encryption key at {key_file:?}");
+            Self::from_keys(&[raw.to_vec()])
+         },
+         Err(e) => {
+            Err(CryptoError::Key(format!(
+               "Failed to read {key_file:?}: {e}"
+            )))
+         },
+      }
+   }
+
+   fn parse<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Self, CryptoError> {
+      let keys = lines
+         .map(str::trim)
+         .filter(|line| !line.is_empty() && !line.starts_with('#'))
+         .map(|line| {
+            BASE64
+               .decode(line.as_bytes())
+               .map_err(|e| CryptoError::Key(format!("invalid base64 key: {e}")))
+         })
+         .collect::<Result<Vec<_>, _>>()?;
+
+      Self::from_keys(&keys)
+   }
+
+   pub fn from_keys(keys: &[Vec<u8>]) -> Result<Self, CryptoError> {
+      let mut keys = keys.iter().map(|raw| MasterKey::new(raw));
+
+      let current = keys
+         .next()
+         .ok_or_else(|| CryptoError::Key("no encryption key configured".to_string()))??;
+      let previous = keys.collect::<Result<Vec<_>, _>>()?;
+
+      Ok(Self { current, previous })
+   }
+
+   pub fn current_id(&self) -> &str {
+      &self.current.id
+   }
+
+   /// Whether a wrapped data key was wrapped by the current master key
+   pub fn is_current(&self, wrapped: &str) -> bool {
+      wrapped
+         .split_once(':')
+         .is_some_and(|(id, _)| id == self.current.id)
+   }
+
+   /// Generate a fresh data key, returning it along with its wrapped form
+   pub fn generate_data_key(&self, aad: &str) -> Result<(DataKey, String), CryptoError> {
+      let key = DataKey {
+         raw: random_bytes::<KEY_LEN>()?,
+      };
+      let wrapped = self.wrap_data_key(&key, aad)?;
+      Ok((key, wrapped))
+   }
+
+   /// Wrap a data key under the current master key as `<key id>:<base64>`
+   pub fn wrap_data_key(&self, key: &DataKey, aad: &str) -> Result<String, CryptoError> {
+      let sealed = seal(&self.current.key, &key.raw, aad)?;
+      Ok(format!("{}:{sealed}", self.current.id))
+   }
+
+   pub fn unwrap_data_key(&self, wrapped: &str, aad: &str) -> Result<DataKey, CryptoError> {
+      let (id, sealed) = wrapped
+         .split_once(':')
+         .ok_or_else(|| CryptoError::Format("wrapped key is missing its key ID".to_string()))?;
+
+      let master = std::iter::once(&self.current)
+         .chain(&self.previous)
+         .find(|k| k.id == id)
+         .ok_or_else(|| CryptoError::UnknownKey(id.to_string()))?;
+
+      let raw = open(&master.key, sealed, aad)?;
+      let raw: [u8; KEY_LEN] = raw
+         .try_into()
+         .map_err(|_| CryptoError::Format("data key has the wrong length".to_string()))?;
+
+      Ok(DataKey { raw })
+   }
+}
+
+/// Per-row key that encrypts the actual column values
+pub struct DataKey {
+   raw: [u8; KEY_LEN],
+}
+
+impl DataKey {
+   fn aead_key(&self) -> Result<LessSafeKey, CryptoError> {
+      let unbound = UnboundKey::new(&AES_256_GCM, &self.raw).map_err(|_| CryptoError::Encrypt)?;
+      Ok(LessSafeKey::new(unbound))
+   }
+
+   pub fn encrypt(&self, plaintext: &str, aad: &str) -> Result<String, CryptoError> {
+      seal(&self.aead_key()?, plaintext.as_bytes(), aad)
+   }
+
+   pub fn decrypt(&self, ciphertext: &str, aad: &str) -> Result<String, CryptoError> {
+      let plaintext = open(&self.aead_key()?, ciphertext, aad)?;
+      String::from_utf8(plaintext).map_err(|e| CryptoError::Format(e.to_string()))
+   }
+}
+
+/// Encrypt with a random nonce, returning base64 of `nonce || ciphertext ||
+/// tag`
+fn seal(key: &LessSafeKey, plaintext: &[u8], aad: &str) -> Result<String, CryptoError> {
+   let nonce_bytes = random_bytes::<NONCE_LEN>()?;
+   let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+
+   let mut in_out = plaintext.to_vec();
+   key.seal_in_place_append_tag(nonce, Aad::from(aad.as_bytes()), &mut in_out)
+      .map_err(|_| CryptoError::Encrypt)?;
+
+   let mut out = Vec::with_capacity(NONCE_LEN + in_out.len());
+   out.extend_from_slice(&nonce_bytes);
+   out.extend_from_slice(&in_out);
+
+   Ok(BASE64.encode(&out))
+}
+
+fn open(key: &LessSafeKey, sealed: &str, aad: &str) -> Result<Vec<u8>, CryptoError> {
+   let data = BASE64
+      .decode(sealed.as_bytes())
+      .map_err(|e| CryptoError::Format(e.to_string()))?;
+
+   if data.len() < NONCE_LEN {
+      return Err(CryptoError::Format("ciphertext is too short".to_string()));
+   }
+
+   let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+   let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| CryptoError::Decrypt)?;
+
+   let mut in_out = ciphertext.to_vec();
+   let plaintext = key
+      .open_in_place(nonce, Aad::from(aad.as_bytes()), &mut in_out)
+      .map_err(|_| CryptoError::Decrypt)?;
+
+   Ok(plaintext.to_vec())
+}
+
+fn write_key_file(path: &Path, key: &str) -> Result<(), CryptoError> {
+   let mut options = std::fs::OpenOptions::new();
+   options.write(true).create_new(true);
+
+   #[cfg(unix)]
+   {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(0o600);
+   }
+
+   let mut file = options
+      .open(path)
+      .map_err(|e| CryptoError::Key(format!("Failed to create {path:?}: {e}")))?;
+
+   writeln!(file, "{key}").map_err(|e| CryptoError::Key(format!("Failed to write {path:?}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn ring(keys: &[u8]) -> KeyRing {
+      let keys: Vec<Vec<u8>> = keys.iter().map(|&b| vec![b; KEY_LEN]).collect();
+      KeyRing::from_keys(&keys).unwrap()
+   }
+
+   #[test]
+   fn round_trip() {
+      let keys = ring(&[1]);
+      let (key, wrapped) = keys.generate_data_key("123:data_key").unwrap();
+      let ciphertext = key.encrypt("secret", "123:auth_token").unwrap();
+
+      let key = keys.unwrap_data_key(&wrapped, "123:data_key").unwrap();
+      assert_eq!(
+         key.decrypt(&ciphertext, "123:auth_token").unwrap(),
+         "secret"
+      );
+   }
+
+   #[test]
+   fn aad_mismatch() {
+      let keys = ring(&[1]);
+      let (key, wrapped) = keys.generate_data_key("123:data_key").unwrap();
+      let ciphertext = key.encrypt("secret", "123:auth_token").unwrap();
+
+      // A value copied to another row or column doesn't decrypt
+      assert!(matches!(
+         key.decrypt(&ciphertext, "456:auth_token"),
+         Err(CryptoError::Decrypt)
+      ));
+      assert!(matches!(
+         key.decrypt(&ciphertext, "123:csrf_token"),
+         Err(CryptoError::Decrypt)
+      ));
+      assert!(matches!(
+         keys.unwrap_data_key(&wrapped, "456:data_key"),
+         Err(CryptoError::Decrypt)
+      ));
+   }
+
+   #[test]
+   fn rotation() {
+      let old = ring(&[1]);
+      let (key, wrapped) = old.generate_data_key("123:data_key").unwrap();
+      let ciphertext = key.encrypt("secret", "123:auth_token").unwrap();
+
+      // The new key ring still unwraps keys of the previous master key
+      let new = ring(&[2, 1]);
+      assert!(old.is_current(&wrapped));
+      assert!(!new.is_current(&wrapped));
+      let key = new.unwrap_data_key(&wrapped, "123:data_key").unwrap();
+
+      let rewrapped = new.wrap_data_key(&key, "123:data_key").unwrap();
+      assert!(new.is_current(&rewrapped));
+
+      // Once re-wrapped, the previous master key isn't needed anymore
+      let key = ring(&[2])
+         .unwrap_data_key(&rewrapped, "123:data_key")
+         .unwrap();
+      assert_eq!(
+         key.decrypt(&ciphertext, "123:auth_token").unwrap(),
+         "secret"
+      );
+   }
+
+   #[test]
+   fn unknown_key_id() {
+      let (_, wrapped) = ring(&[1]).generate_data_key("123:data_key").unwrap();
+
+      let result = ring(&[2]).unwrap_data_key(&wrapped, "123:data_key");
+      assert!(matches!(result, Err(CryptoError::UnknownKey(id)) if id == ring(&[1]).current_id()));
+   }
+
+   #[test]
+   fn parse_key_file() {
+      let keys = KeyRing::parse(
+         format!(
+            "# current\n{}\n\n{}\n",
+            BASE64.encode(&[2; KEY_LEN]),
+            BASE64.encode(&[1; KEY_LEN])
+         )
+         .lines(),
+      )
+      .unwrap();
+
+      assert_eq!(keys.current_id(), ring(&[2]).current_id());
+      assert_eq!(keys.previous.len(), 1);
+      assert!(KeyRing::parse(["c2hvcnQ="].into_iter()).is_err());
+   }
+}
@@ -3,22 +3,57 @@ use std::{
    sync::Mutex,
 };
 
+use data_encoding::BASE64;
 use rusqlite::{
    Connection,
+   OptionalExtension,
    params,
+   types::{
+      FromSql,
+      FromSqlError,
+      FromSqlResult,
+      ValueRef,
+   },
+};
+use tracing::{
+   info,
+   warn,
 };
 
-use crate::twitter::TwitterAuth;
+use crate::{
+   crypto::{
+      CryptoError,
+      KeyRing,
+   },
+   migrations,
+   preferences::Preferences,
+   twitter::TwitterAuth,
+};
 
 #[derive(Debug)]
 pub enum DbError {
    Sqlite(rusqlite::Error),
+   Crypto(CryptoError),
+   SchemaTooNew {
+      found:     u32,
+      supported: u32,
+   },
+   /// Creating the backup before a destructive migration failed
+   Backup(String),
 }
 
 impl std::fmt::Display for DbError {
    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
       match self {
          DbError::Sqlite(e) => write!(f, "SQLite error: {e}"),
+         DbError::Crypto(e) => write!(f, "Crypto error: {e}"),
+         DbError::SchemaTooNew { found, supported } => {
+            write!(
+               f,
+               "Database schema version {found} is newer than the {supported} this binary supports"
+            )
+         },
+         DbError::Backup(e) => write!(f, "Failed to back up database: {e}"),
       }
    }
 }
@@ -31,14 +66,115 @@ impl From<rusqlite::Error> for DbError {
    }
 }
 
+impl From<CryptoError> for DbError {
+   fn from(e: CryptoError) -> Self {
+      DbError::Crypto(e)
+   }
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
    pub id:                    i64,
    pub twitter_user_id:       String,
    pub auth_token:            String,
    pub csrf_token:            String,
-   pub up_endpoint:           String,
    pub last_notif_sort_index: Option<String>,
+   pub status:                UserStatus,
+   /// Consecutive polls Twitter rejected the session for
+   pub auth_failures:         u32,
+   /// Newest direct message seen, `None` until the inbox was first read
+   pub last_dm_id:            Option<i64>,
+   pub last_dm_unread_count:  i32,
+   pub preferences:           Preferences,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserStatus {
+   Active,
+   /// Twitter keeps rejecting the stored session, so polling is paused until
+   /// the user registers again
+   ReauthRequired,
+   /// An operator paused polling; registering again doesn't resume it
+   Disabled,
+}
+
+impl UserStatus {
+   pub fn as_str(self) -> &'static str {
+      match self {
+         UserStatus::Active => "active",
+         UserStatus::ReauthRequired => "reauth_required",
+         UserStatus::Disabled => "disabled",
+      }
+   }
+}
+
+impl FromSql for UserStatus {
+   fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+      match value.as_str()? {
+         "active" => Ok(UserStatus::Active),
+         "reauth_required" => Ok(UserStatus::ReauthRequired),
+         "disabled" => Ok(UserStatus::Disabled),
+         other => {
+            Err(FromSqlError::Other(
+               format!("unknown user status {other:?}").into(),
+            ))
+         },
+      }
+   }
+}
+
+/// What became of a notification, as kept in its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+   /// Sent to at least one device
+   Delivered,
+   /// Every send failed transiently and is being retried
+   Queued,
+   /// The user had no enabled devices
+   Undelivered,
+   /// Dropped by the user's preferences
+   Filtered,
+   /// Waiting for quiet hours to end
+   Held,
+   /// Waiting for the user's next digest
+   Digest,
+}
+
+impl DeliveryStatus {
+   pub fn as_str(self) -> &'static str {
+      match self {
+         DeliveryStatus::Delivered => "delivered",
+         DeliveryStatus::Queued => "queued",
+         DeliveryStatus::Undelivered => "undelivered",
+         DeliveryStatus::Filtered => "filtered",
+         DeliveryStatus::Held => "held",
+         DeliveryStatus::Digest => "digest",
+      }
+   }
+}
+
+impl FromSql for DeliveryStatus {
+   fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+      match value.as_str()? {
+         "delivered" => Ok(DeliveryStatus::Delivered),
+         "queued" => Ok(DeliveryStatus::Queued),
+         "undelivered" => Ok(DeliveryStatus::Undelivered),
+         "filtered" => Ok(DeliveryStatus::Filtered),
+         "held" => Ok(DeliveryStatus::Held),
+         "digest" => Ok(DeliveryStatus::Digest),
+         other => {
+            Err(FromSqlError::Other(
+               format!("unknown delivery status {other:?}").into(),
+            ))
+         },
+      }
+   }
+}
+
+impl FromSql for Preferences {
+   fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+      serde_json::from_str(value.as_str()?).map_err(|e| FromSqlError::Other(e.into()))
+   }
 }
 
 impl User {
@@ -50,42 +186,249 @@ impl User {
    }
 }
 
+#[derive(Debug, Clone)]
+pub struct Device {
+   pub id:                   i64,
+   pub user_id:              i64,
+   pub up_endpoint:          String,
+   pub consecutive_failures: u32,
+   pub last_error:           Option<String>,
+   pub disabled_at:          Option<i64>,
+   pub disabled_reason:      Option<String>,
+   pub push_keys:            Option<PushKeys>,
+}
+
+/// Web Push subscription keys of a device, base64url encoded as registered
+#[derive(Debug, Clone)]
+pub struct PushKeys {
+   pub p256dh: String,
+   pub auth:   String,
+}
+
+impl Device {
+   pub fn is_enabled(&self) -> bool {
+      self.disabled_at.is_none()
+   }
+}
+
+/// Additional authenticated data binding an encrypted column to its row
+pub fn column_aad(twitter_user_id: &str, column: &str) -> String {
+   format!("{twitter_user_id}:{column}")
+}
+
+/// AAD owner of a server key, kept apart from Twitter user IDs
+fn server_key_owner(name: &str) -> String {
+   format!("server:{name}")
+}
+
+/// Queries selecting `(row ID, AAD owner, wrapped data key)` from every table
+/// with envelope-encrypted columns, and updating a row's wrapped data key
+const ENCRYPTED_TABLES: &[(&str, &str)] = &[
+   (
+      "SELECT id, twitter_user_id, data_key FROM users WHERE data_key IS NOT NULL",
+      "UPDATE users SET data_key = ?1 WHERE id = ?2",
+   ),
+   (
+      "SELECT rowid, 'server:' || name, data_key FROM server_keys",
+      "UPDATE server_keys SET data_key = ?1 WHERE rowid = ?2",
+   ),
+];
+
+/// User columns read by [`Db::user_row`], with the wrapped data key last
+const USER_COLUMNS: &str = "id, twitter_user_id, auth_token, csrf_token, last_notif_sort_index, \
+                            status, auth_failures, last_dm_id, last_dm_unread_count, preferences, \
+                            data_key";
+
+const DEVICE_COLUMNS: &str = "devices.id, devices.user_id, devices.up_endpoint, \
+                              devices.consecutive_failures, devices.last_error, \
+                              devices.disabled_at, devices.disabled_reason, devices.p256dh, \
+                              devices.auth_secret";
+
+/// A push waiting in the retry queue
+#[derive(Debug)]
+pub struct QueuedPush {
+   pub id:           i64,
+   pub device:       Device,
+   /// The serialized [`crate::twitter::Notification`]
+   pub notification: String,
+   pub attempts:     u32,
+}
+
+/// A notification held back for a digest or until quiet hours end
+#[derive(Debug)]
+pub struct HeldNotification {
+   pub id:           i64,
+   /// The serialized [`crate::twitter::Notification`]
+   pub notification: String,
+}
+
+/// A user as shown to operators, without any credentials
+#[derive(Debug)]
+pub struct UserSummary {
+   pub id:                   i64,
+   pub twitter_user_id:      String,
+   pub status:               UserStatus,
+   pub auth_failures:        u32,
+   pub last_poll_at:         Option<i64>,
+   pub last_poll_error:      Option<String>,
+   pub device_count:         u32,
+   pub enabled_device_count: u32,
+   pub created_at:           i64,
+}
+
+/// Selects the columns read by [`Db::user_summary_row`]
+const USER_SUMMARY_QUERY: &str = r#"
+            SELECT users.id, users.twitter_user_id, users.status, users.auth_failures,
+                   users.last_poll_at, users.last_poll_error,
+                   COUNT(devices.id), COALESCE(SUM(devices.disabled_at IS NULL), 0),
+                   users.created_at
+            FROM users
+            LEFT JOIN devices ON devices.user_id = users.id
+            "#;
+
+/// Users by status and the sizes of the queues, for metrics
+#[derive(Debug)]
+pub struct MetricCounts {
+   pub active_users:          i64,
+   pub reauth_required_users: i64,
+   pub disabled_users:        i64,
+   /// Active users whose last poll failed
+   pub errored_users:         i64,
+   pub queued_pushes:         i64,
+   pub digest_items:          i64,
+   pub held_notifications:    i64,
+}
+
+/// A notification from a user's history
+#[derive(Debug)]
+pub struct HistoryEntry {
+   pub sort_index:        String,
+   pub notification_type: String,
+   /// The serialized [`crate::twitter::Notification`]
+   pub notification:      String,
+   pub status:            DeliveryStatus,
+   pub created_at:        i64,
+   pub updated_at:        i64,
+}
+
 pub struct Db {
    conn: Mutex<Connection>,
+   keys: KeyRing,
 }
 
 impl Db {
-   pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DbError> {
-      let conn = Connection::open(path)?;
+   pub fn open<P: AsRef<Path>>(path: P, keys: KeyRing) -> Result<Self, DbError> {
+      let mut conn = Connection::open(&path)?;
+      conn.pragma_update(None, "foreign_keys", true)?;
+      // Zero out deleted and overwritten content, so credentials that were
+      // replaced (e.g. plaintext ones encrypted by a migration) don't linger in
+      // free pages of the database file
+      conn.pragma_update(None, "secure_delete", true)?;
+      migrations::run(&mut conn, path.as_ref(), &keys)?;
+
       let db = Db {
          conn: Mutex::new(conn),
+         keys,
       };
-      db.init_schema()?;
+      db.rotate_data_keys()?;
       Ok(db)
    }
 
-   fn init_schema(&self) -> Result<(), DbError> {
+   /// Re-wrap data keys that were wrapped by a previous master key. Only the
+   /// small wrapped keys change; the encrypted tokens are left untouched. Keys
+   /// that can't be unwrapped (e.g. their master key was dropped from the key
+   /// ring) are skipped, like rows that can't be decrypted when polling.
+   fn rotate_data_keys(&self) -> Result<(), DbError> {
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction()?;
+
+      let mut rotated = 0;
+      for (select, update) in ENCRYPTED_TABLES {
+         let rows = tx
+            .prepare(select)?
+            .query_map([], |row| {
+               Ok((
+                  row.get::<_, i64>(0)?,
+                  row.get::<_, String>(1)?,
+                  row.get::<_, String>(2)?,
+               ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+         for (id, owner, wrapped) in rows {
+            if self.keys.is_current(&wrapped) {
+               continue;
+            }
+
+            let aad = column_aad(&owner, "data_key");
+            let key = match self.keys.unwrap_data_key(&wrapped, &aad) {
+               Ok(key) => key,
+               Err(e) => {
+                  warn!(row = id, "Skipping data key that can't be unwrapped: {e}");
+                  continue;
+               },
+            };
+
+            tx.execute(update, params![self.keys.wrap_data_key(&key, &aad)?, id])?;
+            rotated += 1;
+         }
+      }
+
+      tx.commit()?;
+
+      if rotated > 0 {
+         info!(
+            "Re-wrapped {rotated} data keys with master key {}",
+            self.keys.current_id()
+         );
+      }
+
+      Ok(())
+   }
+
+   /// Load a secret the server keeps for itself, generating and storing it on
+   /// first use. It's encrypted the same way user tokens are.
+   pub fn get_or_create_server_key(
+      &self,
+      name: &str,
+      generate: impl FnOnce() -> Result<Vec<u8>, CryptoError>,
+   ) -> Result<Vec<u8>, DbError> {
+      let owner = server_key_owner(name);
       let conn = self.conn.lock().unwrap();
 
-      conn.execute_batch(
-         r#"
-            -- Users registered for notifications
-            CREATE TABLE IF NOT EXISTS users (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                twitter_user_id TEXT UNIQUE NOT NULL,
-                auth_token TEXT NOT NULL,
-                csrf_token TEXT NOT NULL,
-                up_endpoint TEXT NOT NULL,
-                last_notif_sort_index TEXT,
-                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
-                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
-            );
+      let stored: Option<(String, String)> = conn
+         .query_row(
+            "SELECT value, data_key FROM server_keys WHERE name = ?1",
+            params![name],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+         )
+         .optional()?;
 
-            CREATE INDEX IF NOT EXISTS idx_users_twitter_id ON users(twitter_user_id);
-            "#,
+      if let Some((value, wrapped)) = stored {
+         let key = self
+            .keys
+            .unwrap_data_key(&wrapped, &column_aad(&owner, "data_key"))?;
+         let encoded = key.decrypt(&value, &column_aad(&owner, "value"))?;
+
+         return BASE64
+            .decode(encoded.as_bytes())
+            .map_err(|e| DbError::Crypto(CryptoError::Format(e.to_string())));
+      }
+
+      let secret = generate()?;
+
+      let (key, wrapped) = self
+         .keys
+         .generate_data_key(&column_aad(&owner, "data_key"))?;
+      let value = key.encrypt(&BASE64.encode(&secret), &column_aad(&owner, "value"))?;
+
+      conn.execute(
+         "INSERT INTO server_keys (name, value, data_key) VALUES (?1, ?2, ?3)",
+         params![name, value, wrapped],
       )?;
 
-      Ok(())
+      info!("Generated server key {name}");
+      Ok(secret)
    }
 
    pub fn register_user(
@@ -94,30 +437,62 @@ impl Db {
       auth_token: &str,
       csrf_token: &str,
       up_endpoint: &str,
+      push_keys: Option<&PushKeys>,
+      management_token_hash: &str,
    ) -> Result<i64, DbError> {
-      let conn = self.conn.lock().unwrap();
+      let (key, wrapped) = self
+         .keys
+         .generate_data_key(&column_aad(twitter_user_id, "data_key"))?;
+      let auth_token = key.encrypt(auth_token, &column_aad(twitter_user_id, "auth_token"))?;
+      let csrf_token = key.encrypt(csrf_token, &column_aad(twitter_user_id, "csrf_token"))?;
+
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction()?;
 
       // Upsert: insert or update if exists
-      conn.execute(
+      let id: i64 = tx.query_row(
          r#"
-            INSERT INTO users (twitter_user_id, auth_token, csrf_token, up_endpoint, updated_at)
+            INSERT INTO users (twitter_user_id, auth_token, csrf_token, data_key, updated_at)
             VALUES (?1, ?2, ?3, ?4, strftime('%s', 'now'))
             ON CONFLICT(twitter_user_id) DO UPDATE SET
                 auth_token = excluded.auth_token,
                 csrf_token = excluded.csrf_token,
-                up_endpoint = excluded.up_endpoint,
+                data_key = excluded.data_key,
+                status = CASE WHEN status = 'disabled' THEN status ELSE 'active' END,
+                auth_failures = 0,
                 updated_at = strftime('%s', 'now')
+            RETURNING id
             "#,
-         params![twitter_user_id, auth_token, csrf_token, up_endpoint],
+         params![twitter_user_id, auth_token, csrf_token, wrapped],
+         |row| row.get(0),
       )?;
 
-      // Get the user ID
-      let id: i64 = conn.query_row(
-         "SELECT id FROM users WHERE twitter_user_id = ?1",
-         params![twitter_user_id],
-         |row| row.get(0),
+      // Add the device, or refresh it if it was already registered
+      tx.execute(
+         r#"
+            INSERT INTO devices (user_id, up_endpoint, p256dh, auth_secret, management_token_hash)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(user_id, up_endpoint) DO UPDATE SET
+                p256dh = excluded.p256dh,
+                auth_secret = excluded.auth_secret,
+                management_token_hash = excluded.management_token_hash,
+                consecutive_failures = 0,
+                last_error = NULL,
+                disabled_at = NULL,
+                disabled_reason = NULL,
+                updated_at = strftime('%s', 'now')
+            "#,
+         params![
+            id,
+            up_endpoint,
+            push_keys.map(|k| &k.p256dh),
+            push_keys.map(|k| &k.auth),
+            management_token_hash
+         ],
       )?;
 
+      tx.commit()?;
+
       Ok(id)
    }
 
@@ -131,32 +506,723 @@ impl Db {
       Ok(rows > 0)
    }
 
+   /// Remove a single device. A user left without devices is removed too,
+   /// since there's nowhere to deliver their notifications.
+   pub fn unregister_device(
+      &self,
+      twitter_user_id: &str,
+      up_endpoint: &str,
+   ) -> Result<bool, DbError> {
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction()?;
+
+      let rows = tx.execute(
+         r#"
+            DELETE FROM devices
+            WHERE up_endpoint = ?2
+              AND user_id = (SELECT id FROM users WHERE twitter_user_id = ?1)
+            "#,
+         params![twitter_user_id, up_endpoint],
+      )?;
+
+      tx.execute(
+         r#"
+            DELETE FROM users
+            WHERE twitter_user_id = ?1
+              AND NOT EXISTS (SELECT 1 FROM devices WHERE devices.user_id = users.id)
+            "#,
+         params![twitter_user_id],
+      )?;
+
+      tx.commit()?;
+
+      Ok(rows > 0)
+   }
+
+   /// Active users with at least one enabled device, i.e. everyone worth
+   /// polling
    pub fn get_all_users(&self) -> Result<Vec<User>, DbError> {
+      let rows = {
+         let conn = self.conn.lock().unwrap();
+
+         let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {USER_COLUMNS}
+            FROM users
+            WHERE status = 'active' AND EXISTS (
+                SELECT 1 FROM devices
+                WHERE devices.user_id = users.id AND devices.disabled_at IS NULL
+            )
+            "#
+         ))?;
+
+         stmt
+            .query_map([], Self::user_row)?
+            .collect::<Result<Vec<_>, _>>()?
+      };
+
+      // A row that can't be decrypted (e.g. its master key was dropped from the
+      // key ring) shouldn't stop everyone else from being polled
+      let users = rows
+         .into_iter()
+         .filter_map(|(mut user, wrapped)| {
+            match self.decrypt_tokens(&mut user, &wrapped) {
+               Ok(()) => Some(user),
+               Err(e) => {
+                  warn!(user = user.id, "Skipping user: {e}");
+                  None
+               },
+            }
+         })
+         .collect();
+
+      Ok(users)
+   }
+
+   pub fn get_user(&self, twitter_user_id: &str) -> Result<Option<User>, DbError> {
+      let row = {
+         let conn = self.conn.lock().unwrap();
+
+         conn
+            .query_row(
+               &format!("SELECT {USER_COLUMNS} FROM users WHERE twitter_user_id = ?1"),
+               params![twitter_user_id],
+               Self::user_row,
+            )
+            .optional()?
+      };
+
+      let Some((mut user, wrapped)) = row else {
+         return Ok(None);
+      };
+
+      self.decrypt_tokens(&mut user, &wrapped)?;
+      Ok(Some(user))
+   }
+
+   /// Map a user row with still-encrypted tokens, alongside its wrapped data
+   /// key
+   fn user_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<(User, String)> {
+      Ok((
+         User {
+            id:                    row.get(0)?,
+            twitter_user_id:       row.get(1)?,
+            auth_token:            row.get(2)?,
+            csrf_token:            row.get(3)?,
+            last_notif_sort_index: row.get(4)?,
+            status:                row.get(5)?,
+            auth_failures:         row.get(6)?,
+            last_dm_id:            row.get(7)?,
+            last_dm_unread_count:  row.get(8)?,
+            preferences:           row.get(9)?,
+         },
+         row.get(10)?,
+      ))
+   }
+
+   fn decrypt_tokens(&self, user: &mut User, wrapped: &str) -> Result<(), DbError> {
+      let id = &user.twitter_user_id;
+      let key = self
+         .keys
+         .unwrap_data_key(wrapped, &column_aad(id, "data_key"))?;
+
+      user.auth_token = key.decrypt(&user.auth_token, &column_aad(id, "auth_token"))?;
+      user.csrf_token = key.decrypt(&user.csrf_token, &column_aad(id, "csrf_token"))?;
+
+      Ok(())
+   }
+
+   pub fn get_devices(&self, user_id: i64) -> Result<Vec<Device>, DbError> {
       let conn = self.conn.lock().unwrap();
 
-      let mut stmt = conn.prepare(
+      let mut stmt = conn.prepare(&format!(
+         "SELECT {DEVICE_COLUMNS} FROM devices WHERE user_id = ?1"
+      ))?;
+
+      let devices = stmt
+         .query_map(params![user_id], Self::device_row)?
+         .collect::<Result<Vec<_>, _>>()?;
+
+      Ok(devices)
+   }
+
+   /// The device a management token was issued to
+   pub fn get_device_by_token(&self, token_hash: &str) -> Result<Option<Device>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let device = conn
+         .query_row(
+            &format!("SELECT {DEVICE_COLUMNS} FROM devices WHERE management_token_hash = ?1"),
+            params![token_hash],
+            Self::device_row,
+         )
+         .optional()?;
+
+      Ok(device)
+   }
+
+   /// Replace a device's management token, invalidating the old one
+   pub fn set_management_token(&self, device_id: i64, token_hash: &str) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
          r#"
-            SELECT id, twitter_user_id, auth_token, csrf_token, up_endpoint, last_notif_sort_index
-            FROM users
+            UPDATE devices
+            SET management_token_hash = ?1, updated_at = strftime('%s', 'now')
+            WHERE id = ?2
             "#,
+         params![token_hash, device_id],
       )?;
 
-      let users = stmt
+      Ok(())
+   }
+
+   /// Map the leading [`DEVICE_COLUMNS`] of a row
+   fn device_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Device> {
+      Ok(Device {
+         id:                   row.get(0)?,
+         user_id:              row.get(1)?,
+         up_endpoint:          row.get(2)?,
+         consecutive_failures: row.get(3)?,
+         last_error:           row.get(4)?,
+         disabled_at:          row.get(5)?,
+         disabled_reason:      row.get(6)?,
+         push_keys:            row
+            .get::<_, Option<String>>(7)?
+            .zip(row.get::<_, Option<String>>(8)?)
+            .map(|(p256dh, auth)| PushKeys { p256dh, auth }),
+      })
+   }
+
+   pub fn record_delivery_success(&self, device_id: i64) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            UPDATE devices
+            SET consecutive_failures = 0, last_error = NULL, last_success_at = strftime('%s', 'now')
+            WHERE id = ?1
+            "#,
+         params![device_id],
+      )?;
+
+      Ok(())
+   }
+
+   /// Record a failed delivery, returning the device's consecutive failure
+   /// count
+   pub fn record_delivery_failure(&self, device_id: i64, error: &str) -> Result<u32, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let failures = conn.query_row(
+         r#"
+            UPDATE devices
+            SET consecutive_failures = consecutive_failures + 1, last_error = ?1
+            WHERE id = ?2
+            RETURNING consecutive_failures
+            "#,
+         params![error, device_id],
+         |row| row.get(0),
+      )?;
+
+      Ok(failures)
+   }
+
+   /// Stop delivering to a device until it registers again, dropping any
+   /// pushes still queued for it
+   pub fn disable_device(&self, device_id: i64, reason: &str) -> Result<(), DbError> {
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction()?;
+
+      tx.execute(
+         r#"
+            UPDATE devices
+            SET disabled_at = strftime('%s', 'now'), disabled_reason = ?1,
+                updated_at = strftime('%s', 'now')
+            WHERE id = ?2
+            "#,
+         params![reason, device_id],
+      )?;
+
+      tx.execute("DELETE FROM push_queue WHERE device_id = ?1", params![
+         device_id
+      ])?;
+
+      tx.commit()?;
+
+      Ok(())
+   }
+
+   /// Queue a push for another attempt after `delay_secs`
+   pub fn enqueue_push(
+      &self,
+      device_id: i64,
+      notification: &str,
+      delay_secs: u64,
+      error: &str,
+   ) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            INSERT INTO push_queue (device_id, notification, next_attempt_at, last_error)
+            VALUES (?1, ?2, strftime('%s', 'now') + ?3, ?4)
+            "#,
+         params![device_id, notification, delay_secs as i64, error],
+      )?;
+
+      Ok(())
+   }
+
+   /// Queued pushes whose next attempt is due, oldest first
+   pub fn get_due_pushes(&self) -> Result<Vec<QueuedPush>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let mut stmt = conn.prepare(&format!(
+         r#"
+            SELECT {DEVICE_COLUMNS}, push_queue.id, push_queue.notification, push_queue.attempts
+            FROM push_queue
+            JOIN devices ON devices.id = push_queue.device_id
+            WHERE push_queue.next_attempt_at <= strftime('%s', 'now')
+            ORDER BY push_queue.id
+            "#
+      ))?;
+
+      let pushes = stmt
          .query_map([], |row| {
-            Ok(User {
-               id:                    row.get(0)?,
-               twitter_user_id:       row.get(1)?,
-               auth_token:            row.get(2)?,
-               csrf_token:            row.get(3)?,
-               up_endpoint:           row.get(4)?,
-               last_notif_sort_index: row.get(5)?,
+            Ok(QueuedPush {
+               device:       Self::device_row(row)?,
+               id:           row.get(9)?,
+               notification: row.get(10)?,
+               attempts:     row.get(11)?,
             })
          })?
          .collect::<Result<Vec<_>, _>>()?;
 
+      Ok(pushes)
+   }
+
+   pub fn reschedule_push(
+      &self,
+      push_id: i64,
+      attempts: u32,
+      delay_secs: u64,
+      error: &str,
+   ) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            UPDATE push_queue
+            SET attempts = ?1, next_attempt_at = strftime('%s', 'now') + ?2, last_error = ?3
+            WHERE id = ?4
+            "#,
+         params![attempts, delay_secs as i64, error, push_id],
+      )?;
+
+      Ok(())
+   }
+
+   pub fn delete_push(&self, push_id: i64) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute("DELETE FROM push_queue WHERE id = ?1", params![push_id])?;
+
+      Ok(())
+   }
+
+   /// Give up on pushes queued longer than `max_age_secs`, returning how many
+   /// were dropped
+   pub fn expire_pushes(&self, max_age_secs: u64) -> Result<usize, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let rows = conn.execute(
+         "DELETE FROM push_queue WHERE created_at < strftime('%s', 'now') - ?1",
+         params![max_age_secs as i64],
+      )?;
+
+      Ok(rows)
+   }
+
+   /// Record a poll Twitter rejected the session for, returning the
+   /// consecutive count
+   pub fn record_auth_failure(&self, user_id: i64) -> Result<u32, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let failures = conn.query_row(
+         r#"
+            UPDATE users
+            SET auth_failures = auth_failures + 1
+            WHERE id = ?1
+            RETURNING auth_failures
+            "#,
+         params![user_id],
+         |row| row.get(0),
+      )?;
+
+      Ok(failures)
+   }
+
+   pub fn reset_auth_failures(&self, user_id: i64) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute("UPDATE users SET auth_failures = 0 WHERE id = ?1", params![
+         user_id
+      ])?;
+
+      Ok(())
+   }
+
+   pub fn set_user_status(&self, user_id: i64, status: UserStatus) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            UPDATE users
+            SET status = ?1, updated_at = strftime('%s', 'now')
+            WHERE id = ?2
+            "#,
+         params![status.as_str(), user_id],
+      )?;
+
+      Ok(())
+   }
+
+   /// Apply a change to a user's preferences, returning the result
+   pub fn update_preferences(
+      &self,
+      user_id: i64,
+      update: impl FnOnce(&mut Preferences),
+   ) -> Result<Preferences, DbError> {
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction()?;
+
+      let mut preferences: Preferences = tx.query_row(
+         "SELECT preferences FROM users WHERE id = ?1",
+         params![user_id],
+         |row| row.get(0),
+      )?;
+
+      update(&mut preferences);
+
+      let json = serde_json::to_string(&preferences).expect("preferences serialize to JSON");
+      tx.execute("UPDATE users SET preferences = ?1 WHERE id = ?2", params![
+         json, user_id
+      ])?;
+
+      tx.commit()?;
+
+      Ok(preferences)
+   }
+
+   /// Store the direct message cursor along with the unread count it was taken
+   /// at
+   pub fn update_dm_cursor(
+      &self,
+      user_id: i64,
+      last_dm_id: Option<i64>,
+      unread_count: i32,
+   ) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            UPDATE users
+            SET last_dm_id = ?1, last_dm_unread_count = ?2, updated_at = strftime('%s', 'now')
+            WHERE id = ?3
+            "#,
+         params![last_dm_id, unread_count, user_id],
+      )?;
+
+      Ok(())
+   }
+
+   /// Hold a notification for the user's next digest
+   pub fn add_digest_item(&self, user_id: i64, notification: &str) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         "INSERT INTO digest_items (user_id, notification) VALUES (?1, ?2)",
+         params![user_id, notification],
+      )?;
+
+      Ok(())
+   }
+
+   /// All of a user's held notifications, oldest first, once the oldest was
+   /// held for at least `window_secs`. Empty while the window is still open.
+   pub fn get_due_digest(
+      &self,
+      user_id: i64,
+      window_secs: u64,
+   ) -> Result<Vec<HeldNotification>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let mut stmt = conn.prepare(
+         r#"
+            SELECT id, notification
+            FROM digest_items
+            WHERE user_id = ?1
+              AND (SELECT MIN(created_at) FROM digest_items WHERE user_id = ?1)
+                  <= strftime('%s', 'now') - ?2
+            ORDER BY id
+            "#,
+      )?;
+
+      let items = stmt
+         .query_map(params![user_id, window_secs as i64], |row| {
+            Ok(HeldNotification {
+               id:           row.get(0)?,
+               notification: row.get(1)?,
+            })
+         })?
+         .collect::<Result<Vec<_>, _>>()?;
+
+      Ok(items)
+   }
+
+   /// Drop held notifications up to and including `last_id` once their digest
+   /// went out
+   pub fn delete_digest_items(&self, user_id: i64, last_id: i64) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         "DELETE FROM digest_items WHERE user_id = ?1 AND id <= ?2",
+         params![user_id, last_id],
+      )?;
+
+      Ok(())
+   }
+
+   /// Hold a notification that arrived during quiet hours until `release_at`,
+   /// in Unix seconds
+   pub fn hold_notification(
+      &self,
+      user_id: i64,
+      notification: &str,
+      release_at: i64,
+   ) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            INSERT INTO held_notifications (user_id, notification, release_at)
+            VALUES (?1, ?2, ?3)
+            "#,
+         params![user_id, notification, release_at],
+      )?;
+
+      Ok(())
+   }
+
+   /// A user's held notifications due by `now`, in Unix seconds, oldest first
+   pub fn get_released_notifications(
+      &self,
+      user_id: i64,
+      now: i64,
+   ) -> Result<Vec<HeldNotification>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let mut stmt = conn.prepare(
+         r#"
+            SELECT id, notification
+            FROM held_notifications
+            WHERE user_id = ?1 AND release_at <= ?2
+            ORDER BY id
+            "#,
+      )?;
+
+      let notifications = stmt
+         .query_map(params![user_id, now], |row| {
+            Ok(HeldNotification {
+               id:           row.get(0)?,
+               notification: row.get(1)?,
+            })
+         })?
+         .collect::<Result<Vec<_>, _>>()?;
+
+      Ok(notifications)
+   }
+
+   pub fn delete_held_notifications(&self, ids: &[i64]) -> Result<(), DbError> {
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction()?;
+
+      for id in ids {
+         tx.execute("DELETE FROM held_notifications WHERE id = ?1", params![id])?;
+      }
+
+      tx.commit()?;
+
+      Ok(())
+   }
+
+   /// Record a notification in the user's history, or update what became of
+   /// it if it's there already
+   pub fn record_history(
+      &self,
+      user_id: i64,
+      sort_index: &str,
+      notification_type: &str,
+      notification: &str,
+      status: DeliveryStatus,
+   ) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            INSERT INTO notification_history
+                (user_id, sort_index, notification_type, notification, status)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(user_id, notification_type, sort_index) DO UPDATE SET
+                notification = excluded.notification,
+                status = excluded.status,
+                updated_at = strftime('%s', 'now')
+            "#,
+         params![
+            user_id,
+            sort_index,
+            notification_type,
+            notification,
+            status.as_str()
+         ],
+      )?;
+
+      Ok(())
+   }
+
+   /// A page of the user's history after `since`, oldest first, optionally
+   /// only of the given types
+   pub fn get_history(
+      &self,
+      user_id: i64,
+      since: Option<&str>,
+      types: &[String],
+      limit: u32,
+   ) -> Result<Vec<HistoryEntry>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      // The types are passed as a JSON array, so one statement fits any
+      // number of them
+      let types = serde_json::to_string(types).expect("strings serialize to JSON");
+
+      let mut stmt = conn.prepare(
+         r#"
+            SELECT sort_index, notification_type, notification, status, created_at, updated_at
+            FROM notification_history
+            WHERE user_id = ?1
+              AND (?2 IS NULL OR sort_index > ?2)
+              AND (json_array_length(?3) = 0
+                   OR notification_type IN (SELECT value FROM json_each(?3)))
+            ORDER BY sort_index
+            LIMIT ?4
+            "#,
+      )?;
+
+      let entries = stmt
+         .query_map(params![user_id, since, types, limit], |row| {
+            Ok(HistoryEntry {
+               sort_index:        row.get(0)?,
+               notification_type: row.get(1)?,
+               notification:      row.get(2)?,
+               status:            row.get(3)?,
+               created_at:        row.get(4)?,
+               updated_at:        row.get(5)?,
+            })
+         })?
+         .collect::<Result<Vec<_>, _>>()?;
+
+      Ok(entries)
+   }
+
+   /// Drop history older than `max_age_secs` and beyond the newest
+   /// `max_per_user` of each user, returning how many entries were dropped
+   pub fn prune_history(&self, max_age_secs: u64, max_per_user: u32) -> Result<usize, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let expired = conn.execute(
+         "DELETE FROM notification_history WHERE created_at < strftime('%s', 'now') - ?1",
+         params![max_age_secs as i64],
+      )?;
+
+      let excess = conn.execute(
+         r#"
+            DELETE FROM notification_history
+            WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY user_id ORDER BY sort_index DESC
+                    ) AS position
+                    FROM notification_history
+                )
+                WHERE position > ?1
+            )
+            "#,
+         params![max_per_user],
+      )?;
+
+      Ok(expired + excess)
+   }
+
+   /// Record when a user was last polled and why that failed, if it did
+   pub fn record_poll(&self, user_id: i64, error: Option<&str>) -> Result<(), DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      conn.execute(
+         r#"
+            UPDATE users
+            SET last_poll_at = strftime('%s', 'now'), last_poll_error = ?1
+            WHERE id = ?2
+            "#,
+         params![error, user_id],
+      )?;
+
+      Ok(())
+   }
+
+   /// Every user, oldest registration first
+   pub fn get_user_summaries(&self) -> Result<Vec<UserSummary>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let mut stmt = conn.prepare(&format!(
+         "{USER_SUMMARY_QUERY} GROUP BY users.id ORDER BY users.id"
+      ))?;
+
+      let users = stmt
+         .query_map([], Self::user_summary_row)?
+         .collect::<Result<Vec<_>, _>>()?;
+
       Ok(users)
    }
 
+   pub fn get_user_summary(&self, twitter_user_id: &str) -> Result<Option<UserSummary>, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let user = conn
+         .query_row(
+            &format!("{USER_SUMMARY_QUERY} WHERE users.twitter_user_id = ?1 GROUP BY users.id"),
+            params![twitter_user_id],
+            Self::user_summary_row,
+         )
+         .optional()?;
+
+      Ok(user)
+   }
+
+   fn user_summary_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<UserSummary> {
+      Ok(UserSummary {
+         id:                   row.get(0)?,
+         twitter_user_id:      row.get(1)?,
+         status:               row.get(2)?,
+         auth_failures:        row.get(3)?,
+         last_poll_at:         row.get(4)?,
+         last_poll_error:      row.get(5)?,
+         device_count:         row.get(6)?,
+         enabled_device_count: row.get(7)?,
+         created_at:           row.get(8)?,
+      })
+   }
+
    pub fn update_last_notif(&self, user_id: i64, sort_index: &str) -> Result<(), DbError> {
       let conn = self.conn.lock().unwrap();
 
@@ -179,4 +1245,239 @@ impl Db {
 
       Ok(count)
    }
+
+   pub fn get_metric_counts(&self) -> Result<MetricCounts, DbError> {
+      let conn = self.conn.lock().unwrap();
+
+      let counts = conn.query_row(
+         r#"
+            SELECT
+                (SELECT COUNT(*) FROM users WHERE status = 'active'),
+                (SELECT COUNT(*) FROM users WHERE status = 'reauth_required'),
+                (SELECT COUNT(*) FROM users WHERE status = 'disabled'),
+                (SELECT COUNT(*) FROM users
+                 WHERE status = 'active' AND last_poll_error IS NOT NULL),
+                (SELECT COUNT(*) FROM push_queue),
+                (SELECT COUNT(*) FROM digest_items),
+                (SELECT COUNT(*) FROM held_notifications)
+            "#,
+         [],
+         |row| {
+            Ok(MetricCounts {
+               active_users:          row.get(0)?,
+               reauth_required_users: row.get(1)?,
+               disabled_users:        row.get(2)?,
+               errored_users:         row.get(3)?,
+               queued_pushes:         row.get(4)?,
+               digest_items:          row.get(5)?,
+               held_notifications:    row.get(6)?,
+            })
+         },
+      )?;
+
+      Ok(counts)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn keys(keys: &[u8]) -> KeyRing {
+      let keys: Vec<Vec<u8>> = keys.iter().map(|&b| vec![b; 32]).collect();
+      KeyRing::from_keys(&keys).unwrap()
+   }
+
+   #[test]
+   fn rotates_data_keys_on_open() {
+      let dir = tempfile::tempdir().unwrap();
+      let path = dir.path().join("db.sqlite");
+
+      let db = Db::open(&path, keys(&[1])).unwrap();
+      db.register_user(
+         "123",
+         "auth",
+         "csrf",
+         "https://push.example/up",
+         None,
+         "hash",
+      )
+      .unwrap();
+      drop(db);
+
+      // Opening with a new current key re-wraps the data key under it
+      drop(Db::open(&path, keys(&[2, 1])).unwrap());
+
+      let db = Db::open(&path, keys(&[2])).unwrap();
+      let user = db.get_user("123").unwrap().unwrap();
+      assert_eq!(user.auth_token, "auth");
+      assert_eq!(user.csrf_token, "csrf");
+   }
+
+   #[test]
+   fn skips_data_keys_of_missing_master_keys() {
+      let dir = tempfile::tempdir().unwrap();
+      let path = dir.path().join("db.sqlite");
+
+      let db = Db::open(&path, keys(&[1])).unwrap();
+      db.register_user(
+         "123",
+         "auth",
+         "csrf",
+         "https://push.example/up",
+         None,
+         "hash",
+      )
+      .unwrap();
+      drop(db);
+
+      // The master key of the row is gone, which doesn't prevent opening
+      let db = Db::open(&path, keys(&[3])).unwrap();
+      assert!(db.get_all_users().unwrap().is_empty());
+      assert!(db.get_user("123").is_err());
+
+      // Nor does it lose the row, which decrypts once the key is back
+      drop(db);
+      let db = Db::open(&path, keys(&[3, 1])).unwrap();
+      assert_eq!(db.get_user("123").unwrap().unwrap().auth_token, "auth");
+   }
+
+   /// A database with users `123` and `456`, returning their IDs
+   fn with_users(path: &Path) -> (Db, i64, i64) {
+      let db = Db::open(path, keys(&[1])).unwrap();
+      let register = |twitter_user_id| {
+         db.register_user(
+            twitter_user_id,
+            "auth",
+            "csrf",
+            "https://push.example/up",
+            None,
+            twitter_user_id,
+         )
+         .unwrap()
+      };
+      let (first, second) = (register("123"), register("456"));
+      (db, first, second)
+   }
+
+   fn sort_indexes(entries: &[HistoryEntry]) -> Vec<&str> {
+      entries
+         .iter()
+         .map(|entry| entry.sort_index.as_str())
+         .collect()
+   }
+
+   #[test]
+   fn history_pages_by_sort_index() {
+      let dir = tempfile::tempdir().unwrap();
+      let (db, user, other) = with_users(&dir.path().join("db.sqlite"));
+
+      for n in [103, 101, 105, 102, 104] {
+         let sort_index = n.to_string();
+         db.record_history(user, &sort_index, "like", "{}", DeliveryStatus::Delivered)
+            .unwrap();
+      }
+      db.record_history(other, "100", "like", "{}", DeliveryStatus::Delivered)
+         .unwrap();
+
+      let page = db.get_history(user, None, &[], 2).unwrap();
+      assert_eq!(sort_indexes(&page), ["101", "102"]);
+      let page = db.get_history(user, Some("102"), &[], 2).unwrap();
+      assert_eq!(sort_indexes(&page), ["103", "104"]);
+      let page = db.get_history(user, Some("104"), &[], 2).unwrap();
+      assert_eq!(sort_indexes(&page), ["105"]);
+      assert!(
+         db.get_history(user, Some("105"), &[], 2)
+            .unwrap()
+            .is_empty()
+      );
+   }
+
+   #[test]
+   fn history_filters_by_type() {
+      let dir = tempfile::tempdir().unwrap();
+      let (db, user, _) = with_users(&dir.path().join("db.sqlite"));
+
+      for (sort_index, notification_type) in [
+         ("101", "like"),
+         ("102", "reply"),
+         ("103", "follow"),
+         ("104", "reply"),
+         ("105", "mention"),
+      ] {
+         db.record_history(
+            user,
+            sort_index,
+            notification_type,
+            "{}",
+            DeliveryStatus::Delivered,
+         )
+         .unwrap();
+      }
+
+      let types = |types: &[&str]| -> Vec<String> { types.iter().map(|t| t.to_string()).collect() };
+      let replies = db.get_history(user, None, &types(&["reply"]), 10).unwrap();
+      assert_eq!(sort_indexes(&replies), ["102", "104"]);
+      let conversation = db
+         .get_history(user, Some("102"), &types(&["reply", "mention"]), 10)
+         .unwrap();
+      assert_eq!(sort_indexes(&conversation), ["104", "105"]);
+      assert!(
+         db.get_history(user, None, &types(&["dm"]), 10)
+            .unwrap()
+            .is_empty()
+      );
+   }
+
+   #[test]
+   fn history_records_the_latest_status() {
+      let dir = tempfile::tempdir().unwrap();
+      let (db, user, _) = with_users(&dir.path().join("db.sqlite"));
+
+      db.record_history(user, "101", "like", "{}", DeliveryStatus::Held)
+         .unwrap();
+      db.record_history(user, "101", "like", r#"{"a":1}"#, DeliveryStatus::Delivered)
+         .unwrap();
+
+      let history = db.get_history(user, None, &[], 10).unwrap();
+      assert_eq!(history.len(), 1);
+      assert_eq!(history[0].status, DeliveryStatus::Delivered);
+      assert_eq!(history[0].notification, r#"{"a":1}"#);
+   }
+
+   #[test]
+   fn prunes_history_beyond_the_limits() {
+      let dir = tempfile::tempdir().unwrap();
+      let path = dir.path().join("db.sqlite");
+      let (db, user, other) = with_users(&path);
+
+      for n in 101..=105 {
+         let sort_index = n.to_string();
+         db.record_history(user, &sort_index, "like", "{}", DeliveryStatus::Delivered)
+            .unwrap();
+      }
+      db.record_history(other, "100", "like", "{}", DeliveryStatus::Delivered)
+         .unwrap();
+
+      // Only the newest of each user are kept
+      assert_eq!(db.prune_history(3600, 3).unwrap(), 2);
+      let history = db.get_history(user, None, &[], 10).unwrap();
+      assert_eq!(sort_indexes(&history), ["103", "104", "105"]);
+      assert_eq!(db.get_history(other, None, &[], 10).unwrap().len(), 1);
+      assert_eq!(db.prune_history(3600, 3).unwrap(), 0);
+
+      // And only for as long as they're young enough
+      Connection::open(&path)
+         .unwrap()
+         .execute(
+            "UPDATE notification_history SET created_at = created_at - 7200 WHERE sort_index IN \
+             ('103', '100')",
+            [],
+         )
+         .unwrap();
+      assert_eq!(db.prune_history(3600, 3).unwrap(), 2);
+      let history = db.get_history(user, None, &[], 10).unwrap();
+      assert_eq!(sort_indexes(&history), ["104", "105"]);
+      assert!(db.get_history(other, None, &[], 10).unwrap().is_empty());
+   }
 }
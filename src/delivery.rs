@@ -0,0 +1,507 @@
+use std::{
+   collections::HashSet,
+   sync::Arc,
+   time::Duration,
+};
+
+use tokio::time::interval;
+use tracing::{
+   error,
+   warn,
+};
+
+use crate::{
+   config::Config,
+   crypto,
+   db::{
+      Db,
+      DbError,
+      Device,
+   },
+   http_client::HttpClient,
+   metrics::Metrics,
+   twitter::Notification,
+   unified_push::{
+      self,
+      UpError,
+   },
+   vapid::Vapid,
+};
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+pub enum Delivery {
+   Delivered,
+   /// Failed transiently and was queued for a retry
+   Queued,
+   /// Failed and the device got disabled, so the push was dropped
+   Disabled,
+}
+
+/// Push a notification to one device, queueing it for a retry if that fails
+/// transiently. With `queue_only` set the send is skipped and the push goes
+/// straight to the queue, for a device that already failed during this poll.
+pub async fn deliver(
+   db: &Db,
+   client: &HttpClient,
+   config: &Config,
+   vapid: &Vapid,
+   device: &Device,
+   notif: &Notification,
+   queue_only: bool,
+) -> Result<Delivery, DbError> {
+   let payload = serde_json::to_string(notif).expect("notifications serialize to JSON");
+
+   if queue_only {
+      db.enqueue_push(
+         device.id,
+         &payload,
+         backoff(1).as_secs(),
+         "Device failed earlier in this poll",
+      )?;
+      return Ok(Delivery::Queued);
+   }
+
+   let e = match unified_push::send(client, vapid, device, notif).await {
+      Ok(()) => {
+         db.record_delivery_success(device.id)?;
+         return Ok(Delivery::Delivered);
+      },
+      Err(e) => e,
+   };
+
+   if !record_failure(db, config, device, &e)? {
+      return Ok(Delivery::Disabled);
+   }
+
+   db.enqueue_push(device.id, &payload, backoff(1).as_secs(), &e.to_string())?;
+   Ok(Delivery::Queued)
+}
+
+/// Account for a failed send, disabling the device after a permanent failure
+/// or too many transient ones. Returns whether the device is still enabled.
+fn record_failure(db: &Db, config: &Config, device: &Device, e: &UpError) -> Result<bool, DbError> {
+   warn!(
+      user = device.user_id,
+      device = device.id,
+      "Failed to send push: {e}"
+   );
+
+   let failures = db.record_delivery_failure(device.id, &e.to_string())?;
+
+   let reason = if e.is_permanent() {
+      format!("Endpoint is gone: {e}")
+   } else if failures >= config.max_push_failures {
+      format!("{failures} consecutive delivery failures, last: {e}")
+   } else {
+      return Ok(true);
+   };
+
+   warn!(
+      user = device.user_id,
+      device = device.id,
+      "Disabling device: {reason}"
+   );
+   db.disable_device(device.id, &reason)?;
+
+   Ok(false)
+}
+
+/// Exponential backoff before attempt `attempts + 1`, with "equal jitter":
+/// half the delay is fixed and the other half random, so retries of pushes
+/// that failed together spread out
+pub fn backoff(attempts: u32) -> Duration {
+   let exponent = attempts.saturating_sub(1).min(16);
+   let delay = RETRY_BASE_DELAY
+      .saturating_mul(1 << exponent)
+      .min(RETRY_MAX_DELAY);
+
+   delay / 2 + delay.mul_f64(crypto::random_f64() / 2.0)
+}
+
+/// Periodically retry queued pushes whose backoff has elapsed
+pub async fn run_retry_worker(
+   db: Arc<Db>,
+   client: Arc<HttpClient>,
+   config: Arc<Config>,
+   vapid: Arc<Vapid>,
+   metrics: Arc<Metrics>,
+) {
+   let mut retry_interval = interval(RETRY_INTERVAL);
+
+   loop {
+      retry_interval.tick().await;
+
+      if let Err(e) = retry_due(&db, &client, &config, &vapid, &metrics).await {
+         error!("Failed to process retry queue: {e}");
+      }
+   }
+}
+
+async fn retry_due(
+   db: &Db,
+   client: &HttpClient,
+   config: &Config,
+   vapid: &Vapid,
+   metrics: &Metrics,
+) -> Result<(), DbError> {
+   let expired = db.expire_pushes(config.push_retry_max_age_secs)?;
+   if expired > 0 {
+      warn!("Dropped {expired} pushes that were queued too long");
+   }
+
+   // Once a device fails, its remaining pushes wait for the next backoff
+   // instead of each failing (and counting against the device) in turn
+   let mut failed_devices = HashSet::new();
+
+   for push in db.get_due_pushes()? {
+      let attempts = push.attempts + 1;
+
+      if failed_devices.contains(&push.device.id) {
+         db.reschedule_push(
+            push.id,
+            attempts,
+            backoff(attempts).as_secs(),
+            "Device failed earlier in this run",
+         )?;
+         continue;
+      }
+
+      let notif: Notification = match serde_json::from_str(&push.notification) {
+         Ok(notif) => notif,
+         Err(e) => {
+            warn!("Dropping unreadable queued push {}: {e}", push.id);
+            db.delete_push(push.id)?;
+            continue;
+         },
+      };
+
+      let result = unified_push::send(client, vapid, &push.device, &notif).await;
+      metrics.record_push(result.is_ok());
+
+      match result {
+         Ok(()) => {
+            db.record_delivery_success(push.device.id)?;
+            db.delete_push(push.id)?;
+         },
+         Err(e) => {
+            // A disabled device has its queue cleared already
+            if record_failure(db, config, &push.device, &e)? {
+               db.reschedule_push(
+                  push.id,
+                  attempts,
+                  backoff(attempts).as_secs(),
+                  &e.to_string(),
+               )?;
+            }
+            failed_devices.insert(push.device.id);
+         },
+      }
+   }
+
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use std::sync::atomic::{
+      AtomicU32,
+      Ordering,
+   };
+
+   use axum::{
+      Router,
+      extract::State,
+      http::StatusCode,
+      routing::post,
+   };
+   use rusqlite::Connection;
+   use tempfile::TempDir;
+
+   use super::*;
+   use crate::{
+      crypto::KeyRing,
+      test_server,
+   };
+
+   /// A push service that fails the first `failures` pushes, returning its
+   /// endpoint and the count of pushes it got
+   async fn push_service(failures: u32) -> (String, Arc<AtomicU32>) {
+      failing_push_service(failures, StatusCode::INTERNAL_SERVER_ERROR).await
+   }
+
+   /// A push service that answers the first `failures` pushes with `status`
+   async fn failing_push_service(failures: u32, status: StatusCode) -> (String, Arc<AtomicU32>) {
+      let received = Arc::new(AtomicU32::new(0));
+      let app = Router::new()
+         .route(
+            "/up",
+            post(move |State(received): State<Arc<AtomicU32>>| {
+               async move {
+                  if received.fetch_add(1, Ordering::SeqCst) < failures {
+                     status
+                  } else {
+                     StatusCode::CREATED
+                  }
+               }
+            }),
+         )
+         .with_state(received.clone());
+
+      (format!("{}/up", test_server::serve(app).await), received)
+   }
+
+   struct Fixture {
+      dir:     TempDir,
+      db:      Db,
+      client:  HttpClient,
+      config:  Config,
+      vapid:   Vapid,
+      metrics: Metrics,
+   }
+
+   impl Fixture {
+      fn new() -> Self {
+         let dir = tempfile::tempdir().unwrap();
+         let keys = KeyRing::from_keys(&[vec![1; 32]]).unwrap();
+
+         Self {
+            db: Db::open(dir.path().join("db.sqlite"), keys).unwrap(),
+            dir,
+            client: HttpClient::allowing_http(),
+            config: Config {
+               max_push_failures: 5,
+               push_retry_max_age_secs: 60 * 60,
+               ..Config::default()
+            },
+            vapid: Vapid::new(&Vapid::generate_pkcs8().unwrap(), None).unwrap(),
+            metrics: Metrics::new(),
+         }
+      }
+
+      fn device(&self, endpoint: &str) -> Device {
+         let user_id = self
+            .db
+            .register_user("123", "auth", "csrf", endpoint, None, "hash")
+            .unwrap();
+         self.db.get_devices(user_id).unwrap().remove(0)
+      }
+
+      /// Change queued pushes behind the server's back, e.g. to let time pass
+      fn update_queue(&self, sql: &str) {
+         let conn = Connection::open(self.dir.path().join("db.sqlite")).unwrap();
+         conn.execute(sql, []).unwrap();
+      }
+
+      fn queued(&self) -> Vec<(u32, Option<String>)> {
+         let conn = Connection::open(self.dir.path().join("db.sqlite")).unwrap();
+         let mut stmt = conn
+            .prepare("SELECT attempts, last_error FROM push_queue ORDER BY id")
+            .unwrap();
+         stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+      }
+
+      async fn deliver(&self, device: &Device, queue_only: bool) -> Delivery {
+         let notif = Notification {
+            sort_index: "100".to_string(),
+            notification_type: "like".to_string(),
+            message: "Someone liked your post".to_string(),
+            ..Notification::default()
+         };
+
+         deliver(
+            &self.db,
+            &self.client,
+            &self.config,
+            &self.vapid,
+            device,
+            &notif,
+            queue_only,
+         )
+         .await
+         .unwrap()
+      }
+
+      async fn retry_due(&self) {
+         retry_due(
+            &self.db,
+            &self.client,
+            &self.config,
+            &self.vapid,
+            &self.metrics,
+         )
+         .await
+         .unwrap();
+      }
+   }
+
+   #[tokio::test]
+   async fn retries_until_delivered() {
+      let (endpoint, received) = push_service(2).await;
+      let fixture = Fixture::new();
+      let device = fixture.device(&endpoint);
+
+      assert!(matches!(
+         fixture.deliver(&device, false).await,
+         Delivery::Queued
+      ));
+      assert_eq!(received.load(Ordering::SeqCst), 1);
+      let queued = fixture.queued();
+      assert_eq!(queued.len(), 1);
+      assert_eq!(queued[0].0, 1);
+      assert!(queued[0].1.as_deref().unwrap().contains("500"));
+
+      // Nothing is retried before its backoff elapsed
+      fixture.retry_due().await;
+      assert_eq!(received.load(Ordering::SeqCst), 1);
+
+      fixture.update_queue("UPDATE push_queue SET next_attempt_at = 0");
+      fixture.retry_due().await;
+      assert_eq!(received.load(Ordering::SeqCst), 2);
+      assert_eq!(fixture.queued()[0].0, 2);
+      let device = fixture.db.get_devices(device.user_id).unwrap().remove(0);
+      assert_eq!(device.consecutive_failures, 2);
+
+      // The third attempt goes through and takes the push off the queue
+      fixture.update_queue("UPDATE push_queue SET next_attempt_at = 0");
+      fixture.retry_due().await;
+      assert_eq!(received.load(Ordering::SeqCst), 3);
+      assert!(fixture.queued().is_empty());
+      let device = fixture.db.get_devices(device.user_id).unwrap().remove(0);
+      assert_eq!(device.consecutive_failures, 0);
+   }
+
+   #[tokio::test]
+   async fn delivers_right_away() {
+      let (endpoint, received) = push_service(0).await;
+      let fixture = Fixture::new();
+      let device = fixture.device(&endpoint);
+
+      assert!(matches!(
+         fixture.deliver(&device, false).await,
+         Delivery::Delivered
+      ));
+      assert_eq!(received.load(Ordering::SeqCst), 1);
+      assert!(fixture.queued().is_empty());
+   }
+
+   #[tokio::test]
+   async fn queue_only_skips_sending() {
+      let (endpoint, received) = push_service(0).await;
+      let fixture = Fixture::new();
+      let device = fixture.device(&endpoint);
+
+      assert!(matches!(
+         fixture.deliver(&device, true).await,
+         Delivery::Queued
+      ));
+      assert_eq!(received.load(Ordering::SeqCst), 0);
+      assert_eq!(fixture.queued().len(), 1);
+   }
+
+   #[tokio::test]
+   async fn drops_pushes_queued_too_long() {
+      let (endpoint, received) = push_service(u32::MAX).await;
+      let fixture = Fixture::new();
+      let device = fixture.device(&endpoint);
+
+      fixture.deliver(&device, false).await;
+      assert_eq!(fixture.queued().len(), 1);
+
+      // Still within the maximum age
+      fixture
+         .update_queue("UPDATE push_queue SET next_attempt_at = 0, created_at = created_at - 3500");
+      fixture.retry_due().await;
+      assert_eq!(received.load(Ordering::SeqCst), 2);
+      assert_eq!(fixture.queued().len(), 1);
+
+      fixture
+         .update_queue("UPDATE push_queue SET next_attempt_at = 0, created_at = created_at - 200");
+      fixture.retry_due().await;
+      assert_eq!(received.load(Ordering::SeqCst), 2);
+      assert!(fixture.queued().is_empty());
+   }
+
+   #[tokio::test]
+   async fn disables_gone_devices() {
+      let (endpoint, received) = failing_push_service(u32::MAX, StatusCode::GONE).await;
+      let fixture = Fixture::new();
+      let device = fixture.device(&endpoint);
+
+      assert!(matches!(
+         fixture.deliver(&device, false).await,
+         Delivery::Disabled
+      ));
+      assert_eq!(received.load(Ordering::SeqCst), 1);
+      assert!(fixture.queued().is_empty());
+
+      let device = fixture.db.get_devices(device.user_id).unwrap().remove(0);
+      assert!(!device.is_enabled());
+      let reason = device.disabled_reason.unwrap();
+      assert!(reason.starts_with("Endpoint is gone:"), "{reason}");
+      assert!(reason.contains("410"), "{reason}");
+   }
+
+   #[tokio::test]
+   async fn disables_devices_after_repeated_failures() {
+      let (endpoint, received) = push_service(u32::MAX).await;
+      let fixture = Fixture::new();
+      let device = fixture.device(&endpoint);
+
+      assert!(matches!(
+         fixture.deliver(&device, false).await,
+         Delivery::Queued
+      ));
+
+      // Retried until the fifth failure in a row
+      for attempt in 2..=5 {
+         let device = fixture.db.get_devices(device.user_id).unwrap().remove(0);
+         assert!(device.is_enabled(), "disabled before attempt {attempt}");
+
+         fixture.update_queue("UPDATE push_queue SET next_attempt_at = 0");
+         fixture.retry_due().await;
+         assert_eq!(received.load(Ordering::SeqCst), attempt);
+      }
+
+      let device = fixture.db.get_devices(device.user_id).unwrap().remove(0);
+      assert!(!device.is_enabled());
+      assert_eq!(device.consecutive_failures, 5);
+      let reason = device.disabled_reason.unwrap();
+      assert!(
+         reason.starts_with("5 consecutive delivery failures, last:"),
+         "{reason}"
+      );
+
+      // Its queue is dropped with it
+      assert!(fixture.queued().is_empty());
+      fixture.retry_due().await;
+      assert_eq!(received.load(Ordering::SeqCst), 5);
+   }
+
+   #[test]
+   fn backoff_bounds() {
+      for attempts in 0..40u32 {
+         let delay = RETRY_BASE_DELAY
+            .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+            .min(RETRY_MAX_DELAY);
+
+         for _ in 0..20 {
+            let backoff = backoff(attempts);
+            assert!(
+               backoff >= delay / 2 && backoff <= delay,
+               "{backoff:?} for {attempts} attempts"
+            );
+         }
+      }
+
+      assert!(backoff(1) <= RETRY_BASE_DELAY);
+      assert!(backoff(2) >= RETRY_BASE_DELAY);
+      assert!(backoff(u32::MAX) >= RETRY_MAX_DELAY / 2);
+   }
+}
@@ -0,0 +1,461 @@
+use std::{
+   collections::{
+      HashMap,
+      HashSet,
+   },
+   sync::{
+      Arc,
+      RwLock,
+   },
+   time::{
+      Duration,
+      Instant,
+   },
+};
+
+use tokio::sync::Mutex;
+use tracing::{
+   info,
+   warn,
+};
+
+use crate::{
+   homepage::{
+      self,
+      Homepage,
+   },
+   http_client::HttpClient,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60); // 6 hours
+
+/// Minimum time between two fetches, so a broken bundle or an outage doesn't
+/// make every poll download it again
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Looks up GraphQL query IDs and feature switches in the x.com web client,
+/// since they change whenever Twitter deploys a new build
+pub struct QueryDiscovery {
+   client:            HttpClient,
+   homepage:          Arc<Homepage>,
+   state:             RwLock<Option<CachedQueries>>,
+   /// Held while fetching, so concurrent polls wait for one fetch instead of
+   /// each starting their own
+   last_attempt:      Mutex<Option<Instant>>,
+   /// Features the API demanded for an operation, by operation name
+   required_features: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+struct CachedQueries {
+   /// Operations defined in the main bundle, by name
+   operations:     HashMap<String, OperationDefinition>,
+   /// Feature switch values from the homepage's initial state
+   feature_values: HashMap<String, bool>,
+   fetched_at:     Instant,
+}
+
+struct OperationDefinition {
+   query_id:         String,
+   feature_switches: Vec<String>,
+}
+
+/// What a GraphQL request for an operation should be sent with
+pub struct Operation {
+   pub query_id: String,
+   /// Feature switches to send, on top of any built-in defaults
+   pub features: HashMap<String, bool>,
+}
+
+impl QueryDiscovery {
+   pub fn new(client: HttpClient, homepage: Arc<Homepage>) -> Self {
+      Self {
+         client,
+         homepage,
+         state: RwLock::new(None),
+         last_attempt: Mutex::new(None),
+         required_features: RwLock::new(HashMap::new()),
+      }
+   }
+
+   /// The current query ID and features of a GraphQL operation such as
+   /// `NotificationsTimeline`. If they can't be refreshed, the last known ones
+   /// are used, and without any, `fallback_query_id`.
+   pub async fn operation(&self, name: &str, fallback_query_id: &str) -> Operation {
+      if self.cached(name, REFRESH_INTERVAL).is_none()
+         && let Err(e) = self.refresh(REFRESH_INTERVAL).await
+      {
+         warn!("Failed to refresh, using last known operations: {e}");
+      }
+
+      let mut operation = self.cached(name, Duration::MAX).unwrap_or_else(|| {
+         Operation {
+            query_id: fallback_query_id.to_string(),
+            features: HashMap::new(),
+         }
+      });
+
+      // Demanded features the client doesn't know a value for are turned off
+      let required = self.required_features.read().unwrap();
+      for feature in required.get(name).into_iter().flatten() {
+         operation.features.entry(feature.clone()).or_insert(false);
+      }
+
+      operation
+   }
+
+   /// Remember features an API error said an operation can't go without
+   pub fn add_required_features(&self, operation: &str, features: &[String]) {
+      let mut required = self.required_features.write().unwrap();
+      required
+         .entry(operation.to_string())
+         .or_default()
+         .extend(features.iter().cloned());
+   }
+
+   /// Refresh after Twitter rejected a query ID, unless that was just done
+   pub async fn invalidate_and_refresh(&self) -> Result<(), DiscoveryError> {
+      self.refresh(MIN_REFRESH_INTERVAL).await
+   }
+
+   fn cached(&self, name: &str, max_age: Duration) -> Option<Operation> {
+      let state = self.state.read().unwrap();
+      let cached = state.as_ref()?;
+
+      if cached.fetched_at.elapsed() >= max_age {
+         return None;
+      }
+      let definition = cached.operations.get(name)?;
+
+      // Switches without a value in the initial state are left to the
+      // built-in defaults
+      let features = definition
+         .feature_switches
+         .iter()
+         .filter_map(|switch| {
+            let value = cached.feature_values.get(switch)?;
+            Some((switch.clone(), *value))
+         })
+         .collect();
+
+      Some(Operation {
+         query_id: definition.query_id.clone(),
+         features,
+      })
+   }
+
+   /// Fetch the query IDs unless they're younger than `max_age`
+   async fn refresh(&self, max_age: Duration) -> Result<(), DiscoveryError> {
+      let mut last_attempt = self.last_attempt.lock().await;
+
+      // Another poll may have refreshed while this one waited for the lock
+      {
+         let state = self.state.read().unwrap();
+         if let Some(ref cached) = *state
+            && cached.fetched_at.elapsed() < max_age
+         {
+            return Ok(());
+         }
+      }
+
+      if let Some(at) = *last_attempt
+         && at.elapsed() < MIN_REFRESH_INTERVAL
+      {
+         return Err(DiscoveryError::Throttled);
+      }
+      *last_attempt = Some(Instant::now());
+
+      // Fetch homepage, or take the one transaction IDs were just refreshed
+      // from
+      let html = self
+         .homepage
+         .get(homepage::REUSE_MAX_AGE)
+         .await
+         .map_err(|e| DiscoveryError::Fetch(format!("Failed to fetch homepage: {e}")))?;
+
+      // Extract the main bundle URL
+      let js_url = extract_main_js_url(&html)
+         .ok_or_else(|| DiscoveryError::Parse("main.js URL not found".to_string()))?;
+
+      // Fetch JS file
+      let js = self
+         .client
+         .get_text(&js_url)
+         .await
+         .map_err(|e| DiscoveryError::Fetch(format!("Failed to fetch JS: {e}")))?;
+
+      let operations = extract_operations(&js);
+      if operations.is_empty() {
+         return Err(DiscoveryError::Parse(
+            "no operations found in main.js".to_string(),
+         ));
+      }
+
+      let feature_values = extract_feature_values(&html);
+
+      info!(
+         "Found {} GraphQL operations in {js_url} and {} feature values",
+         operations.len(),
+         feature_values.len()
+      );
+
+      // Cache it
+      {
+         let mut state = self.state.write().unwrap();
+         *state = Some(CachedQueries {
+            operations,
+            feature_values,
+            fetched_at: Instant::now(),
+         });
+      }
+
+      Ok(())
+   }
+}
+
+/// Find the URL of the web client's main bundle, which looks like
+/// `https://abs.twimg.com/responsive-web/client-web/main.<hash>.js`
+fn extract_main_js_url(html: &str) -> Option<String> {
+   let marker = html.find("/client-web/main.")?;
+
+   let start = html[..marker].rfind(['"', '\''])? + 1;
+   let end = marker + html[marker..].find(".js")? + ".js".len();
+
+   Some(html[start..end].to_string())
+}
+
+/// Collect the operations defined in the bundle, which appear as
+/// `{queryId:"...",operationName:"...",operationType:"query",metadata:
+/// {featureSwitches:[...],...}}`
+fn extract_operations(js: &str) -> HashMap<String, OperationDefinition> {
+   const QUERY_ID: &str = "queryId:\"";
+   const OPERATION_NAME: &str = "operationName:\"";
+   const FEATURE_SWITCHES: &str = "featureSwitches:[";
+
+   let mut operations = HashMap::new();
+   let mut rest = js;
+
+   while let Some(start) = rest.find(QUERY_ID) {
+      rest = &rest[start + QUERY_ID.len()..];
+
+      // Only look within this definition, up to the next one
+      let definition = &rest[..rest.find(QUERY_ID).unwrap_or(rest.len())];
+
+      let Some(id) = definition.split('"').next() else {
+         continue;
+      };
+      let Some(name) = definition
+         .find(OPERATION_NAME)
+         .and_then(|i| definition[i + OPERATION_NAME.len()..].split('"').next())
+      else {
+         continue;
+      };
+
+      let feature_switches = definition
+         .find(FEATURE_SWITCHES)
+         .and_then(|i| definition[i + FEATURE_SWITCHES.len()..].split(']').next())
+         .map(|list| {
+            list
+               .split(',')
+               .map(|switch| switch.trim().trim_matches('"'))
+               .filter(|switch| !switch.is_empty())
+               .map(str::to_string)
+               .collect()
+         })
+         .unwrap_or_default();
+
+      if !id.is_empty() && !name.is_empty() {
+         operations.insert(name.to_string(), OperationDefinition {
+            query_id: id.to_string(),
+            feature_switches,
+         });
+      }
+   }
+
+   operations
+}
+
+/// Read feature switch values from the `window.__INITIAL_STATE__` object the
+/// homepage embeds, with user-level config taking precedence over defaults
+fn extract_feature_values(html: &str) -> HashMap<String, bool> {
+   const INITIAL_STATE: &str = "window.__INITIAL_STATE__=";
+
+   let mut values = HashMap::new();
+
+   let Some(start) = html.find(INITIAL_STATE) else {
+      return values;
+   };
+
+   // The object is followed by more script, so only parse the first value
+   let state = serde_json::Deserializer::from_str(&html[start + INITIAL_STATE.len()..])
+      .into_iter::<serde_json::Value>()
+      .next();
+   let Some(Ok(state)) = state else {
+      return values;
+   };
+
+   for path in ["/featureSwitch/defaultConfig", "/featureSwitch/user/config"] {
+      let Some(config) = state.pointer(path).and_then(|c| c.as_object()) else {
+         continue;
+      };
+
+      for (name, entry) in config {
+         if let Some(value) = entry.get("value").and_then(|v| v.as_bool()) {
+            values.insert(name.clone(), value);
+         }
+      }
+   }
+
+   values
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+   Fetch(String),
+   Parse(String),
+   /// A refresh was attempted too recently
+   Throttled,
+}
+
+impl std::fmt::Display for DiscoveryError {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      match self {
+         DiscoveryError::Fetch(e) => write!(f, "Fetch error: {e}"),
+         DiscoveryError::Parse(e) => write!(f, "Parse error: {e}"),
+         DiscoveryError::Throttled => write!(f, "Refreshed too recently"),
+      }
+   }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+#[cfg(test)]
+mod tests {
+   use std::sync::atomic::{
+      AtomicU32,
+      Ordering,
+   };
+
+   use axum::{
+      Router,
+      routing::get,
+   };
+
+   use super::*;
+   use crate::{
+      metrics::Metrics,
+      test_server,
+      txid::TxIdGenerator,
+   };
+
+   const HOMEPAGE: &str = include_str!("../tests/fixtures/homepage.html");
+   const MAIN_JS: &str = include_str!("../tests/fixtures/main.js");
+
+   #[test]
+   fn main_js_url() {
+      assert_eq!(
+         extract_main_js_url(HOMEPAGE).as_deref(),
+         Some("https://abs.twimg.com/responsive-web/client-web/main.3f1a9c7e.js")
+      );
+      assert_eq!(
+         extract_main_js_url("<script src='/responsive-web/client-web/main.abc.js'>").as_deref(),
+         Some("/responsive-web/client-web/main.abc.js")
+      );
+      assert_eq!(extract_main_js_url("<html></html>"), None);
+      assert_eq!(
+         extract_main_js_url("client-web/main.abc.js without quotes"),
+         None
+      );
+   }
+
+   #[test]
+   fn operations() {
+      let operations = extract_operations(MAIN_JS);
+
+      let mut names: Vec<_> = operations.keys().map(String::as_str).collect();
+      names.sort_unstable();
+      assert_eq!(names, [
+         "CreateTweet",
+         "NotificationsTimeline",
+         "UserByScreenName"
+      ]);
+
+      let notifications = &operations["NotificationsTimeline"];
+      assert_eq!(notifications.query_id, "Y-4nWuqrAwaEDpHtfJmK5A");
+      assert_eq!(notifications.feature_switches, [
+         "rweb_video_screen_enabled",
+         "responsive_web_graphql_timeline_navigation_enabled",
+         "longform_notetweets_consumption_enabled",
+         "creator_subscriptions_tweet_preview_api_enabled",
+      ]);
+
+      assert_eq!(operations["CreateTweet"].query_id, "lQ3aN5nQc7oVyTe3Ekh0BQ");
+      assert!(operations["CreateTweet"].feature_switches.is_empty());
+      assert_eq!(operations["UserByScreenName"].feature_switches, [
+         "hidden_profile_subscriptions_enabled"
+      ]);
+
+      assert!(extract_operations("no operations here").is_empty());
+   }
+
+   #[test]
+   fn feature_values() {
+      let values = extract_feature_values(HOMEPAGE);
+
+      let mut values: Vec<_> = values.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+      values.sort_unstable();
+      // User config wins over defaults, and non-boolean values are left out
+      assert_eq!(values, [
+         ("creator_subscriptions_tweet_preview_api_enabled", true),
+         ("longform_notetweets_consumption_enabled", true),
+         ("responsive_web_graphql_timeline_navigation_enabled", true),
+         ("rweb_video_screen_enabled", true),
+         ("tweet_awards_web_tipping_enabled", false),
+      ]);
+
+      assert!(extract_feature_values("<html></html>").is_empty());
+      assert!(extract_feature_values("window.__INITIAL_STATE__={broken").is_empty());
+   }
+
+   #[tokio::test]
+   async fn shares_homepage_with_transaction_ids() {
+      let homepage_fetches = Arc::new(AtomicU32::new(0));
+      let fetches = homepage_fetches.clone();
+      let app = Router::new()
+         .route(
+            "/",
+            get(move || {
+               fetches.fetch_add(1, Ordering::SeqCst);
+               async { HOMEPAGE }
+            }),
+         )
+         .route(
+            "/responsive-web/client-web/main.3f1a9c7e.js",
+            get(|| async { MAIN_JS }),
+         );
+      let server = test_server::serve(app).await;
+
+      let client = || {
+         HttpClient::allowing_http()
+            .redirect("https://x.com", &server)
+            .redirect("https://abs.twimg.com", &server)
+      };
+      let homepage = Arc::new(Homepage::new(client()));
+      let discovery = QueryDiscovery::new(client(), homepage.clone());
+      let txid = TxIdGenerator::new(client(), homepage, Arc::new(Metrics::new()));
+
+      let operation = discovery
+         .operation("NotificationsTimeline", "fallback")
+         .await;
+      assert_eq!(operation.query_id, "Y-4nWuqrAwaEDpHtfJmK5A");
+      assert_eq!(
+         operation.features.get("rweb_video_screen_enabled"),
+         Some(&true)
+      );
+
+      // Whether the keys parse doesn't matter here, only where they came from
+      let _ = txid
+         .generate("GET", "/i/api/2/badge_count/badge_count.json")
+         .await;
+      assert_eq!(homepage_fetches.load(Ordering::SeqCst), 1);
+   }
+}
@@ -0,0 +1,336 @@
+use crate::twitter::{
+   Actor,
+   Notification,
+};
+
+/// What a user did in a collapsed notification, for types worth collapsing
+fn verb(notification_type: &str) -> Option<&'static str> {
+   match notification_type {
+      "like" => Some("liked your post"),
+      "retweet" => Some("reposted your post"),
+      "quote" => Some("quoted your post"),
+      "follow" => Some("followed you"),
+      _ => None,
+   }
+}
+
+/// Singular and plural name of a type in a digest summary
+fn label(notification_type: &str) -> (&'static str, &'static str) {
+   match notification_type {
+      "like" => ("like", "likes"),
+      "retweet" => ("repost", "reposts"),
+      "reply" => ("reply", "replies"),
+      "mention" => ("mention", "mentions"),
+      "quote" => ("quote", "quotes"),
+      "follow" => ("new follower", "new followers"),
+      "dm" => {
+         (
+            "conversation with new messages",
+            "conversations with new messages",
+         )
+      },
+      _ => ("notification", "notifications"),
+   }
+}
+
+/// Notifications pushed as one
+pub struct Group<'a> {
+   pub notification: Notification,
+   /// What went into it, oldest first
+   pub members:      Vec<&'a Notification>,
+}
+
+impl<'a> Group<'a> {
+   pub fn single(notif: &'a Notification) -> Self {
+      Self {
+         notification: notif.clone(),
+         members:      vec![notif],
+      }
+   }
+}
+
+/// Collapse notifications of the same type about the same tweet, such as many
+/// likes of one post, into one. Follows are about the user rather than a
+/// tweet, so all of them collapse. Notifications come and go out oldest
+/// first; a group takes the place of its oldest member and the sort index of
+/// its newest.
+pub fn group<'a>(notifs: &[&'a Notification]) -> Vec<Group<'a>> {
+   let mut groups: Vec<Group<'a>> = Vec::new();
+
+   for &notif in notifs {
+      let collapsible = verb(&notif.notification_type).is_some()
+         && (notif.tweet_id.is_some() || notif.notification_type == "follow");
+
+      let existing = groups.iter_mut().find(|group| {
+         collapsible
+            && group.notification.notification_type == notif.notification_type
+            && group.notification.tweet_id == notif.tweet_id
+      });
+
+      let Some(Group {
+         notification: group,
+         members,
+      }) = existing
+      else {
+         groups.push(Group::single(notif));
+         continue;
+      };
+
+      members.push(notif);
+      group.sort_index = notif.sort_index.clone();
+
+      for name in &notif.from_users {
+         if !group.from_users.contains(name) {
+            group.from_users.push(name.clone());
+         }
+      }
+      for actor in &notif.actors {
+         if !group
+            .actors
+            .iter()
+            .any(|a| a.screen_name == actor.screen_name)
+         {
+            group.actors.push(actor.clone());
+         }
+      }
+   }
+
+   for group in &mut groups {
+      let notif = &mut group.notification;
+      if group.members.len() > 1
+         && let Some(verb) = verb(&notif.notification_type)
+      {
+         notif.message = grouped_message(&notif.from_users, group.members.len(), verb);
+      }
+   }
+
+   groups
+}
+
+/// "Alice liked your post", "Alice and Bob liked your post" or "Alice and 14
+/// others liked your post"
+fn grouped_message(names: &[String], members: usize, verb: &str) -> String {
+   // Users without a name still count
+   let people = names.len().max(members);
+
+   match names {
+      [] => format!("{people} people {verb}"),
+      [first] if people == 1 => format!("{first} {verb}"),
+      [first, second] if people == 2 => format!("{first} and {second} {verb}"),
+      [first, ..] if people == 2 => format!("{first} and 1 other {verb}"),
+      [first, ..] => format!("{first} and {} others {verb}", people - 1),
+   }
+}
+
+/// One push summarizing held notifications, oldest first, e.g. "12 likes, 2
+/// replies and 1 new follower"
+pub fn digest(notifs: &[Notification]) -> Notification {
+   let mut counts: Vec<(&str, usize)> = Vec::new();
+
+   for notif in notifs {
+      // A collapsed notification counts everyone in it
+      let count = if verb(&notif.notification_type).is_some() {
+         notif.from_users.len().max(1)
+      } else {
+         1
+      };
+
+      match counts
+         .iter_mut()
+         .find(|(notification_type, _)| *notification_type == notif.notification_type)
+      {
+         Some((_, total)) => *total += count,
+         None => counts.push((&notif.notification_type, count)),
+      }
+   }
+
+   let mut parts: Vec<String> = counts
+      .into_iter()
+      .map(|(notification_type, count)| {
+         let (singular, plural) = label(notification_type);
+         format!("{count} {}", if count == 1 { singular } else { plural })
+      })
+      .collect();
+
+   let message = match parts.pop() {
+      Some(last) if !parts.is_empty() => format!("{} and {last}", parts.join(", ")),
+      Some(last) => last,
+      None => String::new(),
+   };
+
+   let mut from_users: Vec<String> = Vec::new();
+   let mut actors: Vec<Actor> = Vec::new();
+   for notif in notifs {
+      for name in &notif.from_users {
+         if !from_users.contains(name) {
+            from_users.push(name.clone());
+         }
+      }
+      for actor in &notif.actors {
+         if !actors.iter().any(|a| a.screen_name == actor.screen_name) {
+            actors.push(actor.clone());
+         }
+      }
+   }
+
+   Notification {
+      sort_index: notifs
+         .last()
+         .map(|notif| notif.sort_index.clone())
+         .unwrap_or_default(),
+      notification_type: "digest".to_string(),
+      message,
+      url: Some("https://x.com/notifications".to_string()),
+      from_users,
+      actors,
+      ..Default::default()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn notif(
+      sort_index: u32,
+      notification_type: &str,
+      tweet_id: Option<&str>,
+      from: &str,
+   ) -> Notification {
+      Notification {
+         sort_index: sort_index.to_string(),
+         notification_type: notification_type.to_string(),
+         message: format!("{from} did something"),
+         from_users: vec![from.to_string()],
+         actors: vec![Actor {
+            name:            from.to_string(),
+            screen_name:     from.to_lowercase(),
+            avatar_url:      None,
+            followers_count: None,
+            verified:        false,
+         }],
+         tweet_id: tweet_id.map(str::to_string),
+         ..Notification::default()
+      }
+   }
+
+   fn names(notif: &Notification) -> Vec<&str> {
+      notif.from_users.iter().map(String::as_str).collect()
+   }
+
+   #[test]
+   fn likes_of_the_same_tweet_collapse() {
+      let notifs = [
+         notif(1, "like", Some("10"), "Alice"),
+         notif(2, "like", Some("20"), "Bob"),
+         notif(3, "like", Some("10"), "Carol"),
+         notif(4, "like", Some("10"), "Alice"),
+      ];
+      let refs: Vec<&Notification> = notifs.iter().collect();
+
+      let groups = group(&refs);
+      assert_eq!(groups.len(), 2);
+
+      // In place of the oldest member, with the newest sort index
+      let liked = &groups[0];
+      assert_eq!(liked.members.len(), 3);
+      assert_eq!(liked.notification.sort_index, "4");
+      assert_eq!(names(&liked.notification), ["Alice", "Carol"]);
+      assert_eq!(liked.notification.actors.len(), 2);
+      assert_eq!(
+         liked.notification.message,
+         "Alice and 2 others liked your post"
+      );
+
+      assert_eq!(groups[1].members.len(), 1);
+      assert_eq!(groups[1].notification.message, "Bob did something");
+   }
+
+   #[test]
+   fn follows_collapse() {
+      let notifs = [
+         notif(1, "follow", None, "Alice"),
+         notif(2, "follow", None, "Bob"),
+      ];
+      let refs: Vec<&Notification> = notifs.iter().collect();
+
+      let groups = group(&refs);
+      assert_eq!(groups.len(), 1);
+      assert_eq!(groups[0].notification.message, "Alice and Bob followed you");
+      assert_eq!(groups[0].notification.sort_index, "2");
+   }
+
+   #[test]
+   fn singletons_pass_through() {
+      let notifs = [
+         notif(1, "reply", Some("10"), "Alice"),
+         notif(2, "reply", Some("10"), "Bob"),
+         notif(3, "like", None, "Carol"),
+         notif(4, "like", None, "Dave"),
+         notif(5, "retweet", Some("10"), "Erin"),
+      ];
+      let refs: Vec<&Notification> = notifs.iter().collect();
+
+      // Replies aren't collapsed, nor likes without a tweet
+      let groups = group(&refs);
+      assert_eq!(groups.len(), notifs.len());
+      for (group, notif) in groups.iter().zip(&notifs) {
+         assert_eq!(group.members.len(), 1);
+         assert_eq!(group.notification.sort_index, notif.sort_index);
+         assert_eq!(group.notification.message, notif.message);
+         assert_eq!(group.notification.from_users, notif.from_users);
+      }
+   }
+
+   #[test]
+   fn grouped_wording() {
+      let names = |names: &[&str]| -> Vec<String> { names.iter().map(|n| n.to_string()).collect() };
+      let verb = "liked your post";
+
+      assert_eq!(
+         grouped_message(&names(&["Alice"]), 1, verb),
+         "Alice liked your post"
+      );
+      assert_eq!(
+         grouped_message(&names(&["Alice", "Bob"]), 2, verb),
+         "Alice and Bob liked your post"
+      );
+      assert_eq!(
+         grouped_message(&names(&["Alice"]), 2, verb),
+         "Alice and 1 other liked your post"
+      );
+      assert_eq!(
+         grouped_message(&names(&["Alice", "Bob", "Carol"]), 3, verb),
+         "Alice and 2 others liked your post"
+      );
+      // Members without names count as people too
+      assert_eq!(
+         grouped_message(&names(&["Alice"]), 15, verb),
+         "Alice and 14 others liked your post"
+      );
+      assert_eq!(grouped_message(&[], 3, verb), "3 people liked your post");
+   }
+
+   #[test]
+   fn digest_counts_each_type() {
+      let mut liked = notif(1, "like", Some("10"), "Alice");
+      liked.from_users.push("Bob".to_string());
+      let notifs = [
+         liked,
+         notif(2, "like", Some("20"), "Carol"),
+         notif(3, "reply", Some("10"), "Alice"),
+         notif(4, "follow", None, "Dave"),
+         notif(5, "reply", Some("20"), "Erin"),
+      ];
+
+      let summary = digest(&notifs);
+      assert_eq!(summary.notification_type, "digest");
+      assert_eq!(summary.message, "3 likes, 2 replies and 1 new follower");
+      assert_eq!(summary.sort_index, "5");
+      assert_eq!(names(&summary), ["Alice", "Bob", "Carol", "Dave", "Erin"]);
+      assert_eq!(summary.actors.len(), 4);
+
+      assert_eq!(digest(&notifs[3..4]).message, "1 new follower");
+      assert_eq!(digest(&notifs[1..3]).message, "1 like and 1 reply");
+   }
+}
@@ -0,0 +1,62 @@
+use std::{
+   sync::Arc,
+   time::{
+      Duration,
+      Instant,
+   },
+};
+
+use tokio::sync::Mutex;
+
+use crate::http_client::{
+   HttpClient,
+   HttpError,
+};
+
+const HOMEPAGE_URL: &str = "https://x.com";
+
+/// Copies at most this old are handed out again, so query discovery and
+/// transaction IDs refreshing around the same time share one download
+pub const REUSE_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// The x.com homepage, which both the GraphQL query IDs and the transaction
+/// ID keys are found through
+pub struct Homepage {
+   client: HttpClient,
+   /// Held while fetching, so concurrent readers wait for one download
+   cached: Mutex<Option<CachedPage>>,
+}
+
+struct CachedPage {
+   html:       Arc<str>,
+   fetched_at: Instant,
+}
+
+impl Homepage {
+   pub fn new(client: HttpClient) -> Self {
+      Self {
+         client,
+         cached: Mutex::new(None),
+      }
+   }
+
+   /// The homepage HTML, downloaded again unless the last copy is younger
+   /// than `max_age`
+   pub async fn get(&self, max_age: Duration) -> Result<Arc<str>, HttpError> {
+      let mut cached = self.cached.lock().await;
+
+      if let Some(page) = cached.as_ref()
+         && page.fetched_at.elapsed() < max_age
+      {
+         return Ok(page.html.clone());
+      }
+
+      let html: Arc<str> = self.client.get_text(HOMEPAGE_URL).await?.into();
+      *cached = Some(CachedPage {
+         html:       html.clone(),
+         fetched_at: Instant::now(),
+      });
+
+      Ok(html)
+   }
+}
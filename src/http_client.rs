@@ -46,24 +46,62 @@ impl std::fmt::Display for HttpError {
 impl std::error::Error for HttpError {}
 
 pub struct HttpClient {
-   client: HttpsClient,
+   client:    HttpsClient,
+   /// Origins whose requests go to another origin instead, so tests can
+   /// stand in for remote services
+   redirects: Vec<(String, String)>,
 }
 
 impl HttpClient {
    pub fn new() -> Self {
+      Self::build(true)
+   }
+
+   /// A client that also makes plain HTTP requests, for local stand-ins of
+   /// Twitter and push services in tests
+   #[cfg(test)]
+   pub fn allowing_http() -> Self {
+      Self::build(false)
+   }
+
+   /// Send requests for `from` (e.g. `https://x.com`) to `to` instead
+   #[cfg(test)]
+   pub fn redirect(mut self, from: &str, to: &str) -> Self {
+      self.redirects.push((from.to_string(), to.to_string()));
+      self
+   }
+
+   fn resolve(&self, url: &str) -> String {
+      for (from, to) in &self.redirects {
+         if let Some(rest) = url.strip_prefix(from.as_str())
+            && (rest.is_empty() || rest.starts_with(['/', '?']))
+         {
+            return format!("{to}{rest}");
+         }
+      }
+
+      url.to_string()
+   }
+
+   fn build(https_only: bool) -> Self {
       let mut http = HttpConnector::new();
       http.enforce_http(false); // Allow HTTPS
       http.set_local_address(Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
 
-      let https = hyper_rustls::HttpsConnectorBuilder::new()
-         .with_webpki_roots()
-         .https_only()
-         .enable_http1()
-         .wrap_connector(http);
+      let builder = hyper_rustls::HttpsConnectorBuilder::new().with_webpki_roots();
+      let builder = if https_only {
+         builder.https_only()
+      } else {
+         builder.https_or_http()
+      };
+      let https = builder.enable_http1().wrap_connector(http);
 
       let client: HttpsClient = Client::builder(TokioExecutor::new()).build(https);
 
-      Self { client }
+      Self {
+         client,
+         redirects: Vec::new(),
+      }
    }
 
    pub async fn get<H: AsRef<str>>(
@@ -71,7 +109,9 @@ impl HttpClient {
       url: &str,
       headers: &[(&str, H)],
    ) -> Result<Vec<u8>, HttpError> {
-      let mut builder = Request::builder().method(Method::GET).uri(url);
+      let mut builder = Request::builder()
+         .method(Method::GET)
+         .uri(self.resolve(url));
 
       for (key, value) in headers {
          builder = builder.header(*key, value.as_ref());
@@ -107,7 +147,9 @@ impl HttpClient {
       headers: &[(&str, H)],
       body: &[u8],
    ) -> Result<Vec<u8>, HttpError> {
-      let mut builder = Request::builder().method(Method::POST).uri(url);
+      let mut builder = Request::builder()
+         .method(Method::POST)
+         .uri(self.resolve(url));
 
       for (key, value) in headers {
          builder = builder.header(*key, value.as_ref());
@@ -0,0 +1,206 @@
+use std::io::{
+   self,
+   IsTerminal,
+   Write,
+};
+
+use tracing_subscriber::{
+   EnvFilter,
+   fmt::MakeWriter,
+};
+
+use crate::config::{
+   Config,
+   LogFormat,
+};
+
+/// Replaces what looks like a secret in log lines
+const REDACTED: &str = "[redacted]";
+
+/// Words at least this long are checked for looking like tokens. Twitter IDs,
+/// sort indexes and query IDs are shorter.
+const MIN_TOKEN_LEN: usize = 32;
+
+/// Log to stderr at the configured level and in the configured format, with
+/// secrets redacted from every line
+pub fn init(config: &Config) {
+   let (filter, filter_error) = match EnvFilter::try_new(&config.log_level) {
+      Ok(filter) => (filter, None),
+      Err(e) => (EnvFilter::new("info"), Some(e)),
+   };
+
+   let builder = tracing_subscriber::fmt()
+      .with_env_filter(filter)
+      .with_writer(RedactingStderr)
+      .with_ansi(io::stderr().is_terminal());
+
+   match config.log_format {
+      LogFormat::Text => builder.init(),
+      LogFormat::Json => builder.json().flatten_event(true).init(),
+   }
+
+   if let Some(e) = filter_error {
+      tracing::warn!(
+         level = config.log_level.as_str(),
+         "Invalid log level, using info: {e}"
+      );
+   }
+}
+
+/// Makes writers to stderr that redact what's written to them
+struct RedactingStderr;
+
+impl<'a> MakeWriter<'a> for RedactingStderr {
+   type Writer = Redacting<io::Stderr>;
+
+   fn make_writer(&'a self) -> Self::Writer {
+      Redacting(io::stderr())
+   }
+}
+
+/// A writer redacting what's written through it. Each event arrives in a
+/// single write, so a secret is never split between writes.
+struct Redacting<W>(W);
+
+impl<W: Write> Write for Redacting<W> {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let line = redact(&String::from_utf8_lossy(buf));
+      self.0.write_all(line.as_bytes())?;
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      self.0.flush()
+   }
+}
+
+/// Drop the paths and queries of URLs, which for push endpoints are what
+/// allows pushing, and words that look like tokens, such as session, CSRF and
+/// management tokens
+fn redact(text: &str) -> String {
+   let mut out = String::with_capacity(text.len());
+   let mut rest = text;
+
+   while let Some(start) = rest.find(is_word_char) {
+      out.push_str(&rest[..start]);
+      rest = &rest[start..];
+
+      if let Some(url) = rest
+         .strip_prefix("https://")
+         .or_else(|| rest.strip_prefix("http://"))
+      {
+         let scheme_len = rest.len() - url.len();
+         let end = url.find(is_url_end).unwrap_or(url.len());
+         let host_end = url[..end].find(['/', '?', '#']).unwrap_or(end);
+
+         out.push_str(&rest[..scheme_len + host_end]);
+         if url[host_end..end].len() > 1 {
+            out.push('/');
+            out.push_str(REDACTED);
+         }
+
+         rest = &url[end..];
+         continue;
+      }
+
+      let end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+      let word = &rest[..end];
+      out.push_str(if looks_like_token(word) {
+         REDACTED
+      } else {
+         word
+      });
+      rest = &rest[end..];
+   }
+
+   out.push_str(rest);
+   out
+}
+
+fn is_word_char(c: char) -> bool {
+   c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Where a URL in a log line ends, including in a JSON string
+fn is_url_end(c: char) -> bool {
+   c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '<' | '>' | ')')
+}
+
+/// Long words with upper case letters or several digits, like hex and base64
+/// tokens, rather than identifiers like feature switch names
+fn looks_like_token(word: &str) -> bool {
+   word.len() >= MIN_TOKEN_LEN
+      && (word.chars().any(|c| c.is_ascii_uppercase())
+         || word.chars().filter(char::is_ascii_digit).count() >= 2)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::crypto;
+
+   #[test]
+   fn push_endpoints_keep_only_their_origin() {
+      assert_eq!(
+         redact("Push to https://push.example/up/AbCdEf123?token=xyz failed: HTTP 503"),
+         "Push to https://push.example/[redacted] failed: HTTP 503"
+      );
+      assert_eq!(
+         redact(r#"{"endpoint":"http://10.0.0.2:8080/UP?token=abc","status":404}"#),
+         r#"{"endpoint":"http://10.0.0.2:8080/[redacted]","status":404}"#
+      );
+      // Nothing to hide in a bare origin
+      assert_eq!(
+         redact("Fetching https://x.com and https://x.com/"),
+         "Fetching https://x.com and https://x.com"
+      );
+   }
+
+   #[test]
+   fn tokens_are_redacted() {
+      let auth_token = "a1b2c3d4e5f60718293a4b5c6d7e8f9012345678";
+      let ct0 = "0123456789abcdef".repeat(10);
+      let management_token = crypto::generate_token().unwrap();
+
+      assert_eq!(
+         redact(&format!("auth_token={auth_token}; ct0={ct0}")),
+         "auth_token=[redacted]; ct0=[redacted]"
+      );
+      assert_eq!(
+         redact(&format!("Rejected management token {management_token}")),
+         "Rejected management token [redacted]"
+      );
+      assert_eq!(
+         redact(
+            "authorization: Bearer \
+             AAAAAAAAAAAAAAAAAAAAANRILgAAAAAAnNwIzUejRCOuH5E6I8xnZz4puTs%\
+             3D1Zv7ttfk8LF81IUq16cHjhLTvJu4FA33AGWWjCpTnA"
+         ),
+         "authorization: Bearer [redacted]%[redacted]"
+      );
+   }
+
+   #[test]
+   fn identifiers_survive() {
+      for line in [
+         "Polled user 1849203948572039485 up to 1850000000000000001",
+         "Discovered NotificationsTimeline query Ay1Ffx7ic8eAL3kjEYwXWg",
+         "Feature responsive_web_graphql_exclude_directive_enabled is missing",
+         "Feature c9s_tweet_anatomy_moderator_badge_enabled is missing",
+         "HTTP 503: Service Unavailable",
+         "",
+         "ok",
+      ] {
+         assert_eq!(redact(line), line);
+      }
+   }
+
+   #[test]
+   fn writer_redacts_whole_writes() {
+      let mut writer = Redacting(Vec::new());
+      let line = b"Pushed to https://push.example/up/abc123\n";
+
+      assert_eq!(writer.write(line).unwrap(), line.len());
+      assert_eq!(writer.0, b"Pushed to https://push.example/[redacted]\n");
+   }
+}
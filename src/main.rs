@@ -1,41 +1,105 @@
+mod admin;
 mod api;
 mod config;
+mod crypto;
 mod db;
+mod delivery;
+mod discovery;
+mod grouping;
+mod homepage;
 mod http_client;
+mod logging;
+mod metrics;
+mod migrations;
 mod poller;
+mod preferences;
+mod quiet_hours;
 mod rate_limit;
+mod scheduler;
+#[cfg(test)] mod test_server;
+mod timeline;
 mod twitter;
 mod txid;
 mod unified_push;
+mod vapid;
+mod web_push;
 
 use std::{
    sync::Arc,
    time::Duration,
 };
 
+use admin::AdminState;
 use api::AppState;
 use config::Config;
+use crypto::KeyRing;
 use db::Db;
+use discovery::QueryDiscovery;
+use homepage::Homepage;
 use http_client::HttpClient;
+use metrics::Metrics;
+use poller::PollerState;
 use rate_limit::RateLimiters;
-use tokio::net::TcpListener;
+use tokio::{
+   net::TcpListener,
+   sync::mpsc,
+};
+use tracing::{
+   error,
+   info,
+};
 use txid::TxIdGenerator;
+use vapid::Vapid;
 
 #[tokio::main]
 async fn main() {
    let config = Arc::new(Config::from_env());
 
-   eprintln!("Xitter Notification Server");
-   eprintln!("  Database: {:?}", config.db_path);
-   eprintln!("  Listen: {}", config.listen_addr);
-   eprintln!("  Poll interval: {}s", config.poll_interval_secs);
-   eprintln!("  Max concurrent: {}", config.max_concurrent);
+   logging::init(&config);
+
+   info!(
+      database = ?config.db_path,
+      listen = %config.listen_addr,
+      poll_interval_secs = config.poll_interval_secs,
+      min_poll_interval_secs = config.min_poll_interval_secs,
+      max_poll_interval_secs = config.max_poll_interval_secs,
+      max_concurrent = config.max_concurrent,
+      "Starting Xitter Notification Server"
+   );
+
+   // Load the master keys protecting stored credentials
+   let keys = match KeyRing::load(
+      config.encryption_keys.as_deref(),
+      &config.encryption_key_file,
+   ) {
+      Ok(keys) => keys,
+      Err(e) => {
+         error!("Failed to load encryption keys: {e}");
+         std::process::exit(1);
+      },
+   };
 
    // Initialize database
-   let db = match Db::open(&config.db_path) {
+   let db = match Db::open(&config.db_path, keys) {
       Ok(db) => Arc::new(db),
       Err(e) => {
-         eprintln!("Failed to open database: {e}");
+         error!("Failed to open database: {e}");
+         std::process::exit(1);
+      },
+   };
+
+   // Load the VAPID key pair, generating it on first start
+   let vapid_key = match db.get_or_create_server_key(vapid::KEY_NAME, Vapid::generate_pkcs8) {
+      Ok(key) => key,
+      Err(e) => {
+         error!("Failed to load VAPID key: {e}");
+         std::process::exit(1);
+      },
+   };
+   let vapid = match Vapid::new(&vapid_key, config.vapid_subject.clone()) {
+      Ok(vapid) => Arc::new(vapid),
+      Err(e) => {
+         error!("Failed to load VAPID key: {e}");
          std::process::exit(1);
       },
    };
@@ -46,22 +110,66 @@ async fn main() {
    // Initialize rate limiters
    let rate_limiters = Arc::new(RateLimiters::new());
 
+   // Shared by query ID discovery and transaction IDs, which both start there
+   let homepage = Arc::new(Homepage::new(HttpClient::new()));
+
+   // Initialize GraphQL query ID discovery
+   let discovery = Arc::new(QueryDiscovery::new(HttpClient::new(), homepage.clone()));
+
+   // Initialize the metrics registry
+   let metrics = Arc::new(Metrics::new());
+
    // Initialize transaction ID generator
-   let txid_generator = Arc::new(TxIdGenerator::new(HttpClient::new()));
+   let txid_generator = Arc::new(TxIdGenerator::new(
+      HttpClient::new(),
+      homepage,
+      metrics.clone(),
+   ));
 
    // Create app state for API
    let app_state = Arc::new(AppState {
+      db:             db.clone(),
+      client:         client.clone(),
+      rate_limiters:  rate_limiters.clone(),
+      txid_generator: txid_generator.clone(),
+      vapid:          vapid.clone(),
+      metrics:        metrics.clone(),
+   });
+
+   // Lets the admin API ask for immediate polls
+   let (force_poll_tx, force_poll_rx) = mpsc::unbounded_channel();
+
+   let admin_state = Arc::new(AdminState {
+      db:             db.clone(),
+      client:         client.clone(),
+      vapid:          vapid.clone(),
+      metrics:        metrics.clone(),
+      txid_generator: txid_generator.clone(),
+      token:          config.admin_token.clone(),
+      force_poll:     force_poll_tx,
+   });
+
+   // Start the poller in a background task
+   let poller_state = Arc::new(PollerState {
       db: db.clone(),
-      rate_limiters: rate_limiters.clone(),
+      client: client.clone(),
+      config: config.clone(),
+      vapid: vapid.clone(),
+      discovery,
       txid_generator,
+      metrics: metrics.clone(),
+   });
+   tokio::spawn(async move {
+      poller::run_poller(poller_state, force_poll_rx).await;
    });
 
-   // Start the poller in a background task
-   let poller_db = db.clone();
-   let poller_client = client.clone();
-   let poller_config = config.clone();
+   // Start the push retry worker
+   let retry_db = db.clone();
+   let retry_client = client.clone();
+   let retry_config = config.clone();
+   let retry_vapid = vapid.clone();
    tokio::spawn(async move {
-      poller::run_poller(poller_db, poller_client, poller_config).await;
+      delivery::run_retry_worker(retry_db, retry_client, retry_config, retry_vapid, metrics).await;
    });
 
    // Start rate limiter cleanup task
@@ -74,19 +182,66 @@ async fn main() {
       }
    });
 
-   // Build the API router
-   let app = api::router(app_state);
+   // Metrics get their own listener, so they can be scraped without being
+   // public
+   let metrics_addr = config.metrics_listen_addr;
+   let listener = match TcpListener::bind(metrics_addr).await {
+      Ok(l) => l,
+      Err(e) => {
+         error!("Failed to bind metrics to {metrics_addr}: {e}");
+         std::process::exit(1);
+      },
+   };
+
+   info!("Metrics served on {metrics_addr}/metrics");
+
+   let metrics_app = admin::metrics_router(admin_state.clone());
+   tokio::spawn(async move {
+      if let Err(e) = axum::serve(listener, metrics_app).await {
+         error!("Metrics server error: {e}");
+         std::process::exit(1);
+      }
+   });
+
+   // Build the API router. The admin API gets its own listener if it has an
+   // address, and otherwise is only served when protected by a token.
+   let mut app = api::router(app_state);
+
+   if let Some(admin_addr) = config.admin_listen_addr {
+      let listener = match TcpListener::bind(admin_addr).await {
+         Ok(l) => l,
+         Err(e) => {
+            error!("Failed to bind admin API to {admin_addr}: {e}");
+            std::process::exit(1);
+         },
+      };
+
+      info!("Admin API listening on {admin_addr}");
+
+      let admin_app = admin::router(admin_state);
+      tokio::spawn(async move {
+         if let Err(e) = axum::serve(listener, admin_app).await {
+            error!("Admin server error: {e}");
+            std::process::exit(1);
+         }
+      });
+   } else if config.admin_token.is_some() {
+      info!("Admin API served under /admin");
+      app = app.merge(admin::router(admin_state));
+   } else {
+      info!("Admin API disabled, set XITTER_NOTIFY_ADMIN_TOKEN or XITTER_NOTIFY_ADMIN_LISTEN_ADDR");
+   }
 
    // Start the server
    let listener = match TcpListener::bind(config.listen_addr).await {
       Ok(l) => l,
       Err(e) => {
-         eprintln!("Failed to bind to {}: {e}", config.listen_addr);
+         error!("Failed to bind to {}: {e}", config.listen_addr);
          std::process::exit(1);
       },
    };
 
-   eprintln!("Server listening on {}", config.listen_addr);
+   info!("Server listening on {}", config.listen_addr);
 
    if let Err(e) = axum::serve(
       listener,
@@ -94,7 +249,7 @@ async fn main() {
    )
    .await
    {
-      eprintln!("Server error: {e}");
+      error!("Server error: {e}");
       std::process::exit(1);
    }
 }
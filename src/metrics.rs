@@ -0,0 +1,307 @@
+use std::{
+   fmt::Write,
+   sync::atomic::{
+      AtomicU64,
+      Ordering,
+   },
+   time::Duration,
+};
+
+use crate::{
+   db::MetricCounts,
+   http_client::HttpError,
+};
+
+/// Upper bounds of the poll duration buckets, in seconds
+const POLL_DURATION_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Counters of what the server did since it started. Rendered in the
+/// Prometheus text format together with gauges read at scrape time.
+#[derive(Default)]
+pub struct Metrics {
+   poll_duration:           Histogram,
+   polls_succeeded:         AtomicU64,
+   polls_failed:            AtomicU64,
+   /// Polls due but waiting for a free slot
+   polls_waiting:           AtomicU64,
+   polls_running:           AtomicU64,
+   /// Twitter API requests by the first digit of the response status, with
+   /// requests that got no response at 0
+   twitter_requests:        [AtomicU64; 6],
+   pushes_delivered:        AtomicU64,
+   pushes_failed:           AtomicU64,
+   register_rate_limited:   AtomicU64,
+   unregister_rate_limited: AtomicU64,
+   txid_refreshes:          AtomicU64,
+   txid_refresh_failures:   AtomicU64,
+}
+
+/// Cumulative counts of observations at or below each bucket's bound
+#[derive(Default)]
+struct Histogram {
+   buckets:    [AtomicU64; POLL_DURATION_BUCKETS.len()],
+   count:      AtomicU64,
+   sum_micros: AtomicU64,
+}
+
+impl Histogram {
+   fn observe(&self, duration: Duration) {
+      let secs = duration.as_secs_f64();
+      for (bound, bucket) in POLL_DURATION_BUCKETS.iter().zip(&self.buckets) {
+         if secs <= *bound {
+            bucket.fetch_add(1, Ordering::Relaxed);
+         }
+      }
+
+      self.count.fetch_add(1, Ordering::Relaxed);
+      self
+         .sum_micros
+         .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+   }
+}
+
+/// A rate limited endpoint
+#[derive(Debug, Clone, Copy)]
+pub enum Limited {
+   Register,
+   Unregister,
+}
+
+impl Metrics {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   pub fn record_poll(&self, duration: Duration, succeeded: bool) {
+      self.poll_duration.observe(duration);
+      if succeeded {
+         self.polls_succeeded.fetch_add(1, Ordering::Relaxed);
+      } else {
+         self.polls_failed.fetch_add(1, Ordering::Relaxed);
+      }
+   }
+
+   pub fn poll_queued(&self) {
+      self.polls_waiting.fetch_add(1, Ordering::Relaxed);
+   }
+
+   pub fn poll_started(&self) {
+      self.polls_waiting.fetch_sub(1, Ordering::Relaxed);
+      self.polls_running.fetch_add(1, Ordering::Relaxed);
+   }
+
+   pub fn poll_finished(&self) {
+      self.polls_running.fetch_sub(1, Ordering::Relaxed);
+   }
+
+   pub fn record_twitter_request<T>(&self, result: &Result<T, HttpError>) {
+      let class = match result {
+         Ok(_) => 2,
+         Err(HttpError::Status(code, _)) => usize::from(code.as_u16() / 100).min(5),
+         Err(_) => 0,
+      };
+      self.twitter_requests[class].fetch_add(1, Ordering::Relaxed);
+   }
+
+   pub fn record_push(&self, delivered: bool) {
+      if delivered {
+         self.pushes_delivered.fetch_add(1, Ordering::Relaxed);
+      } else {
+         self.pushes_failed.fetch_add(1, Ordering::Relaxed);
+      }
+   }
+
+   pub fn record_rate_limited(&self, endpoint: Limited) {
+      match endpoint {
+         Limited::Register => &self.register_rate_limited,
+         Limited::Unregister => &self.unregister_rate_limited,
+      }
+      .fetch_add(1, Ordering::Relaxed);
+   }
+
+   pub fn record_txid_refresh(&self, succeeded: bool) {
+      if succeeded {
+         self.txid_refreshes.fetch_add(1, Ordering::Relaxed);
+      } else {
+         self.txid_refresh_failures.fetch_add(1, Ordering::Relaxed);
+      }
+   }
+
+   /// All metrics in the Prometheus text format, with the database counts
+   /// and the age of the transaction ID keys (if any were fetched) as gauges
+   pub fn render(&self, counts: &MetricCounts, txid_age: Option<Duration>) -> String {
+      let mut out = String::new();
+      let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+
+      let name = "xitter_notify_poll_duration_seconds";
+      header(&mut out, name, "histogram", "Time taken to poll a user");
+      for (bound, bucket) in POLL_DURATION_BUCKETS
+         .iter()
+         .zip(&self.poll_duration.buckets)
+      {
+         sample(
+            &mut out,
+            &format!("{name}_bucket"),
+            &format!("le=\"{bound}\""),
+            get(bucket),
+         );
+      }
+      let count = get(&self.poll_duration.count);
+      sample(&mut out, &format!("{name}_bucket"), "le=\"+Inf\"", count);
+      sample(
+         &mut out,
+         &format!("{name}_sum"),
+         "",
+         get(&self.poll_duration.sum_micros) / 1e6,
+      );
+      sample(&mut out, &format!("{name}_count"), "", count);
+
+      let name = "xitter_notify_polls_total";
+      header(&mut out, name, "counter", "Polls of users by result");
+      sample(&mut out, name, "result=\"ok\"", get(&self.polls_succeeded));
+      sample(&mut out, name, "result=\"error\"", get(&self.polls_failed));
+
+      let name = "xitter_notify_twitter_requests_total";
+      header(
+         &mut out,
+         name,
+         "counter",
+         "Twitter API requests by response status class",
+      );
+      for (class, counter) in self.twitter_requests.iter().enumerate() {
+         let label = match class {
+            0 => "status=\"error\"".to_string(),
+            class => format!("status=\"{class}xx\""),
+         };
+         sample(&mut out, name, &label, get(counter));
+      }
+
+      let name = "xitter_notify_pushes_total";
+      header(&mut out, name, "counter", "Push sends by result");
+      sample(
+         &mut out,
+         name,
+         "result=\"delivered\"",
+         get(&self.pushes_delivered),
+      );
+      sample(
+         &mut out,
+         name,
+         "result=\"failed\"",
+         get(&self.pushes_failed),
+      );
+
+      let name = "xitter_notify_rate_limited_total";
+      header(
+         &mut out,
+         name,
+         "counter",
+         "Requests rejected by rate limits",
+      );
+      sample(
+         &mut out,
+         name,
+         "endpoint=\"register\"",
+         get(&self.register_rate_limited),
+      );
+      sample(
+         &mut out,
+         name,
+         "endpoint=\"unregister\"",
+         get(&self.unregister_rate_limited),
+      );
+
+      let name = "xitter_notify_txid_refreshes_total";
+      header(
+         &mut out,
+         name,
+         "counter",
+         "Fetches of transaction ID keys by result",
+      );
+      sample(&mut out, name, "result=\"ok\"", get(&self.txid_refreshes));
+      sample(
+         &mut out,
+         name,
+         "result=\"error\"",
+         get(&self.txid_refresh_failures),
+      );
+
+      if let Some(age) = txid_age {
+         let name = "xitter_notify_txid_age_seconds";
+         header(&mut out, name, "gauge", "Age of the transaction ID keys");
+         sample(&mut out, name, "", age.as_secs_f64());
+      }
+
+      let name = "xitter_notify_queue_depth";
+      header(&mut out, name, "gauge", "Work waiting in each queue");
+      sample(&mut out, name, "queue=\"polls\"", get(&self.polls_waiting));
+      sample(
+         &mut out,
+         name,
+         "queue=\"push_retries\"",
+         counts.queued_pushes as f64,
+      );
+      sample(
+         &mut out,
+         name,
+         "queue=\"digest\"",
+         counts.digest_items as f64,
+      );
+      sample(
+         &mut out,
+         name,
+         "queue=\"quiet_hours\"",
+         counts.held_notifications as f64,
+      );
+
+      let name = "xitter_notify_polls_running";
+      header(&mut out, name, "gauge", "Polls in progress");
+      sample(&mut out, name, "", get(&self.polls_running));
+
+      let name = "xitter_notify_users";
+      header(&mut out, name, "gauge", "Registered users by status");
+      sample(
+         &mut out,
+         name,
+         "status=\"active\"",
+         counts.active_users as f64,
+      );
+      sample(
+         &mut out,
+         name,
+         "status=\"reauth_required\"",
+         counts.reauth_required_users as f64,
+      );
+      sample(
+         &mut out,
+         name,
+         "status=\"disabled\"",
+         counts.disabled_users as f64,
+      );
+
+      let name = "xitter_notify_users_errored";
+      header(
+         &mut out,
+         name,
+         "gauge",
+         "Active users whose last poll failed",
+      );
+      sample(&mut out, name, "", counts.errored_users as f64);
+
+      out
+   }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+   let _ = writeln!(out, "# HELP {name} {help}");
+   let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// One line of a metric, with labels like `status="2xx"` or none
+fn sample(out: &mut String, name: &str, labels: &str, value: f64) {
+   let _ = if labels.is_empty() {
+      writeln!(out, "{name} {value}")
+   } else {
+      writeln!(out, "{name}{{{labels}}} {value}")
+   };
+}
@@ -0,0 +1,686 @@
+use std::path::Path;
+
+use rusqlite::{
+   Connection,
+   Transaction,
+   params,
+};
+use tracing::info;
+
+use crate::{
+   crypto::KeyRing,
+   db::{
+      DbError,
+      column_aad,
+   },
+};
+
+struct Migration {
+   version:     u32,
+   description: &'static str,
+   /// Steps that drop or rewrite data get a backup copy of the database first
+   destructive: bool,
+   apply:       fn(&Transaction<'_>, &KeyRing) -> Result<(), DbError>,
+}
+
+/// All schema migrations, in order. Versions must be contiguous from 1 since
+/// `PRAGMA user_version` records the last one applied.
+const MIGRATIONS: &[Migration] = &[
+   Migration {
+      version:     1,
+      description: "create users table",
+      destructive: false,
+      apply:       create_users,
+   },
+   Migration {
+      version:     2,
+      description: "encrypt stored credentials",
+      // Rewrites the tokens in place, and a backup would keep them in
+      // plaintext next to the encrypted database
+      destructive: false,
+      apply:       encrypt_credentials,
+   },
+   Migration {
+      version:     3,
+      description: "move push endpoints into a devices table",
+      destructive: true,
+      apply:       create_devices,
+   },
+   Migration {
+      version:     4,
+      description: "track disabled devices",
+      destructive: false,
+      apply:       add_device_disabled,
+   },
+   Migration {
+      version:     5,
+      description: "store Web Push encryption keys of devices",
+      destructive: false,
+      apply:       add_device_push_keys,
+   },
+   Migration {
+      version:     6,
+      description: "create server keys table",
+      destructive: false,
+      apply:       create_server_keys,
+   },
+   Migration {
+      version:     7,
+      description: "create push retry queue",
+      destructive: false,
+      apply:       create_push_queue,
+   },
+   Migration {
+      version:     8,
+      description: "track users whose Twitter session expired",
+      destructive: false,
+      apply:       add_user_status,
+   },
+   Migration {
+      version:     9,
+      description: "add direct message cursor and user preferences",
+      destructive: false,
+      apply:       add_dm_cursor_and_preferences,
+   },
+   Migration {
+      version:     10,
+      description: "create digest items table",
+      destructive: false,
+      apply:       create_digest_items,
+   },
+   Migration {
+      version:     11,
+      description: "create table of notifications held for quiet hours",
+      destructive: false,
+      apply:       create_held_notifications,
+   },
+   Migration {
+      version:     12,
+      description: "create notification history table",
+      destructive: false,
+      apply:       create_notification_history,
+   },
+   Migration {
+      version:     13,
+      description: "add device management tokens",
+      destructive: false,
+      apply:       add_management_tokens,
+   },
+   Migration {
+      version:     14,
+      description: "track the outcome of each user's last poll",
+      destructive: false,
+      apply:       add_last_poll,
+   },
+];
+
+fn latest_version() -> u32 {
+   MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Bring the database up to the latest schema version
+pub fn run(conn: &mut Connection, path: &Path, keys: &KeyRing) -> Result<(), DbError> {
+   let current: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+   let latest = latest_version();
+
+   if current > latest {
+      return Err(DbError::SchemaTooNew {
+         found:     current,
+         supported: latest,
+      });
+   }
+
+   for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+      if migration.destructive {
+         backup(conn, path, migration.version - 1)?;
+      }
+
+      let tx = conn.transaction()?;
+      (migration.apply)(&tx, keys)?;
+      tx.pragma_update(None, "user_version", migration.version)?;
+      tx.commit()?;
+
+      info!(
+         "Applied migration {}: {}",
+         migration.version, migration.description
+      );
+   }
+
+   Ok(())
+}
+
+/// Copy the database next to itself as `<path>.v<version>-<timestamp>.bak`
+fn backup(conn: &Connection, path: &Path, version: u32) -> Result<(), DbError> {
+   // In-memory databases have nothing on disk worth keeping
+   if path.as_os_str().is_empty() || path == Path::new(":memory:") {
+      return Ok(());
+   }
+
+   let timestamp = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs());
+   let backup_path = format!("{}.v{version}-{timestamp}.bak", path.display());
+
+   // Only readable by the owner, like the data it copies should be.
+   // `VACUUM INTO` accepts an existing empty file.
+   let mut options = std::fs::OpenOptions::new();
+   options.write(true).create_new(true);
+   #[cfg(unix)]
+   std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+   options
+      .open(&backup_path)
+      .map_err(|e| DbError::Backup(format!("{backup_path}: {e}")))?;
+
+   conn.execute("VACUUM INTO ?1", params![backup_path])?;
+
+   info!("Backed up database to {backup_path}");
+   Ok(())
+}
+
+fn create_users(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   // Databases from before migrations existed already have this table, hence
+   // `IF NOT EXISTS`
+   tx.execute_batch(
+      r#"
+            -- Users registered for notifications
+            CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                twitter_user_id TEXT UNIQUE NOT NULL,
+                auth_token TEXT NOT NULL,
+                csrf_token TEXT NOT NULL,
+                up_endpoint TEXT NOT NULL,
+                last_notif_sort_index TEXT,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_users_twitter_id ON users(twitter_user_id);
+            "#,
+   )?;
+
+   Ok(())
+}
+
+/// Encrypt tokens of rows written before encryption at rest existed. These
+/// are recognizable by their missing data key.
+fn encrypt_credentials(tx: &Transaction<'_>, keys: &KeyRing) -> Result<(), DbError> {
+   // Unversioned databases may already have the column
+   let has_data_key: bool = tx.query_row(
+      "SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'data_key'",
+      [],
+      |row| row.get(0),
+   )?;
+   if !has_data_key {
+      tx.execute("ALTER TABLE users ADD COLUMN data_key TEXT", [])?;
+   }
+
+   let rows = tx
+      .prepare(
+         "SELECT id, twitter_user_id, auth_token, csrf_token FROM users WHERE data_key IS NULL",
+      )?
+      .query_map([], |row| {
+         Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+         ))
+      })?
+      .collect::<Result<Vec<_>, _>>()?;
+
+   for (id, twitter_user_id, auth_token, csrf_token) in &rows {
+      let (key, wrapped) = keys.generate_data_key(&column_aad(twitter_user_id, "data_key"))?;
+
+      tx.execute(
+         "UPDATE users SET data_key = ?1, auth_token = ?2, csrf_token = ?3 WHERE id = ?4",
+         params![
+            wrapped,
+            key.encrypt(auth_token, &column_aad(twitter_user_id, "auth_token"))?,
+            key.encrypt(csrf_token, &column_aad(twitter_user_id, "csrf_token"))?,
+            id
+         ],
+      )?;
+   }
+
+   if !rows.is_empty() {
+      info!("Encrypted credentials of {} existing users", rows.len());
+   }
+
+   Ok(())
+}
+
+fn create_devices(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            -- UnifiedPush endpoints, one per device registered for a user
+            CREATE TABLE devices (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                up_endpoint TEXT NOT NULL,
+                consecutive_failures INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                last_success_at INTEGER,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                UNIQUE(user_id, up_endpoint)
+            );
+
+            CREATE INDEX idx_devices_user_id ON devices(user_id);
+
+            INSERT INTO devices (user_id, up_endpoint, created_at, updated_at)
+            SELECT id, up_endpoint, created_at, updated_at FROM users;
+
+            ALTER TABLE users DROP COLUMN up_endpoint;
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn add_device_disabled(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            ALTER TABLE devices ADD COLUMN disabled_at INTEGER;
+            ALTER TABLE devices ADD COLUMN disabled_reason TEXT;
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn add_device_push_keys(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            ALTER TABLE devices ADD COLUMN p256dh TEXT;
+            ALTER TABLE devices ADD COLUMN auth_secret TEXT;
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn create_server_keys(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            -- Secrets the server generates for itself, such as the VAPID key
+            CREATE TABLE server_keys (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                data_key TEXT NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn create_push_queue(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            -- Pushes that failed transiently and are waiting to be retried
+            CREATE TABLE push_queue (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+                notification TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 1,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+            CREATE INDEX idx_push_queue_next_attempt ON push_queue(next_attempt_at);
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn add_user_status(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            ALTER TABLE users ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+            ALTER TABLE users ADD COLUMN auth_failures INTEGER NOT NULL DEFAULT 0;
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn add_dm_cursor_and_preferences(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            ALTER TABLE users ADD COLUMN last_dm_id INTEGER;
+            ALTER TABLE users ADD COLUMN last_dm_unread_count INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE users ADD COLUMN preferences TEXT NOT NULL DEFAULT '{}';
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn create_digest_items(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            -- Notifications held for a user's next digest
+            CREATE TABLE digest_items (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                notification TEXT NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+            CREATE INDEX idx_digest_items_user ON digest_items(user_id);
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn create_held_notifications(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            -- Notifications that arrived during a user's quiet hours
+            CREATE TABLE held_notifications (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                notification TEXT NOT NULL,
+                release_at INTEGER NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+            CREATE INDEX idx_held_notifications_user ON held_notifications(user_id, release_at);
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn create_notification_history(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            -- Every new notification of a user and what became of it
+            CREATE TABLE notification_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                sort_index TEXT NOT NULL,
+                notification_type TEXT NOT NULL,
+                notification TEXT NOT NULL,
+                status TEXT NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                UNIQUE(user_id, notification_type, sort_index)
+            );
+
+            CREATE INDEX idx_notification_history_user ON notification_history(user_id, sort_index);
+            CREATE INDEX idx_notification_history_created ON notification_history(created_at);
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn add_management_tokens(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   // Existing devices get a token when they register again
+   tx.execute_batch(
+      r#"
+            ALTER TABLE devices ADD COLUMN management_token_hash TEXT;
+
+            CREATE UNIQUE INDEX idx_devices_management_token ON devices(management_token_hash);
+            "#,
+   )?;
+
+   Ok(())
+}
+
+fn add_last_poll(tx: &Transaction<'_>, _keys: &KeyRing) -> Result<(), DbError> {
+   tx.execute_batch(
+      r#"
+            ALTER TABLE users ADD COLUMN last_poll_at INTEGER;
+            ALTER TABLE users ADD COLUMN last_poll_error TEXT;
+            "#,
+   )?;
+
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::db::Db;
+
+   /// A column each migration adds, to tell which ones were applied
+   const ADDED_COLUMNS: &[(u32, &str, &str)] = &[
+      (1, "users", "auth_token"),
+      (2, "users", "data_key"),
+      (3, "devices", "up_endpoint"),
+      (4, "devices", "disabled_at"),
+      (5, "devices", "p256dh"),
+      (6, "server_keys", "value"),
+      (7, "push_queue", "attempts"),
+      (8, "users", "status"),
+      (9, "users", "preferences"),
+      (10, "digest_items", "notification"),
+      (11, "held_notifications", "release_at"),
+      (12, "notification_history", "sort_index"),
+      (13, "devices", "management_token_hash"),
+      (14, "users", "last_poll_at"),
+   ];
+
+   /// The schema from before migrations existed
+   const BASELINE: &str = r#"
+      CREATE TABLE users (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          twitter_user_id TEXT UNIQUE NOT NULL,
+          auth_token TEXT NOT NULL,
+          csrf_token TEXT NOT NULL,
+          up_endpoint TEXT NOT NULL,
+          last_notif_sort_index TEXT,
+          created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+          updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+      );
+
+      CREATE INDEX idx_users_twitter_id ON users(twitter_user_id);
+
+      INSERT INTO users (twitter_user_id, auth_token, csrf_token, up_endpoint)
+      VALUES ('123', 'plain-auth', 'plain-csrf', 'https://push.example/up');
+   "#;
+
+   fn keys() -> KeyRing {
+      KeyRing::from_keys(&[vec![1; 32]]).unwrap()
+   }
+
+   fn user_version(conn: &Connection) -> u32 {
+      conn
+         .query_row("PRAGMA user_version", [], |row| row.get(0))
+         .unwrap()
+   }
+
+   fn has_column(conn: &Connection, table: &str, column: &str) -> bool {
+      conn
+         .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, column],
+            |row| row.get(0),
+         )
+         .unwrap()
+   }
+
+   /// Apply a single migration the way `run` does, without backups
+   fn apply(conn: &mut Connection, migration: &Migration, keys: &KeyRing) {
+      let tx = conn.transaction().unwrap();
+      (migration.apply)(&tx, keys).unwrap();
+      tx.pragma_update(None, "user_version", migration.version)
+         .unwrap();
+      tx.commit().unwrap();
+   }
+
+   #[test]
+   fn versions_are_contiguous() {
+      for (i, migration) in MIGRATIONS.iter().enumerate() {
+         assert_eq!(migration.version as usize, i + 1);
+      }
+      assert_eq!(ADDED_COLUMNS.len(), MIGRATIONS.len());
+   }
+
+   #[test]
+   fn each_version_upgrades_from_the_previous() {
+      let keys = keys();
+
+      for &(version, table, column) in ADDED_COLUMNS {
+         let mut conn = Connection::open_in_memory().unwrap();
+         let mut migrations = MIGRATIONS.iter();
+         for earlier in migrations.by_ref().take(version as usize - 1) {
+            apply(&mut conn, earlier, &keys);
+         }
+         assert_eq!(user_version(&conn), version - 1);
+         assert!(
+            !has_column(&conn, table, column),
+            "{table}.{column} before v{version}"
+         );
+
+         apply(&mut conn, migrations.next().unwrap(), &keys);
+         assert_eq!(user_version(&conn), version);
+         assert!(
+            has_column(&conn, table, column),
+            "{table}.{column} after v{version}"
+         );
+
+         // The rest applies on top of it
+         run(&mut conn, Path::new(":memory:"), &keys).unwrap();
+         assert_eq!(user_version(&conn), latest_version());
+      }
+   }
+
+   #[test]
+   fn upgrades_unversioned_baseline() {
+      let keys = keys();
+      let mut conn = Connection::open_in_memory().unwrap();
+      conn.execute_batch(BASELINE).unwrap();
+
+      run(&mut conn, Path::new(":memory:"), &keys).unwrap();
+      assert_eq!(user_version(&conn), latest_version());
+
+      // Tokens are encrypted under a data key bound to the user
+      let (auth_token, csrf_token, wrapped): (String, String, String) = conn
+         .query_row(
+            "SELECT auth_token, csrf_token, data_key FROM users WHERE twitter_user_id = '123'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+         )
+         .unwrap();
+      assert_ne!(auth_token, "plain-auth");
+      let key = keys
+         .unwrap_data_key(&wrapped, &column_aad("123", "data_key"))
+         .unwrap();
+      assert_eq!(
+         key.decrypt(&auth_token, &column_aad("123", "auth_token"))
+            .unwrap(),
+         "plain-auth"
+      );
+      assert_eq!(
+         key.decrypt(&csrf_token, &column_aad("123", "csrf_token"))
+            .unwrap(),
+         "plain-csrf"
+      );
+
+      // The push endpoint moved to the user's device
+      assert!(!has_column(&conn, "users", "up_endpoint"));
+      let endpoint: String = conn
+         .query_row("SELECT up_endpoint FROM devices", [], |row| row.get(0))
+         .unwrap();
+      assert_eq!(endpoint, "https://push.example/up");
+   }
+
+   #[test]
+   fn refuses_newer_schema() {
+      let mut conn = Connection::open_in_memory().unwrap();
+      conn
+         .pragma_update(None, "user_version", latest_version() + 1)
+         .unwrap();
+
+      let result = run(&mut conn, Path::new(":memory:"), &keys());
+      assert!(matches!(
+         result,
+         Err(DbError::SchemaTooNew { found, supported })
+            if found == latest_version() + 1 && supported == latest_version()
+      ));
+   }
+
+   #[test]
+   fn backs_up_before_destructive_migrations() {
+      let dir = tempfile::tempdir().unwrap();
+      let path = dir.path().join("db.sqlite");
+      let keys = keys();
+
+      let mut conn = Connection::open(&path).unwrap();
+      conn.execute_batch(BASELINE).unwrap();
+      apply(&mut conn, &MIGRATIONS[0], &keys);
+      apply(&mut conn, &MIGRATIONS[1], &keys);
+      run(&mut conn, &path, &keys).unwrap();
+
+      let backups: Vec<_> = std::fs::read_dir(dir.path())
+         .unwrap()
+         .map(|entry| entry.unwrap().path())
+         .filter(|p| p != &path)
+         .collect();
+      assert_eq!(backups.len(), 1);
+      let name = backups[0].file_name().unwrap().to_str().unwrap();
+      assert!(
+         name.starts_with("db.sqlite.v2-") && name.ends_with(".bak"),
+         "{name}"
+      );
+
+      // The backup is the database as it was before migration 3
+      let backup = Connection::open(&backups[0]).unwrap();
+      assert_eq!(user_version(&backup), 2);
+      assert!(has_column(&backup, "users", "up_endpoint"));
+      assert!(!has_column(&backup, "devices", "up_endpoint"));
+
+      #[cfg(unix)]
+      {
+         use std::os::unix::fs::PermissionsExt;
+         let mode = std::fs::metadata(&backups[0]).unwrap().permissions().mode();
+         assert_eq!(mode & 0o777, 0o600);
+      }
+   }
+
+   #[test]
+   fn no_plaintext_credentials_left_on_disk() {
+      let dir = tempfile::tempdir().unwrap();
+      let path = dir.path().join("db.sqlite");
+      let auth_token = "a1b2c3d4e5f60718293a4b5c6d7e8f9012345678";
+      let csrf_token = "f0e1d2c3b4a5968778695a4b3c2d1e0f00112233445566778899aabbccddeeff";
+
+      let conn = Connection::open(&path).unwrap();
+      conn.execute_batch(BASELINE).unwrap();
+      conn
+         .execute(
+            "UPDATE users SET auth_token = ?1, csrf_token = ?2",
+            params![auth_token, csrf_token],
+         )
+         .unwrap();
+      drop(conn);
+
+      // Opened the way the server opens it, which upgrades it
+      drop(Db::open(&path, keys()).unwrap());
+
+      let files: Vec<_> = std::fs::read_dir(dir.path())
+         .unwrap()
+         .map(|entry| entry.unwrap().path())
+         .collect();
+      assert!(files.len() > 1, "no backups written");
+      for file in files {
+         let contents = std::fs::read(&file).unwrap();
+         for token in [auth_token, csrf_token] {
+            assert!(
+               !contents
+                  .windows(token.len())
+                  .any(|window| window == token.as_bytes()),
+               "{token} left in {}",
+               file.display()
+            );
+         }
+      }
+   }
+}
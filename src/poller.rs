@@ -1,91 +1,329 @@
 use std::{
+   collections::{
+      HashMap,
+      HashSet,
+   },
    sync::Arc,
-   time::Duration,
+   time::{
+      Duration,
+      Instant,
+   },
 };
 
+use jiff::Timestamp;
 use tokio::{
-   sync::Semaphore,
-   time::interval,
+   sync::{
+      Semaphore,
+      mpsc,
+   },
+   time::{
+      interval,
+      sleep_until,
+   },
+};
+use tracing::{
+   Instrument,
+   error,
+   info,
+   info_span,
+   warn,
 };
 
 use crate::{
    config::Config,
    db::{
       Db,
+      DbError,
+      DeliveryStatus,
+      Device,
+      HeldNotification,
       User,
+      UserStatus,
+   },
+   delivery::{
+      self,
+      Delivery,
+   },
+   discovery::QueryDiscovery,
+   grouping::{
+      self,
+      Group,
    },
    http_client::HttpClient,
-   twitter,
-   unified_push,
+   metrics::Metrics,
+   scheduler::Schedule,
+   twitter::{
+      self,
+      DirectMessage,
+      Notification,
+      TwitterAuth,
+      TwitterError,
+   },
+   txid::TxIdGenerator,
+   vapid::Vapid,
 };
 
-pub async fn run_poller(db: Arc<Db>, client: Arc<HttpClient>, config: Arc<Config>) {
-   let mut poll_interval = interval(Duration::from_secs(config.poll_interval_secs));
+/// How often old notifications are pruned from the history
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-   eprintln!(
-      "[poller] Starting with {}s interval, max {} concurrent",
-      config.poll_interval_secs, config.max_concurrent
+/// Shared state of the poller and the polls it runs
+pub struct PollerState {
+   pub db:             Arc<Db>,
+   pub client:         Arc<HttpClient>,
+   pub config:         Arc<Config>,
+   pub vapid:          Arc<Vapid>,
+   pub discovery:      Arc<QueryDiscovery>,
+   pub txid_generator: Arc<TxIdGenerator>,
+   pub metrics:        Arc<Metrics>,
+}
+
+/// Poll users on their schedule until the process exits. User IDs received
+/// on `force_poll` are polled right away.
+pub async fn run_poller(state: Arc<PollerState>, mut force_poll: mpsc::UnboundedReceiver<i64>) {
+   let config = &state.config;
+   let base = Duration::from_secs(config.poll_interval_secs);
+   let mut schedule = Schedule::new(
+      base,
+      Duration::from_secs(config.min_poll_interval_secs),
+      Duration::from_secs(config.max_poll_interval_secs),
+   );
+
+   // The user list is re-read once per base interval to pick up
+   // registrations and removals
+   let mut refresh_interval = interval(base);
+   let mut prune_interval = interval(HISTORY_PRUNE_INTERVAL);
+   let mut twitter_ids = HashMap::new();
+
+   let semaphore = Arc::new(Semaphore::new(config.max_concurrent));
+   let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+
+   info!(
+      "Starting with {}s base interval ({}s-{}s), max {} concurrent",
+      config.poll_interval_secs,
+      config.min_poll_interval_secs,
+      config.max_poll_interval_secs,
+      config.max_concurrent
    );
 
    loop {
-      poll_interval.tick().await;
+      let wakeup = schedule
+         .next_wakeup()
+         .unwrap_or_else(|| Instant::now() + base);
 
-      let users = match db.get_all_users() {
-         Ok(users) => users,
-         Err(e) => {
-            eprintln!("[poller] Failed to get users: {e}");
-            continue;
+      tokio::select! {
+         _ = refresh_interval.tick() => {
+            sync_users(&state, &mut schedule, &mut twitter_ids);
          },
-      };
-
-      if users.is_empty() {
-         continue;
+         _ = prune_interval.tick() => {
+            match state
+               .db
+               .prune_history(config.history_max_age_secs, config.history_max_per_user)
+            {
+               Ok(0) => {},
+               Ok(pruned) => info!("Pruned {pruned} notifications from history"),
+               Err(e) => error!("Failed to prune history: {e}"),
+            }
+         },
+         Some((user_id, active)) = done_rx.recv() => {
+            schedule.complete(user_id, active, Instant::now());
+         },
+         Some(user_id) = force_poll.recv() => {
+            // The user may have registered since the last sync
+            if !twitter_ids.contains_key(&user_id) {
+               sync_users(&state, &mut schedule, &mut twitter_ids);
+            }
+            if !schedule.poll_now(user_id, Instant::now()) {
+               warn!(user = user_id, "Can't force a poll, the user isn't being polled");
+            }
+         },
+         () = sleep_until(wakeup.into()) => {},
       }
 
-      eprintln!("[poller] Polling {} users", users.len());
+      for user_id in schedule.take_due(Instant::now()) {
+         let Some(twitter_user_id) = twitter_ids.get(&user_id).cloned() else {
+            continue;
+         };
+
+         let semaphore = semaphore.clone();
+         let done_tx = done_tx.clone();
+         let state = state.clone();
 
-      let semaphore = Arc::new(Semaphore::new(config.max_concurrent));
-      let mut handles = Vec::with_capacity(users.len());
+         // Each poll runs on its own, so a slow account only delays itself.
+         // Its logs carry the internal user ID rather than the Twitter one.
+         state.metrics.poll_queued();
+         let span = info_span!("poll", user = user_id);
+         tokio::spawn(
+            async move {
+               let permit = semaphore.acquire_owned().await.unwrap();
+               state.metrics.poll_started();
 
-      for user in users {
-         let permit = semaphore.clone().acquire_owned().await.unwrap();
-         let db = db.clone();
-         let client = client.clone();
+               // Re-read the user so the cursor reflects the previous poll
+               let active = match state.db.get_user(&twitter_user_id) {
+                  Ok(Some(user)) if user.status == UserStatus::Active => {
+                     poll_and_check_auth(&state, &user).await
+                  },
+                  Ok(_) => false,
+                  Err(e) => {
+                     error!("Failed to load user: {e}");
+                     false
+                  },
+               };
 
-         handles.push(tokio::spawn(async move {
-            if let Err(e) = poll_user(&db, &client, &user).await {
-               eprintln!("[poller] Error polling user {}: {e}", user.twitter_user_id);
+               drop(permit);
+               state.metrics.poll_finished();
+               let _ = done_tx.send((user_id, active));
             }
-            drop(permit);
-         }));
+            .instrument(span),
+         );
       }
+   }
+}
 
-      // Wait for all poll tasks to complete
-      for handle in handles {
-         let _ = handle.await;
-      }
+/// Track exactly the users that should be polled, by ID
+fn sync_users(
+   state: &PollerState,
+   schedule: &mut Schedule,
+   twitter_ids: &mut HashMap<i64, String>,
+) {
+   match state.db.get_all_users() {
+      Ok(users) => {
+         *twitter_ids = users
+            .into_iter()
+            .map(|user| (user.id, user.twitter_user_id))
+            .collect();
+         let ids = twitter_ids.keys().copied().collect();
+         schedule.sync(&ids, Instant::now());
+      },
+      Err(e) => error!("Failed to get users: {e}"),
+   }
+}
+
+/// Poll a user, keeping track of whether Twitter still accepts their session.
+/// Returns whether there were new notifications.
+async fn poll_and_check_auth(state: &PollerState, user: &User) -> bool {
+   let now = Timestamp::now();
+   let started = Instant::now();
+   let result = poll_user(state, user, now).await;
+   state.metrics.record_poll(started.elapsed(), result.is_ok());
+
+   let error = result.as_ref().err().map(ToString::to_string);
+   if let Err(e) = state.db.record_poll(user.id, error.as_deref()) {
+      error!("Failed to record poll: {e}");
    }
+
+   if let Err(e) = flush_digest(state, user).await {
+      error!("Failed to send digest: {e}");
+   }
+   if let Err(e) = release_held(state, user, now).await {
+      error!("Failed to send quiet hours summary: {e}");
+   }
+
+   let e = match result {
+      Ok(active) => {
+         if user.auth_failures > 0
+            && let Err(e) = state.db.reset_auth_failures(user.id)
+         {
+            error!("Failed to reset auth failures: {e}");
+         }
+         return active;
+      },
+      Err(e) => e,
+   };
+
+   warn!("Poll failed: {e}");
+
+   if let Some(TwitterError::Auth(_)) = e.downcast_ref::<TwitterError>()
+      && let Err(e) = handle_auth_failure(state, user).await
+   {
+      error!("Failed to handle auth failure: {e}");
+   }
+
+   false
 }
 
+/// Count a rejected session. Once that happened too often in a row, polling
+/// stops until the user registers again, and their devices are told why.
+async fn handle_auth_failure(state: &PollerState, user: &User) -> Result<(), DbError> {
+   let failures = state.db.record_auth_failure(user.id)?;
+   if failures < state.config.max_auth_failures {
+      return Ok(());
+   }
+
+   warn!(
+      "Session was rejected {failures} times in a row, pausing polling until the user registers \
+       again"
+   );
+
+   // Marked first, so the message below goes out exactly once
+   state
+      .db
+      .set_user_status(user.id, UserStatus::ReauthRequired)?;
+
+   let notif = Notification {
+      sort_index: String::new(),
+      notification_type: "session_expired".to_string(),
+      message: "Your X session has expired. Sign in again to keep getting notifications."
+         .to_string(),
+      ..Default::default()
+   };
+
+   PushTargets::load(&state.db, user)?
+      .push(state, &notif)
+      .await?;
+
+   Ok(())
+}
+
+/// Poll one user, returning whether there were new notifications
 async fn poll_user(
-   db: &Db,
-   client: &HttpClient,
+   state: &PollerState,
    user: &User,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+   now: Timestamp,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
    let auth = user.auth();
 
    // 1. Check badge count (lightweight)
-   let badge = twitter::get_badge_count(client, &auth).await?;
+   let badge =
+      twitter::get_badge_count(&state.client, &state.txid_generator, &state.metrics, &auth).await?;
 
-   if badge.ntab_unread_count == 0 {
-      return Ok(());
+   // A failed DM poll doesn't hold up the timeline. It still fails the poll
+   // afterwards, so it's recorded and a rejected session is counted.
+   let dms = if user.preferences.dm_notifications {
+      poll_dms(state, user, &auth, badge.dm_unread_count, now)
+         .await
+         .inspect_err(|e| warn!("Failed to poll direct messages: {e}"))
+   } else {
+      if user.last_dm_id.is_some() {
+         // Forget the cursor, so turning DMs back on doesn't push the backlog
+         state.db.update_dm_cursor(user.id, None, 0)?;
+      }
+      Ok(false)
+   };
+
+   let active = poll_timeline(state, user, &auth, badge.ntab_unread_count, now).await?;
+
+   Ok(dms? || active)
+}
+
+/// Push the user's new notifications if the unread count says there are any,
+/// returning whether there were
+async fn poll_timeline(
+   state: &PollerState,
+   user: &User,
+   auth: &TwitterAuth,
+   unread_count: i32,
+   now: Timestamp,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+   if unread_count == 0 {
+      return Ok(false);
    }
 
    // 2. Fetch notifications timeline
-   let notifs = twitter::get_notifications(client, &auth).await?;
+   let notifs = fetch_unseen_pages(state, user, auth).await?;
 
-   // 3. Filter new ones (sort_index > last_seen)
-   let new_notifs: Vec<_> = notifs
+   // 3. Filter new ones (sort_index > last_seen), oldest first so the cursor
+   // only ever moves past notifications that were handled
+   let mut new_notifs: Vec<_> = notifs
       .iter()
       .filter(|n| {
          user
@@ -95,34 +333,944 @@ async fn poll_user(
             .unwrap_or(true)
       })
       .collect();
+   new_notifs.sort_by(|a, b| a.sort_index.cmp(&b.sort_index));
 
    if new_notifs.is_empty() {
-      return Ok(());
+      return Ok(false);
    }
 
-   eprintln!(
-      "[poller] User {} has {} new notifications",
-      user.twitter_user_id,
-      new_notifs.len()
-   );
+   info!("{} new notifications", new_notifs.len());
+   push_new(state, user, &new_notifs, now).await?;
 
-   // 4. Send via UnifiedPush
-   for notif in &new_notifs {
-      if let Err(e) = unified_push::send(client, &user.up_endpoint, notif).await {
-         eprintln!(
-            "[poller] Failed to send notification to {}: {e}",
-            user.twitter_user_id
-         );
+   Ok(true)
+}
+
+/// Push new notifications, oldest first, moving the user's last seen
+/// notification past each once it's handled. On an error, last seen stays at
+/// the last notification that was delivered or queued everywhere.
+async fn push_new(
+   state: &PollerState,
+   user: &User,
+   new_notifs: &[&Notification],
+   now: Timestamp,
+) -> Result<(), DbError> {
+   // 4. Drop what the user filtered out, keeping it in the history
+   let mut wanted: Vec<&Notification> = Vec::with_capacity(new_notifs.len());
+   for &notif in new_notifs {
+      if user.preferences.allows(notif) {
+         wanted.push(notif);
+      } else {
+         record_history(state, user, notif, DeliveryStatus::Filtered)?;
+      }
+   }
+
+   // 5. Send via UnifiedPush to every enabled device
+   let mut targets = PushTargets::load(&state.db, user)?;
+
+   if user.preferences.group_notifications {
+      for group in &grouping::group(&wanted) {
+         dispatch(state, user, &mut targets, group, now).await?;
+      }
+   } else {
+      for notif in wanted {
+         dispatch(state, user, &mut targets, &Group::single(notif), now).await?;
+
+         // 6. Update last seen once the notification is delivered or queued
+         // everywhere
+         state.db.update_last_notif(user.id, &notif.sort_index)?;
+      }
+   }
+
+   // A group can hold notifications older than ones pushed after it, and
+   // filtered ones are never pushed, so last seen moves past everything once
+   // all is handled
+   if let Some(newest) = new_notifs.last() {
+      state.db.update_last_notif(user.id, &newest.sort_index)?;
+   }
+
+   Ok(())
+}
+
+/// Fetch timeline pages until one reaches notifications that were seen
+/// before, up to the configured page limit
+async fn fetch_unseen_pages(
+   state: &PollerState,
+   user: &User,
+   auth: &TwitterAuth,
+) -> Result<Vec<Notification>, TwitterError> {
+   let mut notifs: Vec<Notification> = Vec::new();
+   let mut cursor = None;
+
+   for page_number in 1..=state.config.max_notification_pages {
+      let page = twitter::get_notifications(
+         &state.client,
+         &state.discovery,
+         &state.txid_generator,
+         &state.metrics,
+         auth,
+         cursor.as_deref(),
+      )
+      .await?;
+
+      let oldest = page.notifications.last().map(|n| n.sort_index.clone());
+      notifs.extend(page.notifications);
+
+      // On the first poll only the newest page matters, there's nothing to
+      // catch up on
+      let (Some(last_seen), Some(oldest)) = (&user.last_notif_sort_index, oldest) else {
+         break;
+      };
+      if oldest <= *last_seen {
+         break;
+      }
+
+      let Some(bottom_cursor) = page.bottom_cursor else {
+         break;
+      };
+
+      if page_number == state.config.max_notification_pages {
+         warn!("More than {page_number} pages of new notifications, skipping older ones");
+         break;
+      }
+
+      cursor = Some(bottom_cursor);
+   }
+
+   // Pages can overlap when notifications arrive while paging
+   notifs.sort_by(|a, b| b.sort_index.cmp(&a.sort_index));
+   notifs.dedup_by(|a, b| a.sort_index == b.sort_index);
+
+   Ok(notifs)
+}
+
+/// Push direct messages that arrived since the last poll, one notification
+/// per conversation. Returns whether there were any.
+async fn poll_dms(
+   state: &PollerState,
+   user: &User,
+   auth: &TwitterAuth,
+   unread_count: i32,
+   now: Timestamp,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+   if !dm_inbox_changed(user, unread_count) {
+      if unread_count != user.last_dm_unread_count {
+         state
+            .db
+            .update_dm_cursor(user.id, user.last_dm_id, unread_count)?;
+      }
+      return Ok(false);
+   }
+
+   let messages =
+      twitter::get_dm_inbox(&state.client, &state.txid_generator, &state.metrics, auth).await?;
+   let newest = messages.iter().map(|m| m.id).max();
+
+   // The first read only records where the inbox is at, rather than pushing
+   // every message in it
+   let Some(last_id) = user.last_dm_id else {
+      state
+         .db
+         .update_dm_cursor(user.id, Some(newest.unwrap_or(0)), unread_count)?;
+      return Ok(false);
+   };
+
+   let conversations = new_conversations(messages, last_id, &user.twitter_user_id);
+
+   if !conversations.is_empty() {
+      info!("New messages in {} conversations", conversations.len());
+
+      let mut targets = PushTargets::load(&state.db, user)?;
+      for (conversation_id, messages) in &conversations {
+         let notif = dm_notification(conversation_id, messages);
+         if user.preferences.allows(&notif) {
+            dispatch(state, user, &mut targets, &Group::single(&notif), now).await?;
+         } else {
+            record_history(state, user, &notif, DeliveryStatus::Filtered)?;
+         }
+
+         // Move the cursor past each conversation once it's handled, so a
+         // failure doesn't push it again. Conversations come ordered by their
+         // latest message. The unread count stays, so the inbox is read again
+         // for the rest.
+         let latest = messages.last().map(|m| m.id);
+         state
+            .db
+            .update_dm_cursor(user.id, latest, user.last_dm_unread_count)?;
+      }
+   }
+
+   state
+      .db
+      .update_dm_cursor(user.id, newest.max(Some(last_id)), unread_count)?;
+
+   Ok(!conversations.is_empty())
+}
+
+/// Whether the inbox has to be read. Only a rising unread count means new
+/// messages, since reading them lowers it. The inbox is read once regardless
+/// to set the cursor.
+fn dm_inbox_changed(user: &User, unread_count: i32) -> bool {
+   user.last_dm_id.is_none() || unread_count > user.last_dm_unread_count
+}
+
+/// Messages newer than `last_id` that others sent, grouped by conversation
+/// and ordered by each conversation's latest message
+fn new_conversations(
+   messages: Vec<DirectMessage>,
+   last_id: i64,
+   own_id: &str,
+) -> Vec<(String, Vec<DirectMessage>)> {
+   let mut new_messages: Vec<_> = messages
+      .into_iter()
+      .filter(|m| m.id > last_id && m.sender_id != own_id)
+      .collect();
+   new_messages.sort_by_key(|m| m.id);
+
+   let mut conversations: Vec<(String, Vec<DirectMessage>)> = Vec::new();
+   for message in new_messages {
+      match conversations
+         .iter()
+         .position(|(id, _)| *id == message.conversation_id)
+      {
+         Some(i) => {
+            let mut conversation = conversations.remove(i);
+            conversation.1.push(message);
+            conversations.push(conversation);
+         },
+         None => conversations.push((message.conversation_id.clone(), vec![message])),
+      }
+   }
+
+   conversations
+}
+
+/// Longest message preview pushed, in characters
+const DM_PREVIEW_LEN: usize = 200;
+
+/// A push for new messages in one conversation, previewing the latest
+fn dm_notification(conversation_id: &str, messages: &[DirectMessage]) -> Notification {
+   let latest = messages.last().expect("conversations have messages");
+
+   let mut from_users: Vec<String> = Vec::new();
+   for message in messages {
+      if !from_users.contains(&message.sender_name) {
+         from_users.push(message.sender_name.clone());
+      }
+   }
+
+   let mut preview: String = latest.text.chars().take(DM_PREVIEW_LEN).collect();
+   if preview.len() < latest.text.len() {
+      preview.push('…');
+   }
+   if messages.len() > 1 {
+      preview = format!("({} messages) {preview}", messages.len());
+   }
+
+   Notification {
+      sort_index: latest.id.to_string(),
+      notification_type: "dm".to_string(),
+      message: preview,
+      url: Some(format!("https://x.com/messages/{conversation_id}")),
+      from_users,
+      ..Default::default()
+   }
+}
+
+/// Push a group of notifications that arrived at `now`, or hold its members
+/// until the user's quiet hours end or for their next digest
+async fn dispatch(
+   state: &PollerState,
+   user: &User,
+   targets: &mut PushTargets,
+   group: &Group<'_>,
+   now: Timestamp,
+) -> Result<(), DbError> {
+   let status = if let Some(release_at) = user.preferences.held_until(&group.notification, now) {
+      for member in &group.members {
+         state
+            .db
+            .hold_notification(user.id, &to_json(member), release_at.as_second())?;
+      }
+      DeliveryStatus::Held
+   } else if user.preferences.digest_interval_mins > 0 {
+      for member in &group.members {
+         state.db.add_digest_item(user.id, &to_json(member))?;
       }
+      DeliveryStatus::Digest
+   } else {
+      targets.push(state, &group.notification).await?
+   };
+
+   for member in &group.members {
+      record_history(state, user, member, status)?;
+   }
+
+   Ok(())
+}
+
+/// Push the user's held notifications once the digest window since the
+/// oldest has passed. With digests turned off, leftovers go out right away.
+async fn flush_digest(state: &PollerState, user: &User) -> Result<(), DbError> {
+   let window_secs = u64::from(user.preferences.digest_interval_mins) * 60;
+
+   let items = state.db.get_due_digest(user.id, window_secs)?;
+   let Some(last) = items.last() else {
+      return Ok(());
+   };
+
+   let notifs = parse_held(&items);
+   if !notifs.is_empty() {
+      info!("Sending digest of {} notifications", notifs.len());
+      push_summary(state, user, &notifs).await?;
+   }
+
+   state.db.delete_digest_items(user.id, last.id)
+}
+
+/// Push one summary of the notifications held during quiet hours that ended
+/// by `now`. Without quiet hours set anymore, all of them go out.
+async fn release_held(state: &PollerState, user: &User, now: Timestamp) -> Result<(), DbError> {
+   let until = if user.preferences.quiet_hours.is_empty() {
+      i64::MAX
+   } else {
+      now.as_second()
+   };
+
+   let held = state.db.get_released_notifications(user.id, until)?;
+   if held.is_empty() {
+      return Ok(());
    }
 
-   // 5. Update last seen (use the newest sort_index)
-   if let Some(newest) = new_notifs
+   let notifs = parse_held(&held);
+   if !notifs.is_empty() {
+      info!(
+         "Quiet hours ended, sending {} held notifications",
+         notifs.len()
+      );
+      push_summary(state, user, &notifs).await?;
+   }
+
+   let ids: Vec<i64> = held.iter().map(|item| item.id).collect();
+   state.db.delete_held_notifications(&ids)
+}
+
+fn parse_held(held: &[HeldNotification]) -> Vec<Notification> {
+   held
       .iter()
-      .max_by(|a, b| a.sort_index.cmp(&b.sort_index))
-   {
-      db.update_last_notif(user.id, &newest.sort_index)?;
+      .filter_map(|item| {
+         serde_json::from_str(&item.notification)
+            .inspect_err(|e| {
+               warn!("Dropping unreadable held notification {}: {e}", item.id);
+            })
+            .ok()
+      })
+      .collect()
+}
+
+/// Push held notifications as one, updating their history
+async fn push_summary(
+   state: &PollerState,
+   user: &User,
+   notifs: &[Notification],
+) -> Result<(), DbError> {
+   // A lone notification says more as itself than as a summary
+   let notif = match notifs {
+      [single] => single.clone(),
+      notifs => grouping::digest(notifs),
+   };
+
+   let status = PushTargets::load(&state.db, user)?
+      .push(state, &notif)
+      .await?;
+
+   for notif in notifs {
+      record_history(state, user, notif, status)?;
    }
 
    Ok(())
 }
+
+fn record_history(
+   state: &PollerState,
+   user: &User,
+   notif: &Notification,
+   status: DeliveryStatus,
+) -> Result<(), DbError> {
+   state.db.record_history(
+      user.id,
+      &notif.sort_index,
+      &notif.notification_type,
+      &to_json(notif),
+      status,
+   )
+}
+
+fn to_json(notif: &Notification) -> String {
+   serde_json::to_string(notif).expect("notifications serialize to JSON")
+}
+
+/// A user's enabled devices, for pushing several notifications in a row
+struct PushTargets {
+   devices: Vec<Device>,
+   /// Devices that failed during this poll; their remaining pushes are queued
+   /// without another attempt
+   failing: HashSet<i64>,
+}
+
+impl PushTargets {
+   fn load(db: &Db, user: &User) -> Result<Self, DbError> {
+      let devices = db
+         .get_devices(user.id)?
+         .into_iter()
+         .filter(Device::is_enabled)
+         .collect();
+
+      Ok(Self {
+         devices,
+         failing: HashSet::new(),
+      })
+   }
+
+   /// Deliver to every device, or queue it for those that can't take it now
+   async fn push(
+      &mut self,
+      state: &PollerState,
+      notif: &Notification,
+   ) -> Result<DeliveryStatus, DbError> {
+      let mut still_enabled = Vec::with_capacity(self.devices.len());
+      let mut status = DeliveryStatus::Undelivered;
+
+      for device in self.devices.drain(..) {
+         let queue_only = self.failing.contains(&device.id);
+
+         match delivery::deliver(
+            &state.db,
+            &state.client,
+            &state.config,
+            &state.vapid,
+            &device,
+            notif,
+            queue_only,
+         )
+         .await?
+         {
+            Delivery::Delivered => {
+               state.metrics.record_push(true);
+               status = DeliveryStatus::Delivered;
+               still_enabled.push(device);
+            },
+            Delivery::Queued => {
+               // Queueing without an attempt isn't a failed send
+               if !queue_only {
+                  state.metrics.record_push(false);
+               }
+               if status == DeliveryStatus::Undelivered {
+                  status = DeliveryStatus::Queued;
+               }
+               self.failing.insert(device.id);
+               still_enabled.push(device);
+            },
+            Delivery::Disabled => state.metrics.record_push(false),
+         }
+      }
+
+      self.devices = still_enabled;
+      Ok(status)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use std::sync::atomic::{
+      AtomicU32,
+      Ordering,
+   };
+
+   use axum::{
+      Router,
+      extract::Query,
+      http::StatusCode,
+      routing::{
+         get,
+         post,
+      },
+   };
+   use rusqlite::Connection;
+   use tempfile::TempDir;
+
+   use super::*;
+   use crate::{
+      crypto::KeyRing,
+      homepage::Homepage,
+      quiet_hours::QuietWindow,
+      test_server,
+   };
+
+   fn state(dir: &TempDir) -> PollerState {
+      twitter_state(dir, "https://x.com")
+   }
+
+   /// Poller state whose requests to x.com go to `twitter` instead
+   fn twitter_state(dir: &TempDir, twitter: &str) -> PollerState {
+      let keys = KeyRing::from_keys(&[vec![1; 32]]).unwrap();
+      let metrics = Arc::new(Metrics::new());
+      let client = || HttpClient::allowing_http().redirect("https://x.com", twitter);
+      let homepage = Arc::new(Homepage::new(client()));
+
+      PollerState {
+         db: Arc::new(Db::open(dir.path().join("db.sqlite"), keys).unwrap()),
+         client: Arc::new(client()),
+         config: Arc::new(Config::default()),
+         vapid: Arc::new(Vapid::new(&Vapid::generate_pkcs8().unwrap(), None).unwrap()),
+         discovery: Arc::new(QueryDiscovery::new(client(), homepage.clone())),
+         txid_generator: Arc::new(TxIdGenerator::new(client(), homepage, metrics.clone())),
+         metrics,
+      }
+   }
+
+   fn notification(sort_index: &str) -> Notification {
+      Notification {
+         sort_index: sort_index.to_string(),
+         notification_type: "like".to_string(),
+         message: "Someone liked your post".to_string(),
+         ..Notification::default()
+      }
+   }
+
+   #[tokio::test]
+   async fn last_seen_stays_on_db_error() {
+      // Takes the first push and fails the rest
+      let received = Arc::new(AtomicU32::new(0));
+      let counter = received.clone();
+      let app = Router::new().route(
+         "/up",
+         post(move || {
+            async move {
+               if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                  StatusCode::CREATED
+               } else {
+                  StatusCode::SERVICE_UNAVAILABLE
+               }
+            }
+         }),
+      );
+      let endpoint = format!("{}/up", test_server::serve(app).await);
+
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir);
+      state
+         .db
+         .register_user("123", "auth", "csrf", &endpoint, None, "hash")
+         .unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+
+      // Failed pushes can't be queued
+      Connection::open(dir.path().join("db.sqlite"))
+         .unwrap()
+         .execute("DROP TABLE push_queue", [])
+         .unwrap();
+
+      let notifs = [
+         notification("100"),
+         notification("200"),
+         notification("300"),
+      ];
+      let new_notifs: Vec<&Notification> = notifs.iter().collect();
+      assert!(
+         push_new(&state, &user, &new_notifs, Timestamp::now())
+            .await
+            .is_err()
+      );
+      assert_eq!(received.load(Ordering::SeqCst), 2);
+
+      // Last seen moved past the delivered notification only, so the next
+      // poll tries the others again
+      let user = state.db.get_user("123").unwrap().unwrap();
+      assert_eq!(user.last_notif_sort_index.as_deref(), Some("100"));
+   }
+
+   #[tokio::test]
+   async fn last_seen_moves_past_queued_notifications() {
+      let app = Router::new().route("/up", post(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+      let endpoint = format!("{}/up", test_server::serve(app).await);
+
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir);
+      state
+         .db
+         .register_user("123", "auth", "csrf", &endpoint, None, "hash")
+         .unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+
+      let notifs = [notification("100"), notification("200")];
+      let new_notifs: Vec<&Notification> = notifs.iter().collect();
+      push_new(&state, &user, &new_notifs, Timestamp::now())
+         .await
+         .unwrap();
+
+      let user = state.db.get_user("123").unwrap().unwrap();
+      assert_eq!(user.last_notif_sort_index.as_deref(), Some("200"));
+      assert_eq!(state.db.get_metric_counts().unwrap().queued_pushes, 2);
+   }
+
+   fn message(id: i64, conversation_id: &str, sender_id: &str) -> DirectMessage {
+      DirectMessage {
+         id,
+         conversation_id: conversation_id.to_string(),
+         sender_id: sender_id.to_string(),
+         sender_name: format!("User {sender_id}"),
+         text: format!("Message {id}"),
+      }
+   }
+
+   #[test]
+   fn dm_inbox_is_read_when_unread_count_rises() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir);
+      state
+         .db
+         .register_user(
+            "123",
+            "auth",
+            "csrf",
+            "https://push.example/up",
+            None,
+            "hash",
+         )
+         .unwrap();
+      let mut user = state.db.get_user("123").unwrap().unwrap();
+
+      // The first poll reads the inbox to set the cursor
+      assert_eq!(user.last_dm_id, None);
+      assert!(dm_inbox_changed(&user, 0));
+
+      user.last_dm_id = Some(100);
+      user.last_dm_unread_count = 2;
+      assert!(!dm_inbox_changed(&user, 2));
+      // Reading messages lowers the count, which isn't anything new
+      assert!(!dm_inbox_changed(&user, 1));
+      assert!(!dm_inbox_changed(&user, 0));
+      assert!(dm_inbox_changed(&user, 3));
+   }
+
+   #[tokio::test]
+   async fn unchanged_dm_inbox_keeps_the_cursor() {
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir);
+      state
+         .db
+         .register_user(
+            "123",
+            "auth",
+            "csrf",
+            "https://push.example/up",
+            None,
+            "hash",
+         )
+         .unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+      state.db.update_dm_cursor(user.id, Some(100), 3).unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+
+      // A lower count is stored without reading the inbox, so it takes a rise
+      // from there to read it again
+      let found = poll_dms(&state, &user, &user.auth(), 1, Timestamp::now())
+         .await
+         .unwrap();
+      assert!(!found);
+
+      let user = state.db.get_user("123").unwrap().unwrap();
+      assert_eq!(user.last_dm_id, Some(100));
+      assert_eq!(user.last_dm_unread_count, 1);
+      assert!(dm_inbox_changed(&user, 2));
+   }
+
+   #[test]
+   fn new_conversations_since_cursor() {
+      let messages = vec![
+         message(106, "a", "111"),
+         message(105, "b", "222"),
+         message(104, "a", "123"),
+         message(103, "a", "111"),
+         message(102, "c", "333"),
+         message(100, "b", "222"),
+      ];
+
+      let conversations = new_conversations(messages, 100, "123");
+      let summary: Vec<_> = conversations
+         .iter()
+         .map(|(id, messages)| {
+            (
+               id.as_str(),
+               messages.iter().map(|m| m.id).collect::<Vec<_>>(),
+            )
+         })
+         .collect();
+
+      // Own and already seen messages are left out, and the conversation with
+      // the latest message comes last
+      assert_eq!(summary, [
+         ("c", vec![102]),
+         ("b", vec![105]),
+         ("a", vec![103, 106]),
+      ]);
+
+      assert!(new_conversations(vec![message(100, "a", "111")], 100, "123").is_empty());
+      // Nor do own replies make a conversation new
+      assert!(new_conversations(vec![message(101, "a", "123")], 100, "123").is_empty());
+   }
+
+   #[tokio::test]
+   async fn dm_cursor_moves_past_each_handled_conversation() {
+      // Fails the second push only
+      let received = Arc::new(AtomicU32::new(0));
+      let counter = received.clone();
+      let app = Router::new().route(
+         "/up",
+         post(move || {
+            async move {
+               if counter.fetch_add(1, Ordering::SeqCst) == 1 {
+                  StatusCode::SERVICE_UNAVAILABLE
+               } else {
+                  StatusCode::CREATED
+               }
+            }
+         }),
+      );
+      let endpoint = format!("{}/up", test_server::serve(app).await);
+
+      let inbox = Router::new().route(
+         "/i/api/1.1/dm/inbox_initial_state.json",
+         get(|| async { include_str!("../tests/fixtures/dm_inbox.json") }),
+      );
+      let twitter = test_server::serve(inbox).await;
+
+      let dir = tempfile::tempdir().unwrap();
+      let state = twitter_state(&dir, &twitter);
+      state
+         .db
+         .register_user("123", "auth", "csrf", &endpoint, None, "hash")
+         .unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+      state
+         .db
+         .update_dm_cursor(user.id, Some(1850000000000000050), 0)
+         .unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+
+      // Failed pushes can't be queued
+      Connection::open(dir.path().join("db.sqlite"))
+         .unwrap()
+         .execute("DROP TABLE push_queue", [])
+         .unwrap();
+
+      // The group conversation goes out, Alice's fails
+      assert!(
+         poll_dms(&state, &user, &user.auth(), 2, Timestamp::now())
+            .await
+            .is_err()
+      );
+      assert_eq!(received.load(Ordering::SeqCst), 2);
+      let user = state.db.get_user("123").unwrap().unwrap();
+      assert_eq!(user.last_dm_id, Some(1850000000000000200));
+      assert_eq!(user.last_dm_unread_count, 0);
+
+      // The next poll only pushes Alice's conversation
+      assert!(dm_inbox_changed(&user, 2));
+      let found = poll_dms(&state, &user, &user.auth(), 2, Timestamp::now())
+         .await
+         .unwrap();
+      assert!(found);
+      assert_eq!(received.load(Ordering::SeqCst), 3);
+      let user = state.db.get_user("123").unwrap().unwrap();
+      assert_eq!(user.last_dm_id, Some(1850000000000000300));
+      assert_eq!(user.last_dm_unread_count, 2);
+   }
+
+   /// Sort index of the fixtures' notification `n`
+   fn sort_index(n: u32) -> String {
+      format!("18500000000000{n:05}")
+   }
+
+   /// A stand-in for the notifications timeline that serves the recorded
+   /// pages by cursor, returning the cursors it was asked for
+   async fn notifications_timeline() -> (String, Arc<std::sync::Mutex<Vec<Option<String>>>>) {
+      let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+      let cursors = requested.clone();
+
+      let app = Router::new().route(
+         "/i/api/graphql/{query_id}/NotificationsTimeline",
+         get(move |Query(query): Query<HashMap<String, String>>| {
+            let variables: serde_json::Value = serde_json::from_str(&query["variables"]).unwrap();
+            let cursor = variables["cursor"].as_str().map(str::to_string);
+            cursors.lock().unwrap().push(cursor.clone());
+
+            async move {
+               match cursor.as_deref() {
+                  None => include_str!("../tests/fixtures/notifications_page1.json"),
+                  Some("bottom-1") => include_str!("../tests/fixtures/notifications_page2.json"),
+                  Some("bottom-2") => include_str!("../tests/fixtures/notifications_page3.json"),
+                  Some(cursor) => panic!("unexpected cursor {cursor}"),
+               }
+            }
+         }),
+      );
+
+      (test_server::serve(app).await, requested)
+   }
+
+   /// Fetch with the user's last seen notification at `last_seen`, returning
+   /// the sort indexes fetched and the cursors requested
+   async fn fetch(last_seen: Option<u32>, max_pages: u32) -> (Vec<String>, Vec<Option<String>>) {
+      let (twitter, requested) = notifications_timeline().await;
+      let dir = tempfile::tempdir().unwrap();
+      let mut state = twitter_state(&dir, &twitter);
+      state.config = Arc::new(Config {
+         max_notification_pages: max_pages,
+         ..Config::default()
+      });
+
+      state
+         .db
+         .register_user(
+            "123",
+            "auth",
+            "csrf",
+            "https://push.example/up",
+            None,
+            "hash",
+         )
+         .unwrap();
+      let mut user = state.db.get_user("123").unwrap().unwrap();
+      user.last_notif_sort_index = last_seen.map(sort_index);
+
+      let notifs = fetch_unseen_pages(&state, &user, &user.auth())
+         .await
+         .unwrap();
+
+      let sort_indexes = notifs.into_iter().map(|n| n.sort_index).collect();
+      let requested = requested.lock().unwrap().clone();
+      (sort_indexes, requested)
+   }
+
+   #[tokio::test]
+   async fn first_poll_fetches_one_page() {
+      let (notifs, requested) = fetch(None, 5).await;
+
+      assert_eq!(requested, [None]);
+      assert_eq!(notifs, [
+         sort_index(900),
+         sort_index(800),
+         sort_index(700),
+         sort_index(600)
+      ]);
+   }
+
+   #[tokio::test]
+   async fn stops_at_last_seen() {
+      // Page 1 reaches back past it
+      let (notifs, requested) = fetch(Some(650), 5).await;
+      assert_eq!(requested, [None]);
+      assert_eq!(notifs.len(), 4);
+
+      // Page 1 is all new, page 2 reaches it
+      let (notifs, requested) = fetch(Some(450), 5).await;
+      assert_eq!(requested, [None, Some("bottom-1".to_string())]);
+      assert_eq!(notifs.first(), Some(&sort_index(900)));
+      assert_eq!(notifs.last(), Some(&sort_index(400)));
+   }
+
+   #[tokio::test]
+   async fn follows_replaced_cursors() {
+      // Page 2 only has its cursors as replacements of page 1's
+      let (notifs, requested) = fetch(Some(250), 5).await;
+
+      assert_eq!(requested, [
+         None,
+         Some("bottom-1".to_string()),
+         Some("bottom-2".to_string())
+      ]);
+      assert_eq!(notifs.last(), Some(&sort_index(200)));
+   }
+
+   #[tokio::test]
+   async fn stops_at_page_limit() {
+      let (notifs, requested) = fetch(Some(100), 2).await;
+
+      assert_eq!(requested.len(), 2);
+      assert_eq!(notifs.last(), Some(&sort_index(400)));
+   }
+
+   #[tokio::test]
+   async fn drops_overlapping_notifications() {
+      // The reply at 600 is on both page 1 and page 2
+      let (notifs, _) = fetch(Some(450), 5).await;
+
+      assert_eq!(notifs, [
+         sort_index(900),
+         sort_index(800),
+         sort_index(700),
+         sort_index(600),
+         sort_index(500),
+         sort_index(400),
+      ]);
+   }
+
+   #[tokio::test]
+   async fn held_notifications_released_when_quiet_hours_end() {
+      let received = Arc::new(AtomicU32::new(0));
+      let counter = received.clone();
+      let app = Router::new().route(
+         "/up",
+         post(move || {
+            async move {
+               counter.fetch_add(1, Ordering::SeqCst);
+               StatusCode::CREATED
+            }
+         }),
+      );
+      let endpoint = format!("{}/up", test_server::serve(app).await);
+
+      let dir = tempfile::tempdir().unwrap();
+      let state = state(&dir);
+      state
+         .db
+         .register_user("123", "auth", "csrf", &endpoint, None, "hash")
+         .unwrap();
+      let user_id = state.db.get_user("123").unwrap().unwrap().id;
+      state
+         .db
+         .update_preferences(user_id, |prefs| {
+            prefs.quiet_hours = vec![QuietWindow {
+               start: jiff::civil::time(22, 0, 0, 0),
+               end:   jiff::civil::time(7, 0, 0, 0),
+            }];
+         })
+         .unwrap();
+      let user = state.db.get_user("123").unwrap().unwrap();
+
+      let release_at: Timestamp = "2024-10-17T07:00:00Z".parse().unwrap();
+      for n in [100, 200] {
+         let json = to_json(&notification(&sort_index(n)));
+         state
+            .db
+            .hold_notification(user.id, &json, release_at.as_second())
+            .unwrap();
+      }
+      let held = || state.db.get_metric_counts().unwrap().held_notifications;
+
+      // Still quiet a second before
+      let before = release_at - jiff::SignedDuration::from_secs(1);
+      release_held(&state, &user, before).await.unwrap();
+      assert_eq!(received.load(Ordering::SeqCst), 0);
+      assert_eq!(held(), 2);
+
+      // Then summarized in one push, and only once
+      release_held(&state, &user, release_at).await.unwrap();
+      assert_eq!(received.load(Ordering::SeqCst), 1);
+      assert_eq!(held(), 0);
+
+      release_held(&state, &user, release_at).await.unwrap();
+      assert_eq!(received.load(Ordering::SeqCst), 1);
+   }
+}
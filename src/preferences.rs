@@ -0,0 +1,428 @@
+use jiff::{
+   Timestamp,
+   tz::TimeZone,
+};
+use serde::{
+   Deserialize,
+   Serialize,
+};
+use serde_json::{
+   Map,
+   Value,
+};
+
+use crate::{
+   quiet_hours::{
+      self,
+      QuietWindow,
+   },
+   twitter::Notification,
+};
+
+/// Per-user settings for what gets pushed. Stored as JSON, so settings can be
+/// added without a migration; missing ones take their default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {
+   /// Push direct messages as well as the notifications timeline
+   pub dm_notifications:      bool,
+   /// Collapse notifications of the same type about the same tweet within a
+   /// poll into one push
+   pub group_notifications:   bool,
+   /// Hold notifications and push a digest of them this many minutes after
+   /// the first; 0 pushes right away
+   pub digest_interval_mins:  u32,
+   /// Types to push, such as `reply` or `follow`; empty allows all
+   pub allowed_types:         Vec<String>,
+   /// Types never to push, checked after `allowed_types`
+   pub denied_types:          Vec<String>,
+   /// Notifications whose text or tweet contains any of these, ignoring case,
+   /// aren't pushed
+   pub muted_keywords:        Vec<String>,
+   /// Fewest followers an actor needs for the notification to be pushed
+   pub min_follower_count:    u64,
+   /// Types only pushed when a verified account acted
+   pub verified_only_types:   Vec<String>,
+   /// IANA timezone of the quiet hours, such as `Europe/Berlin`; UTC when
+   /// unset
+   pub timezone:              Option<String>,
+   /// Windows in which notifications are held, to be summarized once the
+   /// window ends
+   pub quiet_hours:           Vec<QuietWindow>,
+   /// Screen names whose mentions and replies are pushed during quiet hours
+   pub quiet_hours_allowlist: Vec<String>,
+}
+
+impl Default for Preferences {
+   fn default() -> Self {
+      Self {
+         dm_notifications:      true,
+         group_notifications:   false,
+         digest_interval_mins:  0,
+         allowed_types:         Vec::new(),
+         denied_types:          Vec::new(),
+         muted_keywords:        Vec::new(),
+         min_follower_count:    0,
+         verified_only_types:   Vec::new(),
+         timezone:              None,
+         quiet_hours:           Vec::new(),
+         quiet_hours_allowlist: Vec::new(),
+      }
+   }
+}
+
+impl Preferences {
+   /// Lowercase types and keywords and drop empty entries, as given by a
+   /// client
+   pub fn normalize(&mut self) {
+      for list in [
+         &mut self.allowed_types,
+         &mut self.denied_types,
+         &mut self.muted_keywords,
+         &mut self.verified_only_types,
+         &mut self.quiet_hours_allowlist,
+      ] {
+         *list = list
+            .iter()
+            .map(|entry| entry.trim().trim_start_matches('@').to_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .collect();
+      }
+   }
+
+   /// These preferences with the settings in `update` changed, normalized and
+   /// validated. Settings left out of it stay as they are.
+   pub fn merge(&self, update: &Map<String, Value>) -> Result<Self, String> {
+      let Ok(Value::Object(mut merged)) = serde_json::to_value(self) else {
+         unreachable!("preferences serialize to a JSON object");
+      };
+
+      for (name, value) in update {
+         if !merged.contains_key(name) {
+            return Err(format!("Unknown setting {name:?}"));
+         }
+         merged.insert(name.clone(), value.clone());
+      }
+
+      let mut preferences: Preferences = serde_json::from_value(Value::Object(merged))
+         .map_err(|e| format!("Invalid preferences: {e}"))?;
+      preferences.normalize();
+      preferences.validate()?;
+
+      Ok(preferences)
+   }
+
+   /// Check settings that can't simply be normalized, returning what's wrong
+   pub fn validate(&self) -> Result<(), String> {
+      if let Some(name) = &self.timezone {
+         TimeZone::get(name).map_err(|e| format!("Unknown timezone {name:?}: {e}"))?;
+      }
+
+      Ok(())
+   }
+
+   /// When a notification arriving at `now` may be pushed, if it has to wait
+   /// for quiet hours to end. Mentions and replies from allowlisted users go
+   /// through right away.
+   pub fn held_until(&self, notif: &Notification, now: Timestamp) -> Option<Timestamp> {
+      if self.quiet_hours.is_empty() {
+         return None;
+      }
+
+      let allowlisted = matches!(notif.notification_type.as_str(), "mention" | "reply")
+         && notif.actors.iter().any(|actor| {
+            let screen_name = actor.screen_name.to_lowercase();
+            self.quiet_hours_allowlist.contains(&screen_name)
+         });
+      if allowlisted {
+         return None;
+      }
+
+      // Validated when set, but the zone could leave the database
+      let tz = self
+         .timezone
+         .as_deref()
+         .and_then(|name| TimeZone::get(name).ok())
+         .unwrap_or(TimeZone::UTC);
+
+      quiet_hours::quiet_until(&self.quiet_hours, &tz, now)
+   }
+
+   /// Whether a notification passes the user's filters
+   pub fn allows(&self, notif: &Notification) -> bool {
+      let notification_type = notif.notification_type.as_str();
+
+      if !self.allowed_types.is_empty()
+         && !self.allowed_types.iter().any(|t| t == notification_type)
+      {
+         return false;
+      }
+      if self.denied_types.iter().any(|t| t == notification_type) {
+         return false;
+      }
+
+      if !self.muted_keywords.is_empty() {
+         let message = notif.message.to_lowercase();
+         let tweet_text = notif.tweet_text.as_deref().unwrap_or("").to_lowercase();
+
+         if self
+            .muted_keywords
+            .iter()
+            .any(|keyword| message.contains(keyword) || tweet_text.contains(keyword))
+         {
+            return false;
+         }
+      }
+
+      let verified_only = self
+         .verified_only_types
+         .iter()
+         .any(|t| t == notification_type);
+
+      // Without actors there's nobody to check, so only the verification
+      // requirement can fail
+      if notif.actors.is_empty() {
+         return !verified_only;
+      }
+
+      // One qualifying actor is enough, e.g. for a like by several users.
+      // Unknown follower counts pass.
+      notif.actors.iter().any(|actor| {
+         actor
+            .followers_count
+            .is_none_or(|count| count >= self.min_follower_count)
+            && (!verified_only || actor.verified)
+      })
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use jiff::civil::time;
+
+   use super::*;
+   use crate::twitter::Actor;
+
+   /// Quiet from 22:00 to 07:00 in `timezone`
+   fn quiet_at_night(timezone: Option<&str>) -> Preferences {
+      Preferences {
+         timezone: timezone.map(str::to_string),
+         quiet_hours: vec![QuietWindow {
+            start: time(22, 0, 0, 0),
+            end:   time(7, 0, 0, 0),
+         }],
+         quiet_hours_allowlist: vec!["friend".to_string()],
+         ..Preferences::default()
+      }
+   }
+
+   fn notification(notification_type: &str, screen_name: &str) -> Notification {
+      Notification {
+         notification_type: notification_type.to_string(),
+         actors: vec![Actor {
+            name:            screen_name.to_string(),
+            screen_name:     screen_name.to_string(),
+            avatar_url:      None,
+            followers_count: None,
+            verified:        false,
+         }],
+         ..Notification::default()
+      }
+   }
+
+   #[test]
+   fn held_during_quiet_hours() {
+      let prefs = quiet_at_night(Some("Europe/Berlin"));
+      let notif = notification("like", "someone");
+
+      // 23:30 and 12:00 in Berlin
+      let night: Timestamp = "2024-10-16T21:30:00Z".parse().unwrap();
+      let day: Timestamp = "2024-10-17T10:00:00Z".parse().unwrap();
+
+      assert_eq!(
+         prefs.held_until(&notif, night),
+         Some("2024-10-17T05:00:00Z".parse().unwrap())
+      );
+      assert_eq!(prefs.held_until(&notif, day), None);
+      assert_eq!(Preferences::default().held_until(&notif, night), None);
+   }
+
+   #[test]
+   fn allowlisted_mentions_and_replies_go_through() {
+      let prefs = quiet_at_night(None);
+      let night: Timestamp = "2024-10-16T23:30:00Z".parse().unwrap();
+
+      assert_eq!(
+         prefs.held_until(&notification("mention", "friend"), night),
+         None
+      );
+      assert_eq!(
+         prefs.held_until(&notification("reply", "Friend"), night),
+         None
+      );
+
+      // Other types from allowlisted users and mentions from others are held
+      assert!(
+         prefs
+            .held_until(&notification("like", "friend"), night)
+            .is_some()
+      );
+      assert!(
+         prefs
+            .held_until(&notification("mention", "someone"), night)
+            .is_some()
+      );
+   }
+
+   #[test]
+   fn unknown_timezone_falls_back_to_utc() {
+      let prefs = quiet_at_night(Some("Mars/Olympus_Mons"));
+      let night: Timestamp = "2024-10-16T23:30:00Z".parse().unwrap();
+
+      assert_eq!(
+         prefs.held_until(&notification("like", "someone"), night),
+         Some("2024-10-17T07:00:00Z".parse().unwrap())
+      );
+   }
+
+   fn actor(screen_name: &str, followers_count: Option<u64>, verified: bool) -> Actor {
+      Actor {
+         name: screen_name.to_string(),
+         screen_name: screen_name.to_string(),
+         avatar_url: None,
+         followers_count,
+         verified,
+      }
+   }
+
+   #[test]
+   fn allows_by_type() {
+      let like = notification("like", "someone");
+      let reply = notification("reply", "someone");
+
+      assert!(Preferences::default().allows(&like));
+
+      let prefs = Preferences {
+         allowed_types: vec!["reply".to_string(), "like".to_string()],
+         denied_types: vec!["like".to_string()],
+         ..Preferences::default()
+      };
+      assert!(prefs.allows(&reply));
+      assert!(!prefs.allows(&like));
+      assert!(!prefs.allows(&notification("follow", "someone")));
+   }
+
+   #[test]
+   fn muted_keywords_match_message_and_tweet() {
+      let prefs = Preferences {
+         muted_keywords: vec!["giveaway".to_string()],
+         ..Preferences::default()
+      };
+
+      let mut notif = notification("reply", "someone");
+      notif.message = "someone replied to you".to_string();
+      assert!(prefs.allows(&notif));
+
+      notif.tweet_text = Some("Huge GIVEAWAY today".to_string());
+      assert!(!prefs.allows(&notif));
+
+      notif.tweet_text = None;
+      notif.message = "someone mentioned your Giveaway".to_string();
+      assert!(!prefs.allows(&notif));
+   }
+
+   #[test]
+   fn allows_by_actor() {
+      let prefs = Preferences {
+         min_follower_count: 100,
+         verified_only_types: vec!["mention".to_string()],
+         ..Preferences::default()
+      };
+      let with_actors = |notification_type: &str, actors: Vec<Actor>| {
+         Notification {
+            notification_type: notification_type.to_string(),
+            actors,
+            ..Notification::default()
+         }
+      };
+
+      assert!(!prefs.allows(&with_actors("like", vec![actor("small", Some(99), false)])));
+      assert!(prefs.allows(&with_actors("like", vec![actor("big", Some(100), false)])));
+      // Unknown counts pass, and one qualifying actor is enough
+      assert!(prefs.allows(&with_actors("like", vec![actor("unknown", None, false)])));
+      assert!(prefs.allows(&with_actors("like", vec![
+         actor("small", Some(5), false),
+         actor("big", Some(500), false),
+      ])));
+
+      assert!(!prefs.allows(&with_actors("mention", vec![actor(
+         "big",
+         Some(500),
+         false
+      )])));
+      assert!(prefs.allows(&with_actors("mention", vec![actor(
+         "blue",
+         Some(500),
+         true
+      )])));
+      assert!(!prefs.allows(&with_actors("mention", vec![actor("blue", Some(5), true)])));
+
+      // Without actors only the verification requirement fails
+      assert!(prefs.allows(&with_actors("like", Vec::new())));
+      assert!(!prefs.allows(&with_actors("mention", Vec::new())));
+   }
+
+   #[test]
+   fn merge_keeps_settings_left_out() {
+      let prefs = Preferences {
+         dm_notifications: false,
+         ..quiet_at_night(Some("Europe/Berlin"))
+      };
+      let update = |json: Value| -> Map<String, Value> {
+         let Value::Object(update) = json else {
+            unreachable!()
+         };
+         update
+      };
+
+      let merged = prefs
+         .merge(&update(serde_json::json!({
+            "digest_interval_mins": 30,
+            "muted_keywords": [" Giveaway ", ""],
+         })))
+         .unwrap();
+      assert_eq!(merged.digest_interval_mins, 30);
+      assert_eq!(merged.muted_keywords, ["giveaway"]);
+      assert!(!merged.dm_notifications);
+      assert_eq!(merged.timezone.as_deref(), Some("Europe/Berlin"));
+      assert_eq!(merged.quiet_hours.len(), 1);
+      assert_eq!(merged.quiet_hours_allowlist, ["friend"]);
+
+      // Given settings replace the stored ones, including with empty values
+      let merged = prefs
+         .merge(&update(
+            serde_json::json!({ "quiet_hours": [], "timezone": null }),
+         ))
+         .unwrap();
+      assert!(merged.quiet_hours.is_empty());
+      assert_eq!(merged.timezone, None);
+
+      assert!(
+         prefs
+            .merge(&update(serde_json::json!({ "dm_notification": true })))
+            .is_err()
+      );
+      assert!(
+         prefs
+            .merge(&update(serde_json::json!({ "dm_notifications": "yes" })))
+            .is_err()
+      );
+      assert!(
+         prefs
+            .merge(&update(
+               serde_json::json!({ "timezone": "Mars/Olympus_Mons" })
+            ))
+            .is_err()
+      );
+   }
+}
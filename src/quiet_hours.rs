@@ -0,0 +1,223 @@
+use jiff::{
+   Timestamp,
+   civil::Time,
+   tz::TimeZone,
+};
+use serde::{
+   Deserialize,
+   Serialize,
+};
+
+/// A daily do-not-disturb window in the user's timezone, such as
+/// `{"start": "22:00", "end": "07:00"}`. It wraps past midnight when it ends
+/// before it starts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuietWindow {
+   pub start: Time,
+   pub end:   Time,
+}
+
+impl QuietWindow {
+   fn contains(&self, time: Time) -> bool {
+      if self.start <= self.end {
+         self.start <= time && time < self.end
+      } else {
+         time >= self.start || time < self.end
+      }
+   }
+}
+
+/// When the quiet hours covering `now` end, or `None` outside of them.
+/// Windows that start as another ends are followed to the end of the last.
+pub fn quiet_until(windows: &[QuietWindow], tz: &TimeZone, now: Timestamp) -> Option<Timestamp> {
+   let mut until = None;
+   let mut at = now.to_zoned(tz.clone());
+
+   // Windows covering the whole day would chain forever, so each gets to
+   // extend the end once
+   for _ in 0..windows.len() {
+      let Some(window) = windows.iter().find(|w| w.contains(at.time())) else {
+         break;
+      };
+
+      // Ending at or before the current time means the end is tomorrow
+      let date = if window.end > at.time() {
+         at.date()
+      } else {
+         at.date().tomorrow().ok()?
+      };
+      // Ends in a DST gap are moved past it
+      let end = date.to_datetime(window.end).to_zoned(tz.clone()).ok()?;
+      if end <= at {
+         break;
+      }
+
+      until = Some(end.timestamp());
+      at = end;
+   }
+
+   until
+}
+
+#[cfg(test)]
+mod tests {
+   use jiff::civil::{
+      DateTime,
+      time,
+   };
+
+   use super::*;
+
+   fn window(start: (i8, i8), end: (i8, i8)) -> QuietWindow {
+      QuietWindow {
+         start: time(start.0, start.1, 0, 0),
+         end:   time(end.0, end.1, 0, 0),
+      }
+   }
+
+   /// A local date and time in `tz`, like `2024-10-16T23:30`
+   fn local(tz: &TimeZone, datetime: &str) -> Timestamp {
+      datetime
+         .parse::<DateTime>()
+         .unwrap()
+         .to_zoned(tz.clone())
+         .unwrap()
+         .timestamp()
+   }
+
+   #[test]
+   fn window_within_a_day() {
+      let tz = TimeZone::get("Europe/Berlin").unwrap();
+      let windows = [window((13, 0), (14, 30))];
+
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T12:59")),
+         None
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T13:00")),
+         Some(local(&tz, "2024-10-16T14:30"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T14:29")),
+         Some(local(&tz, "2024-10-16T14:30"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T14:30")),
+         None
+      );
+   }
+
+   #[test]
+   fn window_past_midnight() {
+      let tz = TimeZone::get("Europe/Berlin").unwrap();
+      let windows = [window((22, 0), (7, 0))];
+
+      // Before midnight it ends the next day, after midnight the same day
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T22:00")),
+         Some(local(&tz, "2024-10-17T07:00"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T23:59")),
+         Some(local(&tz, "2024-10-17T07:00"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-17T03:00")),
+         Some(local(&tz, "2024-10-17T07:00"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-17T07:00")),
+         None
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-17T21:59")),
+         None
+      );
+   }
+
+   #[test]
+   fn windows_in_the_users_timezone() {
+      let tz = TimeZone::get("America/Los_Angeles").unwrap();
+      let windows = [window((22, 0), (7, 0))];
+
+      // 23:00 in Los Angeles, 07:00 UTC the next day
+      let now: Timestamp = "2024-10-17T06:00:00Z".parse().unwrap();
+      assert_eq!(
+         quiet_until(&windows, &tz, now),
+         Some("2024-10-17T14:00:00Z".parse().unwrap())
+      );
+      assert_eq!(
+         quiet_until(&windows, &TimeZone::UTC, now),
+         Some("2024-10-17T07:00:00Z".parse().unwrap())
+      );
+   }
+
+   #[test]
+   fn chained_windows() {
+      let tz = TimeZone::UTC;
+      let windows = [
+         window((7, 0), (9, 0)),
+         window((22, 0), (7, 0)),
+         window((9, 0), (9, 30)),
+      ];
+
+      // Followed through each window that starts as the previous ends
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T23:00")),
+         Some(local(&tz, "2024-10-17T09:30"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-17T08:00")),
+         Some(local(&tz, "2024-10-17T09:30"))
+      );
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-17T09:30")),
+         None
+      );
+   }
+
+   #[test]
+   fn windows_covering_the_whole_day() {
+      let tz = TimeZone::UTC;
+      let windows = [window((8, 0), (20, 0)), window((20, 0), (8, 0))];
+
+      // Each window extends the end once instead of chaining forever
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T12:00")),
+         Some(local(&tz, "2024-10-17T08:00"))
+      );
+
+      // Empty windows cover nothing
+      let windows = [window((9, 0), (9, 0))];
+      assert_eq!(
+         quiet_until(&windows, &tz, local(&tz, "2024-10-16T09:00")),
+         None
+      );
+   }
+
+   #[test]
+   fn end_in_dst_gap() {
+      // Clocks in New York skipped from 02:00 to 03:00 on 10 March 2024
+      let tz = TimeZone::get("America/New_York").unwrap();
+      let windows = [window((22, 0), (2, 30))];
+
+      let until = quiet_until(&windows, &tz, local(&tz, "2024-03-09T23:00")).unwrap();
+      assert_eq!(until, "2024-03-10T07:30:00Z".parse().unwrap());
+      assert_eq!(until.to_zoned(tz.clone()).time(), time(3, 30, 0, 0));
+
+      // Once it's over, the window doesn't cover the time after the gap
+      assert_eq!(quiet_until(&windows, &tz, until), None);
+   }
+
+   #[test]
+   fn end_in_dst_fold() {
+      // Clocks in New York went from 02:00 back to 01:00 on 3 November 2024
+      let tz = TimeZone::get("America/New_York").unwrap();
+      let windows = [window((22, 0), (1, 30))];
+
+      // The earlier of the two 01:30s
+      let until = quiet_until(&windows, &tz, local(&tz, "2024-11-02T23:00")).unwrap();
+      assert_eq!(until, "2024-11-03T05:30:00Z".parse().unwrap());
+   }
+}
@@ -0,0 +1,251 @@
+use std::{
+   collections::{
+      HashMap,
+      HashSet,
+   },
+   time::{
+      Duration,
+      Instant,
+   },
+};
+
+use crate::crypto;
+
+/// How far each poll time is randomly moved, as a fraction of the interval
+const JITTER: f64 = 0.1;
+
+/// Growth of a quiet account's interval after each poll without news
+const BACKOFF_FACTOR: f64 = 1.5;
+
+struct Entry {
+   next_poll: Instant,
+   interval:  Duration,
+   in_flight: bool,
+}
+
+/// Per-user poll times. Accounts with new notifications are polled at the
+/// minimum interval, quiet ones back off towards the maximum.
+pub struct Schedule {
+   entries: HashMap<i64, Entry>,
+   base:    Duration,
+   min:     Duration,
+   max:     Duration,
+}
+
+impl Schedule {
+   pub fn new(base: Duration, min: Duration, max: Duration) -> Self {
+      Self {
+         entries: HashMap::new(),
+         base,
+         min,
+         max,
+      }
+   }
+
+   /// Track exactly the given users. New users get a random first poll within
+   /// the base interval, so a batch of them doesn't poll in lockstep.
+   pub fn sync(&mut self, user_ids: &HashSet<i64>, now: Instant) {
+      self.entries.retain(|id, _| user_ids.contains(id));
+
+      for &id in user_ids {
+         self.entries.entry(id).or_insert_with(|| {
+            Entry {
+               next_poll: now + self.base.mul_f64(crypto::random_f64()),
+               interval:  self.base,
+               in_flight: false,
+            }
+         });
+      }
+   }
+
+   /// Users whose poll is due and not already running, marking them in flight
+   pub fn take_due(&mut self, now: Instant) -> Vec<i64> {
+      self
+         .entries
+         .iter_mut()
+         .filter(|(_, entry)| !entry.in_flight && entry.next_poll <= now)
+         .map(|(&id, entry)| {
+            entry.in_flight = true;
+            id
+         })
+         .collect()
+   }
+
+   /// Schedule the next poll after one finished. `active` is whether it found
+   /// anything new.
+   pub fn complete(&mut self, user_id: i64, active: bool, now: Instant) {
+      let Some(entry) = self.entries.get_mut(&user_id) else {
+         return;
+      };
+
+      entry.interval = if active {
+         self.min
+      } else {
+         entry
+            .interval
+            .mul_f64(BACKOFF_FACTOR)
+            .clamp(self.min, self.max)
+      };
+
+      let jitter = 1.0 + JITTER * (2.0 * crypto::random_f64() - 1.0);
+      entry.next_poll = now + entry.interval.mul_f64(jitter);
+      entry.in_flight = false;
+   }
+
+   /// Move a user's next poll to now. Returns false for users not tracked.
+   pub fn poll_now(&mut self, user_id: i64, now: Instant) -> bool {
+      let Some(entry) = self.entries.get_mut(&user_id) else {
+         return false;
+      };
+
+      // A running poll reschedules once it completes, so it's left alone
+      if !entry.in_flight {
+         entry.next_poll = now;
+      }
+      true
+   }
+
+   /// The earliest upcoming poll of a user not currently being polled
+   pub fn next_wakeup(&self) -> Option<Instant> {
+      self
+         .entries
+         .values()
+         .filter(|entry| !entry.in_flight)
+         .map(|entry| entry.next_poll)
+         .min()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const BASE: Duration = Duration::from_secs(15);
+   const MIN: Duration = Duration::from_secs(10);
+   const MAX: Duration = Duration::from_secs(120);
+
+   fn schedule(user_ids: &[i64], now: Instant) -> Schedule {
+      let mut schedule = Schedule::new(BASE, MIN, MAX);
+      schedule.sync(&user_ids.iter().copied().collect(), now);
+      schedule
+   }
+
+   /// Check the next poll is the interval from `now`, give or take the jitter
+   fn assert_next_poll(schedule: &Schedule, user_id: i64, interval: Duration, now: Instant) {
+      let entry = &schedule.entries[&user_id];
+      assert_eq!(entry.interval, interval);
+
+      let next = entry.next_poll - now;
+      assert!(
+         next >= interval.mul_f64(1.0 - JITTER) && next <= interval.mul_f64(1.0 + JITTER),
+         "next poll in {next:?} for an interval of {interval:?}"
+      );
+   }
+
+   #[test]
+   fn new_users_poll_within_the_base_interval() {
+      let now = Instant::now();
+      let mut schedule = schedule(&[1, 2, 3], now);
+
+      for entry in schedule.entries.values() {
+         assert_eq!(entry.interval, BASE);
+         assert!(entry.next_poll >= now && entry.next_poll <= now + BASE);
+      }
+
+      let mut due = schedule.take_due(now + BASE);
+      due.sort();
+      assert_eq!(due, [1, 2, 3]);
+
+      // Users gone from the database are dropped, the rest keep their state
+      schedule.sync(&[2, 4].into_iter().collect(), now + BASE);
+      let mut tracked: Vec<i64> = schedule.entries.keys().copied().collect();
+      tracked.sort();
+      assert_eq!(tracked, [2, 4]);
+      assert!(schedule.entries[&2].in_flight);
+      assert!(!schedule.entries[&4].in_flight);
+   }
+
+   #[test]
+   fn quiet_users_back_off_to_the_maximum() {
+      let mut now = Instant::now();
+      let mut schedule = schedule(&[1], now);
+
+      let mut interval = BASE;
+      for _ in 0..10 {
+         now += MAX * 2;
+         assert_eq!(schedule.take_due(now), [1]);
+         schedule.complete(1, false, now);
+
+         interval = interval.mul_f64(BACKOFF_FACTOR).min(MAX);
+         assert_next_poll(&schedule, 1, interval, now);
+      }
+      assert_eq!(interval, MAX);
+   }
+
+   #[test]
+   fn activity_resets_to_the_minimum() {
+      let mut now = Instant::now();
+      let mut schedule = schedule(&[1], now);
+
+      for _ in 0..10 {
+         now += MAX * 2;
+         schedule.take_due(now);
+         schedule.complete(1, false, now);
+      }
+      assert_eq!(schedule.entries[&1].interval, MAX);
+
+      now += MAX * 2;
+      schedule.take_due(now);
+      schedule.complete(1, true, now);
+      assert_next_poll(&schedule, 1, MIN, now);
+
+      // And backs off from there once it's quiet again
+      now += MAX;
+      schedule.take_due(now);
+      schedule.complete(1, false, now);
+      assert_next_poll(&schedule, 1, MIN.mul_f64(BACKOFF_FACTOR), now);
+   }
+
+   #[test]
+   fn users_in_flight_are_left_out() {
+      let now = Instant::now();
+      let mut schedule = schedule(&[1, 2], now);
+      let later = now + BASE;
+
+      let mut due = schedule.take_due(later);
+      due.sort();
+      assert_eq!(due, [1, 2]);
+
+      // Not due again, nor waited for, until their polls complete
+      assert!(schedule.take_due(later + MAX * 10).is_empty());
+      assert_eq!(schedule.next_wakeup(), None);
+
+      schedule.complete(1, true, later);
+      assert_eq!(schedule.next_wakeup(), Some(schedule.entries[&1].next_poll));
+      assert_eq!(schedule.take_due(later + MAX), [1]);
+
+      // Completing users no longer tracked does nothing
+      schedule.complete(3, true, later);
+      assert!(!schedule.entries.contains_key(&3));
+   }
+
+   #[test]
+   fn poll_now_moves_the_next_poll() {
+      let now = Instant::now();
+      let mut schedule = schedule(&[1, 2], now);
+      schedule.take_due(now + BASE);
+      schedule.complete(1, false, now + BASE);
+      schedule.complete(2, false, now + BASE);
+
+      let later = now + BASE + Duration::from_secs(1);
+      assert!(schedule.take_due(later).is_empty());
+      assert!(schedule.poll_now(1, later));
+      assert_eq!(schedule.next_wakeup(), Some(later));
+      assert_eq!(schedule.take_due(later), [1]);
+
+      // A running poll keeps its place, and unknown users are reported
+      assert!(schedule.poll_now(1, later));
+      assert!(schedule.take_due(later).is_empty());
+      assert!(!schedule.poll_now(3, later));
+   }
+}
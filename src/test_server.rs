@@ -0,0 +1,15 @@
+use axum::Router;
+use tokio::net::TcpListener;
+
+/// Serve `app` on a free local port for the rest of the test, returning its
+/// base URL like `http://127.0.0.1:1234`
+pub async fn serve(app: Router) -> String {
+   let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+   let addr = listener.local_addr().unwrap();
+
+   tokio::spawn(async move {
+      axum::serve(listener, app).await.unwrap();
+   });
+
+   format!("http://{addr}")
+}
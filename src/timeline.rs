@@ -0,0 +1,389 @@
+use serde::Deserialize;
+
+/// Response of the `NotificationsTimeline` GraphQL operation. Fields we rely
+/// on are required, so schema drift fails with the path that broke instead of
+/// producing empty notifications. Kinds of instructions, entries and items we
+/// don't know are read as `Unknown` and skipped. Tagged unions are a tag plus
+/// optional fields, since internally tagged enums would hide the path of
+/// errors inside them.
+#[derive(Debug, Deserialize)]
+pub struct TimelineResponse {
+   #[serde(default)]
+   pub errors: Vec<ApiError>,
+   pub data:   Option<ResponseData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiError {
+   pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResponseData {
+   pub user: UserTimelineResult,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserTimelineResult {
+   pub result: UserTimeline,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserTimeline {
+   pub timeline: TimelineContainer,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimelineContainer {
+   pub timeline: Timeline,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Timeline {
+   pub instructions: Vec<Instruction>,
+}
+
+/// An instruction's payload is kept as is until its kind is known, since
+/// kinds we don't know may use the same fields for anything
+#[derive(Debug, Deserialize)]
+pub struct Instruction {
+   #[serde(rename = "type")]
+   pub kind:    InstructionType,
+   /// [`Entry`]s of `TimelineAddEntries`
+   pub entries: Option<serde_json::Value>,
+   /// The [`Entry`] of `TimelineReplaceEntry`
+   pub entry:   Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub enum InstructionType {
+   TimelineAddEntries,
+   TimelineReplaceEntry,
+   #[serde(other)]
+   Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+   /// Missing on replaced cursor entries
+   #[serde(rename = "sortIndex", default)]
+   pub sort_index: String,
+   pub content:    EntryContent,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntryContent {
+   #[serde(rename = "entryType")]
+   pub entry_type:        EntryType,
+   /// Set for `TimelineTimelineItem`
+   #[serde(rename = "itemContent")]
+   pub item_content:      Option<ItemContent>,
+   /// Set for `TimelineTimelineCursor`
+   pub value:             Option<String>,
+   #[serde(rename = "cursorType")]
+   pub cursor_type:       Option<CursorType>,
+   #[serde(rename = "clientEventInfo")]
+   pub client_event_info: Option<ClientEventInfo>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub enum EntryType {
+   TimelineTimelineItem,
+   TimelineTimelineCursor,
+   #[serde(other)]
+   Unknown,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub enum CursorType {
+   Top,
+   Bottom,
+   #[serde(other)]
+   Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientEventInfo {
+   /// What the entry is about, e.g. `user_replied_to_your_tweet`
+   pub element: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ItemContent {
+   #[serde(rename = "itemType")]
+   pub item_type:         ItemType,
+   #[serde(rename = "notificationType")]
+   pub notification_type: Option<String>,
+   /// Icon name such as `heart_icon`, which hints at the notification type
+   pub notification_icon: Option<String>,
+   #[serde(alias = "rich_message")]
+   pub message:           Option<RichText>,
+   pub header:            Option<RichText>,
+   #[serde(alias = "notification_url")]
+   pub url:               Option<UrlRef>,
+   pub icon:              Option<Icon>,
+   #[serde(rename = "fromUsers")]
+   pub from_users:        Option<Vec<UserRef>>,
+   /// Newer notifications carry their users and tweets in here
+   pub template:          Option<Template>,
+   /// Set for `TimelineTweet`, e.g. mentions and replies
+   pub tweet_results:     Option<TweetResults>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub enum ItemType {
+   TimelineNotification,
+   TimelineTweet,
+   #[serde(other)]
+   Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RichText {
+   pub text: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UrlRef {
+   pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Icon {
+   #[serde(rename = "iconUrl")]
+   pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Template {
+   #[serde(default)]
+   pub from_users:     Vec<UserRef>,
+   #[serde(default)]
+   pub target_objects: Vec<TargetObject>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TargetObject {
+   pub tweet_results: Option<TweetResults>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserRef {
+   pub user_results: UserResults,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserResults {
+   /// Missing for suspended or deleted accounts
+   pub result: Option<UserResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserResult {
+   pub is_blue_verified: Option<bool>,
+   pub legacy:           Option<UserLegacy>,
+   /// Newer responses moved the names here
+   pub core:             Option<UserCore>,
+   /// Newer responses moved the profile image here
+   pub avatar:           Option<UserAvatar>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserLegacy {
+   pub name:                    Option<String>,
+   pub screen_name:             Option<String>,
+   pub profile_image_url_https: Option<String>,
+   pub followers_count:         Option<u64>,
+   /// Legacy verification, before paid verification
+   pub verified:                Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserCore {
+   pub name:        Option<String>,
+   pub screen_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserAvatar {
+   pub image_url: Option<String>,
+}
+
+impl UserResult {
+   pub fn name(&self) -> Option<&str> {
+      self
+         .core
+         .as_ref()
+         .and_then(|core| core.name.as_deref())
+         .or_else(|| self.legacy.as_ref()?.name.as_deref())
+   }
+
+   pub fn screen_name(&self) -> Option<&str> {
+      self
+         .core
+         .as_ref()
+         .and_then(|core| core.screen_name.as_deref())
+         .or_else(|| self.legacy.as_ref()?.screen_name.as_deref())
+   }
+
+   pub fn followers_count(&self) -> Option<u64> {
+      self.legacy.as_ref()?.followers_count
+   }
+
+   pub fn is_verified(&self) -> bool {
+      self.is_blue_verified.unwrap_or(false)
+         || self
+            .legacy
+            .as_ref()
+            .and_then(|legacy| legacy.verified)
+            .unwrap_or(false)
+   }
+
+   pub fn avatar_url(&self) -> Option<&str> {
+      self
+         .avatar
+         .as_ref()
+         .and_then(|avatar| avatar.image_url.as_deref())
+         .or_else(|| self.legacy.as_ref()?.profile_image_url_https.as_deref())
+   }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TweetResults {
+   /// Missing for deleted or withheld tweets
+   pub result: Option<TweetResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TweetResult {
+   #[serde(rename = "__typename")]
+   pub typename: Option<String>,
+   pub rest_id:  Option<String>,
+   pub legacy:   Option<TweetLegacy>,
+   /// The author
+   pub core:     Option<TweetCore>,
+   /// Set for `TweetWithVisibilityResults`, which wraps the actual tweet
+   pub tweet:    Option<Box<TweetResult>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TweetCore {
+   pub user_results: UserResults,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TweetLegacy {
+   pub full_text:         String,
+   pub entities:          Option<TweetEntities>,
+   /// All attached media, where `entities` only has the first
+   pub extended_entities: Option<TweetEntities>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TweetEntities {
+   #[serde(default)]
+   pub media: Vec<Media>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Media {
+   /// The image, or the thumbnail of a video or GIF
+   pub media_url_https: String,
+}
+
+impl TweetResult {
+   /// The tweet itself, looking through visibility wrappers
+   pub fn tweet(&self) -> &TweetResult {
+      match (&self.typename, &self.tweet) {
+         (Some(typename), Some(tweet)) if typename == "TweetWithVisibilityResults" => tweet.tweet(),
+         _ => self,
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn tweet_behind_visibility_wrappers() {
+      let result: TweetResult = serde_json::from_value(serde_json::json!({
+         "__typename": "TweetWithVisibilityResults",
+         "tweet": {
+            "__typename": "TweetWithVisibilityResults",
+            "tweet": {
+               "__typename": "Tweet",
+               "rest_id": "1",
+               "legacy": { "full_text": "Hello" }
+            }
+         }
+      }))
+      .unwrap();
+
+      let tweet = result.tweet();
+      assert_eq!(tweet.rest_id.as_deref(), Some("1"));
+      assert_eq!(tweet.legacy.as_ref().unwrap().full_text, "Hello");
+
+      // Plain tweets are their own tweet, even with a nested one (e.g. a quote)
+      let result: TweetResult = serde_json::from_value(serde_json::json!({
+         "__typename": "Tweet",
+         "rest_id": "2",
+         "tweet": { "rest_id": "3" }
+      }))
+      .unwrap();
+      assert_eq!(result.tweet().rest_id.as_deref(), Some("2"));
+   }
+
+   #[test]
+   fn user_fields_old_and_new() {
+      let new: UserResult = serde_json::from_value(serde_json::json!({
+         "core": { "name": "New", "screen_name": "new" },
+         "avatar": { "image_url": "https://pbs.twimg.com/new.jpg" },
+         "legacy": {
+            "name": "Old",
+            "screen_name": "old",
+            "profile_image_url_https": "https://pbs.twimg.com/old.jpg",
+            "followers_count": 7
+         },
+         "is_blue_verified": false
+      }))
+      .unwrap();
+      assert_eq!(new.name(), Some("New"));
+      assert_eq!(new.screen_name(), Some("new"));
+      assert_eq!(new.avatar_url(), Some("https://pbs.twimg.com/new.jpg"));
+      assert_eq!(new.followers_count(), Some(7));
+      assert!(!new.is_verified());
+
+      let old: UserResult = serde_json::from_value(serde_json::json!({
+         "legacy": {
+            "name": "Old",
+            "screen_name": "old",
+            "profile_image_url_https": "https://pbs.twimg.com/old.jpg",
+            "verified": true
+         }
+      }))
+      .unwrap();
+      assert_eq!(old.name(), Some("Old"));
+      assert_eq!(old.screen_name(), Some("old"));
+      assert_eq!(old.avatar_url(), Some("https://pbs.twimg.com/old.jpg"));
+      assert_eq!(old.followers_count(), None);
+      assert!(old.is_verified());
+   }
+
+   #[test]
+   fn unknown_kinds() {
+      let instruction: Instruction = serde_json::from_value(serde_json::json!({
+         "type": "TimelinePinEntry",
+         "entry": {
+            "content": { "entryType": "TimelineTimelineModule", "cursorType": "Gap" }
+         }
+      }))
+      .unwrap();
+
+      assert_eq!(instruction.kind, InstructionType::Unknown);
+      let entry: Entry = serde_json::from_value(instruction.entry.unwrap()).unwrap();
+      let content = entry.content;
+      assert_eq!(content.entry_type, EntryType::Unknown);
+      assert_eq!(content.cursor_type, Some(CursorType::Unknown));
+   }
+}
@@ -1,11 +1,35 @@
 use serde::{
    Deserialize,
    Serialize,
+   de::DeserializeOwned,
+};
+use tracing::{
+   info,
+   warn,
 };
 
-use crate::http_client::{
-   HttpClient,
-   HttpError,
+use crate::{
+   discovery::{
+      Operation,
+      QueryDiscovery,
+   },
+   http_client::{
+      HttpClient,
+      HttpError,
+   },
+   metrics::Metrics,
+   timeline::{
+      CursorType,
+      Entry,
+      EntryContent,
+      EntryType,
+      InstructionType,
+      ItemType,
+      TimelineResponse,
+      TweetResult,
+      UserResult,
+   },
+   txid::TxIdGenerator,
 };
 
 const BEARER_TOKEN: &str = "Bearer AAAAAAAAAAAAAAAAAAAAANRILgAAAAAAnNwIzUejRCOuH5E6I8xnZz4puTs%\
@@ -13,13 +37,22 @@ const BEARER_TOKEN: &str = "Bearer AAAAAAAAAAAAAAAAAAAAANRILgAAAAAAnNwIzUejRCOuH
 const USER_AGENT: &str = "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like \
                           Gecko) Chrome/123.0.0.0 Mobile Safari/537.3";
 
-// GraphQL query ID for NotificationsTimeline - this may need periodic updates
-const NOTIFICATIONS_QUERY_ID: &str = "Y-4nWuqrAwaEDpHtfJmK5A";
+const NOTIFICATIONS_OPERATION: &str = "NotificationsTimeline";
+
+// Last known query ID for NotificationsTimeline, used while discovery fails
+const FALLBACK_NOTIFICATIONS_QUERY_ID: &str = "Y-4nWuqrAwaEDpHtfJmK5A";
 
 #[derive(Debug)]
 pub enum TwitterError {
    Http(HttpError),
+   /// The session was rejected, e.g. because the auth token was revoked
+   Auth(HttpError),
    Parse(String),
+   /// The response didn't have the expected shape at `path`
+   Schema {
+      path:    String,
+      message: String,
+   },
    Api(String),
 }
 
@@ -27,7 +60,11 @@ impl std::fmt::Display for TwitterError {
    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
       match self {
          TwitterError::Http(e) => write!(f, "HTTP error: {e}"),
+         TwitterError::Auth(e) => write!(f, "Authentication failed: {e}"),
          TwitterError::Parse(e) => write!(f, "Parse error: {e}"),
+         TwitterError::Schema { path, message } => {
+            write!(f, "Unexpected response at {path}: {message}")
+         },
          TwitterError::Api(e) => write!(f, "API error: {e}"),
       }
    }
@@ -35,12 +72,86 @@ impl std::fmt::Display for TwitterError {
 
 impl std::error::Error for TwitterError {}
 
+impl TwitterError {
+   /// Whether the query ID was likely rotated out, so the operation doesn't
+   /// exist under it anymore
+   fn is_stale_query(&self) -> bool {
+      match self {
+         TwitterError::Http(HttpError::Status(code, _)) => code.as_u16() == 404,
+         TwitterError::Api(message) => message.contains("Query: Unspecified"),
+         _ => false,
+      }
+   }
+
+   /// Features named by a "The following features cannot be null: a, b"
+   /// error, which comes either as an HTTP 400 body or a GraphQL error
+   fn missing_features(&self) -> Vec<String> {
+      const MARKER: &str = "cannot be null:";
+
+      let message = match self {
+         TwitterError::Http(HttpError::Status(_, body)) => body,
+         TwitterError::Api(message) => message,
+         _ => return Vec::new(),
+      };
+
+      let Some(start) = message.find(MARKER) else {
+         return Vec::new();
+      };
+
+      // The list ends wherever the error message does
+      message[start + MARKER.len()..]
+         .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ',' || c == ' '))
+         .next()
+         .unwrap_or("")
+         .split(',')
+         .map(str::trim)
+         .filter(|feature| !feature.is_empty())
+         .map(str::to_string)
+         .collect()
+   }
+}
+
 impl From<HttpError> for TwitterError {
    fn from(e: HttpError) -> Self {
-      TwitterError::Http(e)
+      match &e {
+         HttpError::Status(code, body)
+            if code.as_u16() == 401 || (code.as_u16() == 403 && is_auth_error(body)) =>
+         {
+            TwitterError::Auth(e)
+         },
+         _ => TwitterError::Http(e),
+      }
    }
 }
 
+/// Error codes Twitter rejects a session with: "Could not authenticate you"
+/// (32), "Invalid or expired token" (89) and "Bad Authentication data" (215)
+const AUTH_ERROR_CODES: [u32; 3] = [32, 89, 215];
+
+#[derive(Deserialize)]
+struct ErrorCodes {
+   #[serde(default)]
+   errors: Vec<ErrorCode>,
+}
+
+#[derive(Deserialize)]
+struct ErrorCode {
+   code: Option<u32>,
+}
+
+/// Whether a 403 body names the session as the problem. Other 403s, like
+/// those for stale transaction IDs or from rate limiting proxies, say nothing
+/// about the session.
+fn is_auth_error(body: &str) -> bool {
+   serde_json::from_str::<ErrorCodes>(body).is_ok_and(|body| {
+      body.errors.iter().any(|error| {
+         error
+            .code
+            .is_some_and(|code| AUTH_ERROR_CODES.contains(&code))
+      })
+   })
+}
+
 #[derive(Clone)]
 pub struct TwitterAuth {
    pub auth_token: String,
@@ -75,11 +186,10 @@ pub struct BadgeCount {
    #[serde(default)]
    pub ntab_unread_count: i32,
    #[serde(default)]
-   #[expect(unused, reason = "will use later")]
    pub dm_unread_count:   i32,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Notification {
    pub sort_index:        String,
    pub notification_type: String,
@@ -87,6 +197,30 @@ pub struct Notification {
    pub icon_url:          Option<String>,
    pub url:               Option<String>,
    pub from_users:        Vec<String>,
+   /// The users who acted, e.g. liked or replied. Defaults keep pushes queued
+   /// before these fields existed readable.
+   #[serde(default)]
+   pub actors:            Vec<Actor>,
+   /// Text of the tweet the notification is about
+   #[serde(default)]
+   pub tweet_text:        Option<String>,
+   /// First media thumbnail of that tweet
+   #[serde(default)]
+   pub image_url:         Option<String>,
+   /// ID of that tweet
+   #[serde(default)]
+   pub tweet_id:          Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+   pub name:            String,
+   pub screen_name:     String,
+   pub avatar_url:      Option<String>,
+   #[serde(default)]
+   pub followers_count: Option<u64>,
+   #[serde(default)]
+   pub verified:        bool,
 }
 
 impl Notification {
@@ -98,6 +232,15 @@ impl Notification {
          "mention" => "New Mention".to_string(),
          "follow" => "New Follower".to_string(),
          "quote" => "New Quote".to_string(),
+         "session_expired" => "Session Expired".to_string(),
+         "digest" => "Notification Digest".to_string(),
+         "test" => "Test Notification".to_string(),
+         "dm" => {
+            match self.from_users.as_slice() {
+               [] => "New Message".to_string(),
+               names => format!("Message from {}", names.join(", ")),
+            }
+         },
          _ => "New Notification".to_string(),
       }
    }
@@ -105,28 +248,134 @@ impl Notification {
    pub fn body(&self) -> &str {
       &self.message
    }
+
+   /// A push for checking that a device receives them
+   pub fn test() -> Self {
+      Self {
+         notification_type: "test".to_string(),
+         message: "Notifications from this server reach this device.".to_string(),
+         ..Default::default()
+      }
+   }
 }
 
 /// Check the badge count for unread notifications
 pub async fn get_badge_count(
    client: &HttpClient,
+   txid: &TxIdGenerator,
+   metrics: &Metrics,
    auth: &TwitterAuth,
 ) -> Result<BadgeCount, TwitterError> {
    let url = "https://x.com/i/api/2/badge_count/badge_count.json?supports_ntab_urt=1";
 
-   let headers = auth.headers();
-
-   let body = client.get(url, &headers).await?;
+   let body = get_with_txid(client, txid, metrics, auth, url).await?;
 
    serde_json::from_slice(&body).map_err(|e| TwitterError::Parse(e.to_string()))
 }
 
-/// Fetch notifications timeline
+/// Fetch a page of the notifications timeline, the newest one without a
+/// `cursor`. If Twitter no longer knows the query ID or demands features we
+/// don't send, the request is fixed up and retried.
 pub async fn get_notifications(
    client: &HttpClient,
+   discovery: &QueryDiscovery,
+   txid: &TxIdGenerator,
+   metrics: &Metrics,
    auth: &TwitterAuth,
-) -> Result<Vec<Notification>, TwitterError> {
-   let variables = serde_json::json!({
+   cursor: Option<&str>,
+) -> Result<NotificationsPage, TwitterError> {
+   let mut refreshed = false;
+   let mut added_features = false;
+
+   loop {
+      let operation = discovery
+         .operation(NOTIFICATIONS_OPERATION, FALLBACK_NOTIFICATIONS_QUERY_ID)
+         .await;
+
+      let e = match fetch_notifications(client, txid, metrics, auth, &operation, cursor).await {
+         Err(e) => e,
+         result => return result,
+      };
+
+      if !refreshed && e.is_stale_query() {
+         info!(
+            "Query ID {} was rejected ({e}), refreshing",
+            operation.query_id
+         );
+         refreshed = true;
+
+         if let Err(refresh_error) = discovery.invalidate_and_refresh().await {
+            warn!("Failed to refresh query IDs: {refresh_error}");
+            return Err(e);
+         }
+         continue;
+      }
+
+      let missing = e.missing_features();
+      if !added_features && !missing.is_empty() {
+         info!(
+            "Adding features required by {NOTIFICATIONS_OPERATION}: {}",
+            missing.join(", ")
+         );
+         added_features = true;
+
+         discovery.add_required_features(NOTIFICATIONS_OPERATION, &missing);
+         continue;
+      }
+
+      return Err(e);
+   }
+}
+
+/// Feature switches sent with `NotificationsTimeline` unless the web client
+/// says otherwise
+const NOTIFICATIONS_FEATURES: &[(&str, bool)] = &[
+   ("rweb_tipjar_consumption_enabled", true),
+   ("responsive_web_graphql_exclude_directive_enabled", true),
+   ("verified_phone_label_enabled", false),
+   ("creator_subscriptions_tweet_preview_api_enabled", true),
+   ("responsive_web_graphql_timeline_navigation_enabled", true),
+   (
+      "responsive_web_graphql_skip_user_profile_image_extensions_enabled",
+      false,
+   ),
+   ("communities_web_enable_tweet_community_results_fetch", true),
+   ("c9s_tweet_anatomy_moderator_badge_enabled", true),
+   ("articles_preview_enabled", true),
+   ("responsive_web_edit_tweet_api_enabled", true),
+   (
+      "graphql_is_translatable_rweb_tweet_is_translatable_enabled",
+      true,
+   ),
+   ("view_counts_everywhere_api_enabled", true),
+   ("longform_notetweets_consumption_enabled", true),
+   (
+      "responsive_web_twitter_article_tweet_consumption_enabled",
+      true,
+   ),
+   ("tweet_awards_web_tipping_enabled", false),
+   ("creator_subscriptions_quote_tweet_preview_enabled", false),
+   ("freedom_of_speech_not_reach_fetch_enabled", true),
+   ("standardized_nudges_misinfo", true),
+   (
+      "tweet_with_visibility_results_prefer_gql_limited_actions_policy_enabled",
+      true,
+   ),
+   ("rweb_video_timestamps_enabled", true),
+   ("longform_notetweets_rich_text_read_enabled", true),
+   ("longform_notetweets_inline_media_enabled", true),
+   ("responsive_web_enhance_cards_enabled", false),
+];
+
+async fn fetch_notifications(
+   client: &HttpClient,
+   txid: &TxIdGenerator,
+   metrics: &Metrics,
+   auth: &TwitterAuth,
+   operation: &Operation,
+   cursor: Option<&str>,
+) -> Result<NotificationsPage, TwitterError> {
+   let mut variables = serde_json::json!({
        "count": 20,
        "includePromotedContent": false,
        "withCommunity": true,
@@ -135,46 +384,207 @@ pub async fn get_notifications(
        "withVoice": true,
        "withV2Timeline": true
    });
+   if let Some(cursor) = cursor {
+      variables["cursor"] = cursor.into();
+   }
 
-   let features = serde_json::json!({
-       "rweb_tipjar_consumption_enabled": true,
-       "responsive_web_graphql_exclude_directive_enabled": true,
-       "verified_phone_label_enabled": false,
-       "creator_subscriptions_tweet_preview_api_enabled": true,
-       "responsive_web_graphql_timeline_navigation_enabled": true,
-       "responsive_web_graphql_skip_user_profile_image_extensions_enabled": false,
-       "communities_web_enable_tweet_community_results_fetch": true,
-       "c9s_tweet_anatomy_moderator_badge_enabled": true,
-       "articles_preview_enabled": true,
-       "responsive_web_edit_tweet_api_enabled": true,
-       "graphql_is_translatable_rweb_tweet_is_translatable_enabled": true,
-       "view_counts_everywhere_api_enabled": true,
-       "longform_notetweets_consumption_enabled": true,
-       "responsive_web_twitter_article_tweet_consumption_enabled": true,
-       "tweet_awards_web_tipping_enabled": false,
-       "creator_subscriptions_quote_tweet_preview_enabled": false,
-       "freedom_of_speech_not_reach_fetch_enabled": true,
-       "standardized_nudges_misinfo": true,
-       "tweet_with_visibility_results_prefer_gql_limited_actions_policy_enabled": true,
-       "rweb_video_timestamps_enabled": true,
-       "longform_notetweets_rich_text_read_enabled": true,
-       "longform_notetweets_inline_media_enabled": true,
-       "responsive_web_enhance_cards_enabled": false
-   });
+   // Discovered values override the built-in ones
+   let mut features: serde_json::Map<String, serde_json::Value> = NOTIFICATIONS_FEATURES
+      .iter()
+      .map(|(name, value)| (name.to_string(), (*value).into()))
+      .collect();
+   for (name, value) in &operation.features {
+      features.insert(name.clone(), (*value).into());
+   }
 
    let url = format!(
-      "https://x.com/i/api/graphql/{NOTIFICATIONS_QUERY_ID}/NotificationsTimeline?variables={}&features={}",
+      "https://x.com/i/api/graphql/{}/{NOTIFICATIONS_OPERATION}?variables={}&features={}",
+      operation.query_id,
       urlencoding(&variables.to_string()),
-      urlencoding(&features.to_string())
+      urlencoding(&serde_json::Value::Object(features).to_string())
    );
 
-   let headers = auth.headers();
-
-   let body = client.get(&url, &headers).await?;
+   let body = get_with_txid(client, txid, metrics, auth, &url).await?;
 
    parse_notifications(&body)
 }
 
+/// One page of the notifications timeline
+#[derive(Debug, Default)]
+pub struct NotificationsPage {
+   /// Newest first
+   pub notifications: Vec<Notification>,
+   /// Cursor for notifications newer than this page
+   pub top_cursor:    Option<String>,
+   /// Cursor for the page of older notifications
+   pub bottom_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VerifiedAccount {
+   id_str: String,
+}
+
+/// The ID of the account a session belongs to, failing with
+/// [`TwitterError::Auth`] if Twitter doesn't accept the session
+pub async fn verify_credentials(
+   client: &HttpClient,
+   txid: &TxIdGenerator,
+   metrics: &Metrics,
+   auth: &TwitterAuth,
+) -> Result<String, TwitterError> {
+   let url = "https://x.com/i/api/1.1/account/verify_credentials.json?skip_status=true&\
+              include_entities=false&include_email=false";
+
+   let body = get_with_txid(client, txid, metrics, auth, url).await?;
+
+   let account: VerifiedAccount =
+      serde_json::from_slice(&body).map_err(|e| TwitterError::Parse(e.to_string()))?;
+
+   Ok(account.id_str)
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectMessage {
+   /// Snowflake ID, so newer messages have larger IDs
+   pub id:              i64,
+   pub conversation_id: String,
+   pub sender_id:       String,
+   pub sender_name:     String,
+   pub text:            String,
+}
+
+/// Fetch the most recent direct messages across all conversations
+pub async fn get_dm_inbox(
+   client: &HttpClient,
+   txid: &TxIdGenerator,
+   metrics: &Metrics,
+   auth: &TwitterAuth,
+) -> Result<Vec<DirectMessage>, TwitterError> {
+   let url = "https://x.com/i/api/1.1/dm/inbox_initial_state.json?nsfw_filtering_enabled=false&\
+              include_groups=true&include_inbox_timelines=true&include_conversation_info=true&\
+              supports_reactions=true&dm_users=true&include_ext_profile_image_shape=1";
+
+   let body = get_with_txid(client, txid, metrics, auth, url).await?;
+
+   parse_dm_inbox(&body)
+}
+
+fn parse_dm_inbox(body: &[u8]) -> Result<Vec<DirectMessage>, TwitterError> {
+   let json: serde_json::Value =
+      serde_json::from_slice(body).map_err(|e| TwitterError::Parse(e.to_string()))?;
+
+   let Some(inbox) = json.get("inbox_initial_state") else {
+      return Err(TwitterError::Parse(
+         "missing inbox_initial_state".to_string(),
+      ));
+   };
+
+   let users = inbox.get("users");
+   let entries = inbox
+      .get("entries")
+      .and_then(|e| e.as_array())
+      .map(Vec::as_slice)
+      .unwrap_or_default();
+
+   let mut messages = Vec::new();
+
+   for entry in entries {
+      // Other entry kinds are conversation events such as joins or renames
+      let Some(message) = entry.get("message") else {
+         continue;
+      };
+
+      let Some(id) = message
+         .get("id")
+         .and_then(|id| id.as_str())
+         .and_then(|id| id.parse().ok())
+      else {
+         continue;
+      };
+
+      let conversation_id = message
+         .get("conversation_id")
+         .and_then(|c| c.as_str())
+         .unwrap_or("")
+         .to_string();
+
+      let sender_id = message
+         .pointer("/message_data/sender_id")
+         .and_then(|s| s.as_str())
+         .unwrap_or("")
+         .to_string();
+
+      let sender_name = users
+         .and_then(|users| users.get(&sender_id))
+         .and_then(|user| user.get("name"))
+         .and_then(|n| n.as_str())
+         .unwrap_or("Someone")
+         .to_string();
+
+      let text = message
+         .pointer("/message_data/text")
+         .and_then(|t| t.as_str())
+         .unwrap_or("")
+         .to_string();
+
+      messages.push(DirectMessage {
+         id,
+         conversation_id,
+         sender_id,
+         sender_name,
+         text,
+      });
+   }
+
+   Ok(messages)
+}
+
+/// GET an API URL with a transaction ID generated for its path, like the web
+/// client sends. A 403 or 404 may mean the ID was rejected, so that's retried
+/// once with freshly fetched keys.
+async fn get_with_txid(
+   client: &HttpClient,
+   txid: &TxIdGenerator,
+   metrics: &Metrics,
+   auth: &TwitterAuth,
+   url: &str,
+) -> Result<Vec<u8>, HttpError> {
+   let path = url
+      .strip_prefix("https://x.com")
+      .unwrap_or(url)
+      .split('?')
+      .next()
+      .unwrap_or("/");
+
+   let mut retried = false;
+
+   loop {
+      let mut headers = auth.headers();
+
+      // Without an ID the request can still go through, so it's sent anyway
+      match txid.generate("GET", path).await {
+         Ok(id) => headers.push(("x-client-transaction-id", id)),
+         Err(e) => warn!("Failed to generate transaction ID: {e}"),
+      }
+
+      let result = client.get(url, &headers).await;
+      metrics.record_twitter_request(&result);
+
+      match result {
+         Err(HttpError::Status(code, body)) if !retried && matches!(code.as_u16(), 403 | 404) => {
+            retried = true;
+
+            if let Err(e) = txid.refresh_rejected().await {
+               warn!("Failed to refresh transaction ID keys: {e}");
+               return Err(HttpError::Status(code, body));
+            }
+         },
+         result => return result,
+      }
+   }
+}
+
 fn urlencoding(s: &str) -> String {
    let mut result = String::with_capacity(s.len() * 3);
    for c in s.chars() {
@@ -191,181 +601,559 @@ fn urlencoding(s: &str) -> String {
    result
 }
 
-fn parse_notifications(body: &[u8]) -> Result<Vec<Notification>, TwitterError> {
-   let json: serde_json::Value =
-      serde_json::from_slice(body).map_err(|e| TwitterError::Parse(e.to_string()))?;
+fn parse_notifications(body: &[u8]) -> Result<NotificationsPage, TwitterError> {
+   let de = &mut serde_json::Deserializer::from_slice(body);
+   let response: TimelineResponse = serde_path_to_error::deserialize(de).map_err(|e| {
+      if e.inner().is_syntax() || e.inner().is_eof() {
+         TwitterError::Parse(e.inner().to_string())
+      } else {
+         TwitterError::Schema {
+            path:    e.path().to_string(),
+            message: e.inner().to_string(),
+         }
+      }
+   })?;
 
    // Check for errors
-   if let Some(errors) = json.get("errors")
-      && let Some(first_error) = errors.as_array().and_then(|arr| arr.first())
-   {
-      let message = first_error
-         .get("message")
-         .and_then(|m| m.as_str())
-         .unwrap_or("Unknown error");
-      return Err(TwitterError::Api(message.to_string()));
+   if let Some(error) = response.errors.first() {
+      return Err(TwitterError::Api(error.message.clone()));
    }
 
-   let mut notifications = Vec::new();
-
-   // Navigate to the instructions
-   let instructions = json
-      .pointer("/data/user/result/timeline/timeline/instructions")
-      .and_then(|v| v.as_array());
-
-   let Some(instructions) = instructions else {
-      return Ok(notifications);
+   let Some(data) = response.data else {
+      return Err(TwitterError::Schema {
+         path:    "data".to_string(),
+         message: "missing field `data`".to_string(),
+      });
    };
 
-   for instruction in instructions {
-      if instruction.get("type").and_then(|t| t.as_str()) != Some("TimelineAddEntries") {
-         continue;
-      }
+   let mut page = NotificationsPage::default();
 
-      let entries = instruction.get("entries").and_then(|e| e.as_array());
-      let Some(entries) = entries else {
-         continue;
+   let instructions = data.user.result.timeline.timeline.instructions;
+   for (i, instruction) in instructions.into_iter().enumerate() {
+      let path = format!("data.user.result.timeline.timeline.instructions[{i}]");
+
+      // Pages past the first may deliver their cursors as replacements of the
+      // previous page's
+      let entries: Vec<Entry> = match (instruction.kind, instruction.entries, instruction.entry) {
+         (InstructionType::TimelineAddEntries, Some(entries), _) => {
+            parse_part(entries, &format!("{path}.entries"))?
+         },
+         (InstructionType::TimelineReplaceEntry, _, Some(entry)) => {
+            vec![parse_part(entry, &format!("{path}.entry"))?]
+         },
+         _ => continue,
       };
 
       for entry in entries {
-         let entry_id = entry.get("entryId").and_then(|e| e.as_str()).unwrap_or("");
+         match entry.content.entry_type {
+            EntryType::TimelineTimelineCursor => {
+               let Some(value) = entry.content.value else {
+                  continue;
+               };
 
-         // Skip cursors
-         if entry_id.starts_with("cursor-") {
-            continue;
-         }
-
-         let sort_index = entry
-            .get("sortIndex")
-            .and_then(|s| s.as_str())
-            .unwrap_or("")
-            .to_string();
-
-         if sort_index.is_empty() {
-            continue;
-         }
+               match entry.content.cursor_type {
+                  Some(CursorType::Top) => page.top_cursor = Some(value),
+                  Some(CursorType::Bottom) => page.bottom_cursor = Some(value),
+                  _ => {},
+               }
+            },
+            EntryType::TimelineTimelineItem => {
+               if entry.sort_index.is_empty() {
+                  continue;
+               }
 
-         // Extract notification content
-         let content = entry.get("content");
-         let Some(content) = content else {
-            continue;
-         };
-
-         if let Some(notif) = parse_notification_entry(content, &sort_index) {
-            notifications.push(notif);
+               if let Some(notif) = parse_notification_entry(entry.sort_index, entry.content) {
+                  page.notifications.push(notif);
+               }
+            },
+            EntryType::Unknown => {},
          }
       }
    }
 
    // Sort by sort_index descending (newest first)
-   notifications.sort_by(|a, b| b.sort_index.cmp(&a.sort_index));
+   page
+      .notifications
+      .sort_by(|a, b| b.sort_index.cmp(&a.sort_index));
 
-   Ok(notifications)
+   Ok(page)
 }
 
-fn parse_notification_entry(content: &serde_json::Value, sort_index: &str) -> Option<Notification> {
-   // Try to get the notification from itemContent
-   let item_content = content.get("itemContent")?;
+/// Parse a part of a response that was kept as JSON, with schema errors
+/// located under the part's `path`
+fn parse_part<T: DeserializeOwned>(
+   value: serde_json::Value,
+   path: &str,
+) -> Result<T, TwitterError> {
+   serde_path_to_error::deserialize(value).map_err(|e| {
+      let inner = e.path().to_string();
+      let path = match inner.as_str() {
+         "." => path.to_string(),
+         index if index.starts_with('[') => format!("{path}{index}"),
+         field => format!("{path}.{field}"),
+      };
 
-   let notification_type = item_content
-      .get("notificationType")
-      .and_then(|t| t.as_str())
+      TwitterError::Schema {
+         path,
+         message: e.inner().to_string(),
+      }
+   })
+}
+
+fn parse_notification_entry(sort_index: String, content: EntryContent) -> Option<Notification> {
+   let item = content.item_content?;
+
+   if item.item_type == ItemType::Unknown {
+      return None;
+   }
+
+   // Tweets shown as notifications (mentions, replies, quotes) only say what
+   // they are in the client event info
+   let notification_type = item
+      .notification_type
+      .as_deref()
+      .or_else(|| item.notification_icon.as_deref().and_then(type_from_icon))
+      .or_else(|| {
+         content
+            .client_event_info
+            .as_ref()?
+            .element
+            .as_deref()
+            .and_then(type_from_event_element)
+      })
       .unwrap_or("unknown");
 
-   // Try to extract the message from the notification
-   let message = extract_notification_message(item_content);
+   // The tweet the notification is about, if any
+   let tweet = item
+      .tweet_results
+      .as_ref()
+      .or_else(|| {
+         item
+            .template
+            .as_ref()?
+            .target_objects
+            .iter()
+            .find_map(|target| target.tweet_results.as_ref())
+      })
+      .and_then(|results| results.result.as_ref())
+      .map(TweetResult::tweet);
 
-   // Extract user info
-   let from_users = extract_from_users(item_content);
+   let message = item
+      .message
+      .as_ref()
+      .or(item.header.as_ref())
+      .map(|text| text.text.clone())
+      .or_else(|| Some(tweet?.legacy.as_ref()?.full_text.clone()))
+      .unwrap_or_else(|| "New notification".to_string());
 
-   // Extract URL if available
-   let url = extract_notification_url(item_content);
+   let mut users: Vec<&UserResult> = item
+      .from_users
+      .iter()
+      .flatten()
+      .chain(item.template.iter().flat_map(|t| &t.from_users))
+      .filter_map(|user| user.user_results.result.as_ref())
+      .collect();
 
-   // Extract icon URL
-   let icon_url = item_content
-      .pointer("/icon/iconUrl")
-      .and_then(|u| u.as_str())
-      .map(|s| s.to_string());
+   // A mention or reply is the tweet itself, so its author is the actor
+   if users.is_empty()
+      && let Some(author) = tweet.and_then(|t| t.core.as_ref()?.user_results.result.as_ref())
+   {
+      users.push(author);
+   }
+
+   let from_users = users
+      .iter()
+      .filter_map(|user| user.name())
+      .map(str::to_string)
+      .collect();
+
+   let actors = users
+      .iter()
+      .filter_map(|user| {
+         Some(Actor {
+            name:            user.name()?.to_string(),
+            screen_name:     user.screen_name()?.to_string(),
+            avatar_url:      user.avatar_url().map(str::to_string),
+            followers_count: user.followers_count(),
+            verified:        user.is_verified(),
+         })
+      })
+      .collect();
+
+   let tweet_legacy = tweet.and_then(|t| t.legacy.as_ref());
+   let tweet_text = tweet_legacy.map(|legacy| legacy.full_text.clone());
+   let image_url = tweet_legacy
+      .and_then(|legacy| {
+         legacy
+            .extended_entities
+            .as_ref()
+            .or(legacy.entities.as_ref())?
+            .media
+            .first()
+      })
+      .map(|media| media.media_url_https.clone());
+
+   let tweet_id = tweet.and_then(|t| t.rest_id.clone());
+
+   let url = item
+      .url
+      .map(|url| url.url)
+      .or_else(|| Some(format!("https://x.com/i/status/{}", tweet_id.as_ref()?)));
+
+   let icon_url = item.icon.and_then(|icon| icon.icon_url);
 
    Some(Notification {
-      sort_index: sort_index.to_string(),
+      sort_index,
       notification_type: normalize_notification_type(notification_type),
       message,
       icon_url,
       url,
       from_users,
+      actors,
+      tweet_text,
+      image_url,
+      tweet_id,
    })
 }
 
-fn extract_notification_message(item_content: &serde_json::Value) -> String {
-   // Try multiple paths to get the message
-   if let Some(message) = item_content
-      .pointer("/message/text")
-      .and_then(|t| t.as_str())
-   {
-      return message.to_string();
+fn type_from_icon(icon: &str) -> Option<&'static str> {
+   match icon {
+      "heart_icon" => Some("like"),
+      "retweet_icon" => Some("retweet"),
+      "person_icon" => Some("follow"),
+      _ => None,
    }
+}
 
-   if let Some(header) = item_content
-      .pointer("/header/text")
-      .and_then(|t| t.as_str())
-   {
-      return header.to_string();
+fn type_from_event_element(element: &str) -> Option<&'static str> {
+   match element {
+      "user_mentioned_you" => Some("mention"),
+      "user_replied_to_your_tweet" => Some("reply"),
+      "user_quoted_your_tweet" => Some("quote"),
+      _ => None,
    }
+}
 
-   // For tweet-based notifications, try to get the tweet text
-   if let Some(tweet) = item_content.pointer("/tweet_results/result/legacy/full_text")
-      && let Some(text) = tweet.as_str()
-   {
-      return text.to_string();
+fn normalize_notification_type(notification_type: &str) -> String {
+   match notification_type.to_lowercase().as_str() {
+      "like" | "likes" | "liked" => "like".to_string(),
+      "retweet" | "retweets" | "retweeted" => "retweet".to_string(),
+      "reply" | "replies" | "replied" => "reply".to_string(),
+      "mention" | "mentions" | "mentioned" => "mention".to_string(),
+      "follow" | "follows" | "followed" => "follow".to_string(),
+      "quote" | "quotes" | "quoted" => "quote".to_string(),
+      other => other.to_string(),
    }
-
-   "New notification".to_string()
 }
 
-fn extract_from_users(item_content: &serde_json::Value) -> Vec<String> {
-   let mut users = Vec::new();
+#[cfg(test)]
+mod tests {
+   use hyper::StatusCode;
 
-   // Try to get users from various paths
-   if let Some(from_users) = item_content.get("fromUsers").and_then(|u| u.as_array()) {
-      for user in from_users {
-         if let Some(name) = user
-            .pointer("/user_results/result/legacy/name")
-            .and_then(|n| n.as_str())
-         {
-            users.push(name.to_string());
-         }
+   use super::*;
+
+   fn status_error(code: u16, body: &str) -> TwitterError {
+      HttpError::Status(StatusCode::from_u16(code).unwrap(), body.to_string()).into()
+   }
+
+   #[test]
+   fn auth_errors() {
+      assert!(matches!(status_error(401, ""), TwitterError::Auth(_)));
+      for code in AUTH_ERROR_CODES {
+         let body = format!(r#"{{"errors":[{{"code":{code},"message":"..."}}]}}"#);
+         assert!(matches!(status_error(403, &body), TwitterError::Auth(_)));
       }
+      assert!(matches!(
+         status_error(
+            403,
+            r#"{"errors":[{"message":"Rate limit"},{"code":89,"message":"Invalid or expired token."}]}"#
+         ),
+         TwitterError::Auth(_)
+      ));
    }
 
-   users
-}
+   #[test]
+   fn other_forbidden_errors() {
+      for body in [
+         "",
+         "<html>Forbidden</html>",
+         "{}",
+         r#"{"errors":[]}"#,
+         r#"{"errors":[{"code":353,"message":"This request requires a matching csrf cookie and header."}]}"#,
+         r#"{"errors":[{"message":"Forbidden"}]}"#,
+      ] {
+         assert!(
+            matches!(status_error(403, body), TwitterError::Http(_)),
+            "{body}"
+         );
+      }
 
-fn extract_notification_url(item_content: &serde_json::Value) -> Option<String> {
-   // Try to get URL from notification
-   if let Some(url) = item_content.pointer("/url/url").and_then(|u| u.as_str()) {
-      return Some(url.to_string());
+      let body = r#"{"errors":[{"code":32,"message":"Could not authenticate you."}]}"#;
+      assert!(matches!(status_error(404, body), TwitterError::Http(_)));
+      assert!(matches!(status_error(500, body), TwitterError::Http(_)));
    }
 
-   // Try to get tweet ID for tweet-based notifications
-   if let Some(tweet_id) = item_content
-      .pointer("/tweet_results/result/rest_id")
-      .and_then(|id| id.as_str())
-   {
-      return Some(format!("https://x.com/i/status/{}", tweet_id));
+   #[test]
+   fn dm_inbox() {
+      let messages = parse_dm_inbox(include_bytes!("../tests/fixtures/dm_inbox.json")).unwrap();
+
+      let summary: Vec<_> = messages
+         .iter()
+         .map(|m| {
+            (
+               m.id,
+               m.conversation_id.as_str(),
+               m.sender_id.as_str(),
+               m.sender_name.as_str(),
+               m.text.as_str(),
+            )
+         })
+         .collect();
+      assert_eq!(summary, [
+         (
+            1850000000000000300,
+            "111-123",
+            "111",
+            "Alice",
+            "See you there"
+         ),
+         (
+            1850000000000000200,
+            "1849999999999999999",
+            "333",
+            "Someone",
+            "Welcome to the group"
+         ),
+         (
+            1850000000000000150,
+            "111-123",
+            "123",
+            "Me",
+            "Are you coming tonight?"
+         ),
+         (1850000000000000100, "111-123", "111", "Alice", "Hey"),
+      ]);
    }
 
-   None
-}
+   #[test]
+   fn empty_dm_inbox() {
+      let messages = parse_dm_inbox(br#"{"inbox_initial_state":{"entries":[]}}"#).unwrap();
+      assert!(messages.is_empty());
+      let messages = parse_dm_inbox(br#"{"inbox_initial_state":{}}"#).unwrap();
+      assert!(messages.is_empty());
 
-fn normalize_notification_type(notification_type: &str) -> String {
-   match notification_type.to_lowercase().as_str() {
-      "like" | "likes" | "liked" => "like".to_string(),
-      "retweet" | "retweets" | "retweeted" => "retweet".to_string(),
-      "reply" | "replies" | "replied" => "reply".to_string(),
-      "mention" | "mentions" | "mentioned" => "mention".to_string(),
-      "follow" | "follows" | "followed" => "follow".to_string(),
-      "quote" | "quotes" | "quoted" => "quote".to_string(),
-      other => other.to_string(),
+      assert!(matches!(
+         parse_dm_inbox(br#"{"errors":[]}"#),
+         Err(TwitterError::Parse(_))
+      ));
+      assert!(matches!(
+         parse_dm_inbox(b"<html>"),
+         Err(TwitterError::Parse(_))
+      ));
+   }
+
+   const PAGE_1: &str = include_str!("../tests/fixtures/notifications_page1.json");
+   const PAGE_2: &str = include_str!("../tests/fixtures/notifications_page2.json");
+
+   /// Name, screen name, avatar, followers and whether they're verified
+   type ActorFields<'a> = (&'a str, &'a str, Option<&'a str>, Option<u64>, bool);
+
+   fn actors(notif: &Notification) -> Vec<ActorFields<'_>> {
+      notif
+         .actors
+         .iter()
+         .map(|actor| {
+            (
+               actor.name.as_str(),
+               actor.screen_name.as_str(),
+               actor.avatar_url.as_deref(),
+               actor.followers_count,
+               actor.verified,
+            )
+         })
+         .collect()
+   }
+
+   #[test]
+   fn notifications_page() {
+      let page = parse_notifications(PAGE_1.as_bytes()).unwrap();
+
+      assert_eq!(page.top_cursor.as_deref(), Some("top-1"));
+      assert_eq!(page.bottom_cursor.as_deref(), Some("bottom-1"));
+
+      // The module and the prompt aren't notifications
+      let [like, follow, mention, reply] = page.notifications.as_slice() else {
+         panic!("{:#?}", page.notifications);
+      };
+
+      assert_eq!(like.sort_index, "1850000000000000900");
+      assert_eq!(like.notification_type, "like");
+      assert_eq!(like.message, "Alice and Bob liked your post");
+      assert_eq!(
+         like.url.as_deref(),
+         Some("https://x.com/me/status/1849000000000000001")
+      );
+      assert_eq!(like.from_users, ["Alice", "Bob"]);
+      assert_eq!(actors(like), [
+         (
+            "Alice",
+            "alice",
+            Some("https://pbs.twimg.com/profile_images/1/alice_normal.jpg"),
+            Some(1500),
+            true
+         ),
+         (
+            "Bob",
+            "bob",
+            Some("https://pbs.twimg.com/profile_images/1/bob_normal.jpg"),
+            Some(40),
+            false
+         ),
+      ]);
+      assert_eq!(like.tweet_text.as_deref(), Some("Sunset over the bay"));
+      assert_eq!(
+         like.image_url.as_deref(),
+         Some("https://pbs.twimg.com/media/sunset.jpg")
+      );
+      assert_eq!(like.tweet_id.as_deref(), Some("1849000000000000001"));
+
+      assert_eq!(follow.sort_index, "1850000000000000800");
+      assert_eq!(follow.notification_type, "follow");
+      assert_eq!(follow.message, "Carol followed you");
+      assert_eq!(follow.url.as_deref(), Some("https://x.com/carol"));
+      assert_eq!(follow.from_users, ["Carol"]);
+      assert_eq!(follow.tweet_text, None);
+      assert_eq!(follow.tweet_id, None);
+
+      // A tweet as the notification, with its author as the actor
+      assert_eq!(mention.sort_index, "1850000000000000700");
+      assert_eq!(mention.notification_type, "mention");
+      assert_eq!(mention.message, "@me are you coming tonight?");
+      assert_eq!(
+         mention.url.as_deref(),
+         Some("https://x.com/i/status/1850000000000000700")
+      );
+      assert_eq!(mention.from_users, ["Dave"]);
+      assert_eq!(
+         mention.tweet_text.as_deref(),
+         Some("@me are you coming tonight?")
+      );
+      assert_eq!(mention.image_url, None);
+
+      // The same, wrapped in visibility results
+      assert_eq!(reply.sort_index, "1850000000000000600");
+      assert_eq!(reply.notification_type, "reply");
+      assert_eq!(reply.message, "@me Agreed, see you there");
+      assert_eq!(reply.from_users, ["Alice"]);
+      assert_eq!(reply.tweet_id.as_deref(), Some("1850000000000000600"));
+      assert_eq!(
+         reply.url.as_deref(),
+         Some("https://x.com/i/status/1850000000000000600")
+      );
+   }
+
+   #[test]
+   fn replaced_cursors() {
+      let page = parse_notifications(PAGE_2.as_bytes()).unwrap();
+
+      assert_eq!(page.top_cursor.as_deref(), Some("top-2"));
+      assert_eq!(page.bottom_cursor.as_deref(), Some("bottom-2"));
+      let types: Vec<_> = page
+         .notifications
+         .iter()
+         .map(|n| n.notification_type.as_str())
+         .collect();
+      assert_eq!(types, ["reply", "like", "retweet"]);
+   }
+
+   /// Page 1 with the value at `pointer` replaced, or removed for `None`
+   fn drifted(pointer: &str, value: Option<serde_json::Value>) -> Vec<u8> {
+      let mut json: serde_json::Value = serde_json::from_str(PAGE_1).unwrap();
+      let (parent, key) = pointer.rsplit_once('/').unwrap();
+      let parent = json.pointer_mut(parent).unwrap().as_object_mut().unwrap();
+      match value {
+         Some(value) => parent.insert(key.to_string(), value),
+         None => parent.remove(key),
+      };
+      serde_json::to_vec(&json).unwrap()
+   }
+
+   fn schema_error_path(body: &[u8]) -> String {
+      match parse_notifications(body) {
+         Err(TwitterError::Schema { path, .. }) => path,
+         other => panic!("expected a schema error, got {other:?}"),
+      }
+   }
+
+   #[test]
+   fn schema_drift() {
+      const ENTRY: &str = "/data/user/result/timeline/timeline/instructions/1/entries/1";
+
+      // A field changed its type
+      let body = drifted(
+         &format!("{ENTRY}/content/itemContent/rich_message/text"),
+         Some(serde_json::json!({ "runs": [] })),
+      );
+      assert_eq!(
+         schema_error_path(&body),
+         "data.user.result.timeline.timeline.instructions[1].entries[1].content.itemContent.\
+          rich_message.text"
+      );
+
+      // A required field is gone
+      let body = drifted(&format!("{ENTRY}/content"), None);
+      assert_eq!(
+         schema_error_path(&body),
+         "data.user.result.timeline.timeline.instructions[1].entries[1]"
+      );
+
+      let body = drifted("/data/user/result/timeline", None);
+      assert_eq!(schema_error_path(&body), "data.user.result");
+
+      // Deeper in a tweet behind a visibility wrapper
+      let body = drifted(
+         "/data/user/result/timeline/timeline/instructions/1/entries/5/content/itemContent/\
+          tweet_results/result/tweet/legacy/full_text",
+         Some(serde_json::json!(42)),
+      );
+      assert_eq!(
+         schema_error_path(&body),
+         "data.user.result.timeline.timeline.instructions[1].entries[5].content.itemContent.\
+          tweet_results.result.tweet.legacy.full_text"
+      );
+
+      let body = drifted("/data", None);
+      assert_eq!(schema_error_path(&body), "data");
+   }
+
+   #[test]
+   fn unknown_instructions_with_odd_payloads() {
+      let body = include_bytes!("../tests/fixtures/notifications_odd_instructions.json");
+      let page = parse_notifications(body).unwrap();
+
+      assert_eq!(page.top_cursor.as_deref(), Some("top-1"));
+      assert_eq!(page.bottom_cursor.as_deref(), Some("bottom-1"));
+      let [like] = page.notifications.as_slice() else {
+         panic!("{:#?}", page.notifications);
+      };
+      assert_eq!(like.sort_index, "1850000000000000900");
+      assert_eq!(like.message, "Alice and Bob liked your post");
+   }
+
+   #[test]
+   fn api_and_syntax_errors() {
+      let body = br#"{"errors":[{"message":"Query: Unspecified"}]}"#;
+      assert!(
+         matches!(parse_notifications(body), Err(TwitterError::Api(message)) if message == "Query: Unspecified")
+      );
+
+      assert!(matches!(
+         parse_notifications(&PAGE_1.as_bytes()[..100]),
+         Err(TwitterError::Parse(_))
+      ));
+      assert!(matches!(
+         parse_notifications(b"<html>"),
+         Err(TwitterError::Parse(_))
+      ));
    }
 }
@@ -1,20 +1,37 @@
 use std::{
-   sync::RwLock,
+   sync::{
+      Arc,
+      RwLock,
+   },
    time::{
       Duration,
       Instant,
    },
 };
 
+use tracing::info;
 use xitter_txid::ClientTransaction;
 
-use crate::http_client::HttpClient;
+use crate::{
+   homepage::{
+      self,
+      Homepage,
+   },
+   http_client::HttpClient,
+   metrics::Metrics,
+};
 
 const REFRESH_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60); // 12 hours
 
+/// Keys younger than this aren't refetched when Twitter rejects a request, so
+/// a burst of rejected requests doesn't turn into a burst of homepage downloads
+const MIN_REJECTED_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct TxIdGenerator {
-   client: HttpClient,
-   state:  RwLock<Option<CachedState>>,
+   client:   HttpClient,
+   homepage: Arc<Homepage>,
+   state:    RwLock<Option<CachedState>>,
+   metrics:  Arc<Metrics>,
 }
 
 struct CachedState {
@@ -23,13 +40,21 @@ struct CachedState {
 }
 
 impl TxIdGenerator {
-   pub fn new(client: HttpClient) -> Self {
+   pub fn new(client: HttpClient, homepage: Arc<Homepage>, metrics: Arc<Metrics>) -> Self {
       Self {
          client,
+         homepage,
          state: RwLock::new(None),
+         metrics,
       }
    }
 
+   /// How long ago the cached keys were fetched, `None` before the first fetch
+   pub fn age(&self) -> Option<Duration> {
+      let state = self.state.read().unwrap();
+      state.as_ref().map(|cached| cached.fetched_at.elapsed())
+   }
+
    pub async fn generate(&self, method: &str, path: &str) -> Result<String, TxIdError> {
       // Check if we have a valid cached state
       {
@@ -42,18 +67,25 @@ impl TxIdGenerator {
       }
 
       // Need to refresh
-      self.refresh().await?;
+      self.refresh(homepage::REUSE_MAX_AGE).await?;
 
       let state = self.state.read().unwrap();
       let cached = state.as_ref().ok_or(TxIdError::NotInitialized)?;
       Ok(cached.transaction.generate_transaction_id(method, path))
    }
 
-   async fn refresh(&self) -> Result<(), TxIdError> {
+   /// Fetch new keys from a homepage at most `homepage_max_age` old
+   async fn refresh(&self, homepage_max_age: Duration) -> Result<(), TxIdError> {
+      let result = self.fetch(homepage_max_age).await;
+      self.metrics.record_txid_refresh(result.is_ok());
+      result
+   }
+
+   async fn fetch(&self, homepage_max_age: Duration) -> Result<(), TxIdError> {
       // Fetch homepage
       let html = self
-         .client
-         .get_text("https://x.com")
+         .homepage
+         .get(homepage_max_age)
          .await
          .map_err(|e| TxIdError::Fetch(format!("Failed to fetch homepage: {e}")))?;
 
@@ -81,17 +113,30 @@ impl TxIdGenerator {
          });
       }
 
-      eprintln!("[txid] Refreshed transaction ID keys");
+      info!("Refreshed transaction ID keys");
       Ok(())
    }
 
-   /// Force refresh the cached keys (e.g., on 403/404)
+   /// Force refresh the cached keys (e.g., when a client asks for it)
    pub async fn invalidate_and_refresh(&self) -> Result<(), TxIdError> {
       {
          let mut state = self.state.write().unwrap();
          *state = None;
       }
-      self.refresh().await
+      // The keys were rejected, so a homepage from before that won't do
+      self.refresh(Duration::ZERO).await
+   }
+
+   /// Refresh after Twitter rejected a request (e.g., with a 403/404), unless
+   /// the keys were fetched just now
+   pub async fn refresh_rejected(&self) -> Result<(), TxIdError> {
+      if self
+         .age()
+         .is_some_and(|age| age < MIN_REJECTED_REFRESH_INTERVAL)
+      {
+         return Ok(());
+      }
+      self.invalidate_and_refresh().await
    }
 }
 
@@ -113,3 +158,50 @@ impl std::fmt::Display for TxIdError {
 }
 
 impl std::error::Error for TxIdError {}
+
+#[cfg(test)]
+mod tests {
+   use std::sync::atomic::{
+      AtomicU32,
+      Ordering,
+   };
+
+   use axum::{
+      Router,
+      routing::get,
+   };
+
+   use super::*;
+   use crate::test_server;
+
+   #[tokio::test]
+   async fn forced_refreshes_fetch_the_homepage() {
+      let homepage_fetches = Arc::new(AtomicU32::new(0));
+      let fetches = homepage_fetches.clone();
+      let app = Router::new().route(
+         "/",
+         get(move || {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            async { include_str!("../tests/fixtures/homepage.html") }
+         }),
+      );
+      let server = test_server::serve(app).await;
+
+      let client = || {
+         HttpClient::allowing_http()
+            .redirect("https://x.com", &server)
+            .redirect("https://abs.twimg.com", &server)
+      };
+      let txid = TxIdGenerator::new(
+         client(),
+         Arc::new(Homepage::new(client())),
+         Arc::new(Metrics::new()),
+      );
+
+      // Each one goes out, however recent the last one was
+      for expected in 1..=3 {
+         let _ = txid.invalidate_and_refresh().await;
+         assert_eq!(homepage_fetches.load(Ordering::SeqCst), expected);
+      }
+   }
+}
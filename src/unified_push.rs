@@ -1,17 +1,28 @@
 use serde::Serialize;
 
 use crate::{
+   crypto::CryptoError,
+   db::Device,
    http_client::{
       HttpClient,
       HttpError,
    },
-   twitter::Notification,
+   twitter::{
+      Actor,
+      Notification,
+   },
+   vapid::Vapid,
+   web_push::{
+      self,
+      ClientKeys,
+   },
 };
 
 #[derive(Debug)]
 pub enum UpError {
    Http(HttpError),
    Serialize(String),
+   Encrypt(CryptoError),
 }
 
 impl std::fmt::Display for UpError {
@@ -19,12 +30,26 @@ impl std::fmt::Display for UpError {
       match self {
          UpError::Http(e) => write!(f, "HTTP error: {e}"),
          UpError::Serialize(e) => write!(f, "Serialize error: {e}"),
+         UpError::Encrypt(e) => write!(f, "Encryption error: {e}"),
       }
    }
 }
 
 impl std::error::Error for UpError {}
 
+impl UpError {
+   /// Whether the distributor reported the endpoint as gone for good, as
+   /// opposed to a failure that may clear up on its own
+   pub fn is_permanent(&self) -> bool {
+      match self {
+         UpError::Http(HttpError::Status(code, _)) => {
+            matches!(code.as_u16(), 404 | 410)
+         },
+         _ => false,
+      }
+   }
+}
+
 impl From<HttpError> for UpError {
    fn from(e: HttpError) -> Self {
       UpError::Http(e)
@@ -39,19 +64,36 @@ struct UpPayload {
    data:     UpData,
 }
 
+/// Most actors sent along, e.g. for a like by many users
+const MAX_ACTORS: usize = 5;
+
+/// Tweet text is halved down to this many characters before other content is
+/// left out to fit a push
+const MIN_TWEET_TEXT_CHARS: usize = 100;
+
 #[derive(Serialize)]
 struct UpData {
    url:               Option<String>,
    notification_type: String,
    sort_index:        String,
+   actors:            Vec<Actor>,
+   tweet_text:        Option<String>,
+   image_url:         Option<String>,
+}
+
+impl From<CryptoError> for UpError {
+   fn from(e: CryptoError) -> Self {
+      UpError::Encrypt(e)
+   }
 }
 
 pub async fn send(
    client: &HttpClient,
-   endpoint: &str,
+   vapid: &Vapid,
+   device: &Device,
    notif: &Notification,
 ) -> Result<(), UpError> {
-   let payload = UpPayload {
+   let mut payload = UpPayload {
       title:    notif.title(),
       message:  notif.body().to_string(),
       priority: 3,
@@ -59,14 +101,160 @@ pub async fn send(
          url:               notif.url.clone(),
          notification_type: notif.notification_type.clone(),
          sort_index:        notif.sort_index.clone(),
+         actors:            notif.actors.iter().take(MAX_ACTORS).cloned().collect(),
+         tweet_text:        notif.tweet_text.clone(),
+         image_url:         notif.image_url.clone(),
       },
    };
 
-   let body = serde_json::to_vec(&payload).map_err(|e| UpError::Serialize(e.to_string()))?;
+   let body = serialize_within_limit(&mut payload)?;
 
-   let headers = [("Content-Type", "application/json")];
+   let authorization = vapid.authorization(&device.up_endpoint)?;
 
-   client.post(endpoint, &headers, &body).await?;
+   // Devices that registered Web Push keys get an encrypted payload, so the
+   // push server and distributor can't read it
+   if let Some(keys) = &device.push_keys {
+      let keys = ClientKeys::from_base64(&keys.p256dh, &keys.auth)?;
+      let body = web_push::encrypt(&keys, &body)?;
+
+      let headers = [
+         ("Content-Type", "application/octet-stream"),
+         ("Content-Encoding", "aes128gcm"),
+         ("TTL", "86400"),
+         ("Authorization", &authorization),
+      ];
+
+      client.post(&device.up_endpoint, &headers, &body).await?;
+   } else {
+      let headers = [
+         ("Content-Type", "application/json"),
+         ("Authorization", &authorization),
+      ];
+
+      client.post(&device.up_endpoint, &headers, &body).await?;
+   }
 
    Ok(())
 }
+
+/// Serialize the payload, shortening the tweet text and then leaving out
+/// actors until it fits in a single push message
+fn serialize_within_limit(payload: &mut UpPayload) -> Result<Vec<u8>, UpError> {
+   loop {
+      let body = serde_json::to_vec(payload).map_err(|e| UpError::Serialize(e.to_string()))?;
+      if body.len() <= web_push::MAX_PLAINTEXT_LEN {
+         return Ok(body);
+      }
+
+      let data = &mut payload.data;
+      let text_chars = data
+         .tweet_text
+         .as_ref()
+         .map_or(0, |text| text.chars().count());
+
+      if text_chars > MIN_TWEET_TEXT_CHARS {
+         let text = data.tweet_text.as_mut().expect("text has characters");
+         *text = text.chars().take(text_chars / 2).chain(['…']).collect();
+      } else if data.actors.len() > 1 {
+         data.actors.pop();
+      } else if data.tweet_text.take().is_none() {
+         // Nothing optional left, so let sending report the size
+         return Ok(body);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use hyper::StatusCode;
+
+   use super::*;
+
+   fn actor(name: &str) -> Actor {
+      Actor {
+         name:            name.to_string(),
+         screen_name:     "someone".to_string(),
+         avatar_url:      None,
+         followers_count: None,
+         verified:        false,
+      }
+   }
+
+   fn payload(tweet_text: Option<String>, actors: Vec<Actor>) -> UpPayload {
+      UpPayload {
+         title:    "Someone liked your post".to_string(),
+         message:  "Hello".to_string(),
+         priority: 3,
+         data:     UpData {
+            url: None,
+            notification_type: "like".to_string(),
+            sort_index: "100".to_string(),
+            actors,
+            tweet_text,
+            image_url: None,
+         },
+      }
+   }
+
+   #[test]
+   fn small_payloads_are_left_alone() {
+      let mut small = payload(Some("Hello".to_string()), vec![actor("Alice")]);
+
+      let body = serialize_within_limit(&mut small).unwrap();
+      assert_eq!(body, serde_json::to_vec(&small).unwrap());
+      assert_eq!(small.data.tweet_text.as_deref(), Some("Hello"));
+   }
+
+   #[test]
+   fn long_tweet_text_is_halved() {
+      // Multibyte characters, which are never split
+      let text = "é".repeat(4000);
+      let mut long = payload(Some(text), vec![actor("Alice"), actor("Bob")]);
+
+      let body = serialize_within_limit(&mut long).unwrap();
+      assert!(body.len() <= web_push::MAX_PLAINTEXT_LEN);
+      assert_eq!(body, serde_json::to_vec(&long).unwrap());
+
+      let text = long.data.tweet_text.unwrap();
+      assert_eq!(text, format!("{}…", "é".repeat(1000)));
+      assert_eq!(long.data.actors.len(), 2);
+   }
+
+   #[test]
+   fn actors_are_left_out_after_the_text() {
+      let name = "A".repeat(1000);
+      let actors = vec![actor(&name); MAX_ACTORS];
+      let mut many = payload(Some("x".repeat(150)), actors);
+
+      let body = serialize_within_limit(&mut many).unwrap();
+      assert!(body.len() <= web_push::MAX_PLAINTEXT_LEN);
+      assert_eq!(
+         many.data.tweet_text.unwrap(),
+         format!("{}…", "x".repeat(75))
+      );
+      assert_eq!(many.data.actors.len(), 3);
+   }
+
+   #[test]
+   fn oversized_payloads_lose_the_text_last() {
+      let mut huge = payload(Some("Hello".to_string()), vec![actor(&"A".repeat(5000))]);
+
+      // Sending reports what still doesn't fit
+      let body = serialize_within_limit(&mut huge).unwrap();
+      assert!(body.len() > web_push::MAX_PLAINTEXT_LEN);
+      assert_eq!(huge.data.tweet_text, None);
+      assert_eq!(huge.data.actors.len(), 1);
+   }
+
+   #[test]
+   fn gone_endpoints_are_permanent() {
+      let status = |code| UpError::Http(HttpError::Status(code, String::new()));
+
+      assert!(status(StatusCode::NOT_FOUND).is_permanent());
+      assert!(status(StatusCode::GONE).is_permanent());
+      assert!(!status(StatusCode::INTERNAL_SERVER_ERROR).is_permanent());
+      assert!(!status(StatusCode::TOO_MANY_REQUESTS).is_permanent());
+      assert!(!status(StatusCode::FORBIDDEN).is_permanent());
+      assert!(!UpError::Http(HttpError::Request("timed out".to_string())).is_permanent());
+   }
+}
@@ -0,0 +1,237 @@
+use std::time::{
+   Duration,
+   SystemTime,
+   UNIX_EPOCH,
+};
+
+use data_encoding::BASE64URL_NOPAD;
+use hyper::Uri;
+use ring::{
+   rand::SystemRandom,
+   signature::{
+      ECDSA_P256_SHA256_FIXED_SIGNING,
+      EcdsaKeyPair,
+      KeyPair,
+   },
+};
+use serde::Serialize;
+
+use crate::crypto::CryptoError;
+
+/// How long a signed token stays valid; RFC 8292 caps this at 24 hours
+const TOKEN_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Name of the VAPID key in the server keys table
+pub const KEY_NAME: &str = "vapid";
+
+#[derive(Serialize)]
+struct Claims<'a> {
+   aud: &'a str,
+   exp: u64,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   sub: Option<&'a str>,
+}
+
+/// Voluntary application server identification (RFC 8292) for push requests
+pub struct Vapid {
+   key_pair: EcdsaKeyPair,
+   subject:  Option<String>,
+   rng:      SystemRandom,
+}
+
+impl Vapid {
+   /// Generate a new P-256 key pair, PKCS#8 encoded
+   pub fn generate_pkcs8() -> Result<Vec<u8>, CryptoError> {
+      let rng = SystemRandom::new();
+      let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+         .map_err(|_| CryptoError::Random)?;
+      Ok(pkcs8.as_ref().to_vec())
+   }
+
+   /// `subject` is the contact URI (`mailto:` or `https:`) push services can
+   /// use to reach the operator
+   pub fn new(pkcs8: &[u8], subject: Option<String>) -> Result<Self, CryptoError> {
+      let rng = SystemRandom::new();
+      let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+         .map_err(|e| CryptoError::Key(format!("invalid VAPID key: {e}")))?;
+
+      Ok(Self {
+         key_pair,
+         subject,
+         rng,
+      })
+   }
+
+   /// The uncompressed public key, base64url encoded, as clients pass it to
+   /// `applicationServerKey`
+   pub fn public_key(&self) -> String {
+      BASE64URL_NOPAD.encode(self.key_pair.public_key().as_ref())
+   }
+
+   /// Build the `Authorization` header value for a request to `endpoint`
+   pub fn authorization(&self, endpoint: &str) -> Result<String, CryptoError> {
+      let exp = (SystemTime::now() + TOKEN_LIFETIME)
+         .duration_since(UNIX_EPOCH)
+         .map_or(0, |d| d.as_secs());
+
+      let token = self.sign(&audience(endpoint)?, exp)?;
+
+      Ok(format!("vapid t={token},k={}", self.public_key()))
+   }
+
+   /// Create an ES256 JWT for the push service at `aud`
+   fn sign(&self, aud: &str, exp: u64) -> Result<String, CryptoError> {
+      let header = BASE64URL_NOPAD.encode(br#"{"typ":"JWT","alg":"ES256"}"#);
+
+      let claims = Claims {
+         aud,
+         exp,
+         sub: self.subject.as_deref(),
+      };
+      let claims = serde_json::to_vec(&claims).map_err(|e| CryptoError::Format(e.to_string()))?;
+
+      let signing_input = format!("{header}.{}", BASE64URL_NOPAD.encode(&claims));
+
+      // The fixed encoding is the raw `r || s` that JWS expects
+      let signature = self
+         .key_pair
+         .sign(&self.rng, signing_input.as_bytes())
+         .map_err(|_| CryptoError::Encrypt)?;
+
+      Ok(format!(
+         "{signing_input}.{}",
+         BASE64URL_NOPAD.encode(signature.as_ref())
+      ))
+   }
+}
+
+/// The origin of a push endpoint, which is the JWT audience
+fn audience(endpoint: &str) -> Result<String, CryptoError> {
+   let uri: Uri = endpoint
+      .parse()
+      .map_err(|e| CryptoError::Format(format!("invalid endpoint: {e}")))?;
+
+   match (uri.scheme_str(), uri.authority()) {
+      (Some(scheme), Some(authority)) => Ok(format!("{scheme}://{authority}")),
+      _ => Err(CryptoError::Format("endpoint has no origin".to_string())),
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use data_encoding::BASE64;
+   use ring::signature::{
+      ECDSA_P256_SHA256_FIXED,
+      UnparsedPublicKey,
+   };
+
+   use super::*;
+
+   /// A P-256 key in PKCS#8, as stored in the server keys table
+   const PKCS8: &str = "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgXeEXUtIaFJgNpmOuEOIou9f3E+HeMcvcfyyUlberJDWhRANCAARXMCqN3E8lMvdWKSz0xxD2wCBv64qTvCayVbrFbA7VNVOutrJj3Gtc4P1QeVEoZ9gDHZhi5GBkq4TC0Cy9nYIb";
+
+   fn vapid(subject: Option<&str>) -> Vapid {
+      let pkcs8 = BASE64.decode(PKCS8.as_bytes()).unwrap();
+      Vapid::new(&pkcs8, subject.map(str::to_string)).unwrap()
+   }
+
+   /// Check the signature of a JWT and return its decoded header and claims
+   fn verify(vapid: &Vapid, token: &str) -> (String, String) {
+      let parts: Vec<&str> = token.split('.').collect();
+      assert_eq!(parts.len(), 3);
+
+      let public_key = BASE64URL_NOPAD
+         .decode(vapid.public_key().as_bytes())
+         .unwrap();
+      let signature = BASE64URL_NOPAD.decode(parts[2].as_bytes()).unwrap();
+      UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+         .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)
+         .unwrap();
+
+      let decode =
+         |part: &str| String::from_utf8(BASE64URL_NOPAD.decode(part.as_bytes()).unwrap()).unwrap();
+      (decode(parts[0]), decode(parts[1]))
+   }
+
+   #[test]
+   fn public_key() {
+      assert_eq!(
+         vapid(None).public_key(),
+         "BFcwKo3cTyUy91YpLPTHEPbAIG_ripO8JrJVusVsDtU1U662smPca1zg_VB5UShn2AMdmGLkYGSrhMLQLL2dghs"
+      );
+   }
+
+   #[test]
+   fn sign() {
+      let vapid = vapid(Some("mailto:admin@example.com"));
+      let token = vapid.sign("https://push.example.net", 1700000000).unwrap();
+
+      let (header, claims) = verify(&vapid, &token);
+      assert_eq!(header, r#"{"typ":"JWT","alg":"ES256"}"#);
+      assert_eq!(
+         claims,
+         r#"{"aud":"https://push.example.net","exp":1700000000,"sub":"mailto:admin@example.com"}"#
+      );
+   }
+
+   #[test]
+   fn sign_without_subject() {
+      let vapid = vapid(None);
+      let token = vapid.sign("https://push.example.net", 1700000000).unwrap();
+
+      let (_, claims) = verify(&vapid, &token);
+      assert_eq!(
+         claims,
+         r#"{"aud":"https://push.example.net","exp":1700000000}"#
+      );
+   }
+
+   #[test]
+   fn authorization() {
+      let vapid = vapid(Some("mailto:admin@example.com"));
+      let value = vapid
+         .authorization("https://push.example.net/wpush/v2/abc?x=1")
+         .unwrap();
+
+      let (token, key) = value
+         .strip_prefix("vapid t=")
+         .and_then(|rest| rest.split_once(",k="))
+         .unwrap();
+      assert_eq!(key, vapid.public_key());
+
+      let (_, claims) = verify(&vapid, token);
+      let claims: serde_json::Value = serde_json::from_str(&claims).unwrap();
+      assert_eq!(claims["aud"], "https://push.example.net");
+
+      // Expires within the lifetime allowed by RFC 8292
+      let now = SystemTime::now()
+         .duration_since(UNIX_EPOCH)
+         .unwrap()
+         .as_secs();
+      let exp = claims["exp"].as_u64().unwrap();
+      assert!(exp > now && exp <= now + 24 * 60 * 60);
+   }
+
+   #[test]
+   fn audience_is_the_origin() {
+      assert_eq!(
+         audience("https://push.example.net/wpush/v2/abc").unwrap(),
+         "https://push.example.net"
+      );
+      assert_eq!(
+         audience("https://push.example.net:8443/up?token=abc").unwrap(),
+         "https://push.example.net:8443"
+      );
+      assert_eq!(
+         audience("https://push.example.net/up/host:443/x?y=1#z").unwrap(),
+         "https://push.example.net"
+      );
+      assert_eq!(
+         audience("http://10.0.0.1:8080").unwrap(),
+         "http://10.0.0.1:8080"
+      );
+
+      assert!(audience("/relative/path").is_err());
+      assert!(audience("not a url").is_err());
+   }
+}
@@ -0,0 +1,263 @@
+use data_encoding::BASE64URL_NOPAD;
+use ring::{
+   aead::{
+      AES_128_GCM,
+      Aad,
+      LessSafeKey,
+      Nonce,
+      UnboundKey,
+   },
+   agreement::{
+      self,
+      ECDH_P256,
+      EphemeralPrivateKey,
+      UnparsedPublicKey,
+   },
+   hkdf::{
+      self,
+      HKDF_SHA256,
+   },
+   rand::SystemRandom,
+};
+
+use crate::crypto::{
+   CryptoError,
+   random_bytes,
+};
+
+const PUBLIC_KEY_LEN: usize = 65;
+const AUTH_SECRET_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+/// Size of the single record we send. Push services commonly cap the whole
+/// request body at 4096 bytes, so the record has to fit alongside the header.
+const RECORD_SIZE: u32 = 4096;
+const HEADER_LEN: usize = SALT_LEN + 4 + 1 + PUBLIC_KEY_LEN;
+pub const MAX_PLAINTEXT_LEN: usize = RECORD_SIZE as usize - HEADER_LEN - TAG_LEN - 1;
+
+/// A subscription's client keys, as sent by the user agent
+pub struct ClientKeys {
+   /// Uncompressed P-256 public key
+   p256dh: [u8; PUBLIC_KEY_LEN],
+   auth:   [u8; AUTH_SECRET_LEN],
+}
+
+impl ClientKeys {
+   /// Decode the base64url `p256dh` and `auth` values, with or without padding
+   pub fn from_base64(p256dh: &str, auth: &str) -> Result<Self, CryptoError> {
+      let decode = |value: &str, name: &str| {
+         BASE64URL_NOPAD
+            .decode(value.trim_end_matches('=').as_bytes())
+            .map_err(|e| CryptoError::Key(format!("invalid {name}: {e}")))
+      };
+
+      let p256dh: [u8; PUBLIC_KEY_LEN] = decode(p256dh, "p256dh")?
+         .try_into()
+         .map_err(|_| CryptoError::Key(format!("p256dh must be {PUBLIC_KEY_LEN} bytes")))?;
+      let auth: [u8; AUTH_SECRET_LEN] = decode(auth, "auth")?
+         .try_into()
+         .map_err(|_| CryptoError::Key(format!("auth must be {AUTH_SECRET_LEN} bytes")))?;
+
+      if p256dh[0] != 0x04 {
+         return Err(CryptoError::Key(
+            "p256dh must be an uncompressed P-256 point".to_string(),
+         ));
+      }
+
+      Ok(Self { p256dh, auth })
+   }
+}
+
+/// Encrypt a push message body for the given subscription keys as described
+/// in RFC 8291, using the `aes128gcm` content coding from RFC 8188
+pub fn encrypt(keys: &ClientKeys, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+   let rng = SystemRandom::new();
+
+   let as_private =
+      EphemeralPrivateKey::generate(&ECDH_P256, &rng).map_err(|_| CryptoError::Random)?;
+   let as_public: [u8; PUBLIC_KEY_LEN] = as_private
+      .compute_public_key()
+      .map_err(|_| CryptoError::Encrypt)?
+      .as_ref()
+      .try_into()
+      .map_err(|_| CryptoError::Encrypt)?;
+
+   let ecdh_secret = agreement::agree_ephemeral(
+      as_private,
+      &UnparsedPublicKey::new(&ECDH_P256, &keys.p256dh),
+      |secret| secret.to_vec(),
+   )
+   .map_err(|_| CryptoError::Key("p256dh is not a valid P-256 point".to_string()))?;
+
+   let salt = random_bytes::<SALT_LEN>()?;
+
+   encrypt_with(keys, &as_public, &ecdh_secret, &salt, plaintext)
+}
+
+/// The deterministic part of [`encrypt`], given the application server's
+/// ephemeral public key, the ECDH shared secret and a salt
+fn encrypt_with(
+   keys: &ClientKeys,
+   as_public: &[u8; PUBLIC_KEY_LEN],
+   ecdh_secret: &[u8],
+   salt: &[u8; SALT_LEN],
+   plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+   if plaintext.len() > MAX_PLAINTEXT_LEN {
+      return Err(CryptoError::Format(format!(
+         "payload of {} bytes exceeds the {MAX_PLAINTEXT_LEN} byte limit",
+         plaintext.len()
+      )));
+   }
+
+   // Combine the ECDH secret with the auth secret (RFC 8291 section 3.3)
+   let mut key_info = Vec::with_capacity(14 + 2 * PUBLIC_KEY_LEN);
+   key_info.extend_from_slice(b"WebPush: info\0");
+   key_info.extend_from_slice(&keys.p256dh);
+   key_info.extend_from_slice(as_public);
+
+   let prk_key = hkdf::Salt::new(HKDF_SHA256, &keys.auth).extract(ecdh_secret);
+   let ikm = expand::<32>(&prk_key, &key_info)?;
+
+   // Derive the content encryption key and nonce (RFC 8188 section 2.2)
+   let prk = hkdf::Salt::new(HKDF_SHA256, salt).extract(&ikm);
+   let cek = expand::<16>(&prk, b"Content-Encoding: aes128gcm\0")?;
+   let nonce = expand::<12>(&prk, b"Content-Encoding: nonce\0")?;
+
+   // A single record, so the sequence number is zero and the nonce is used as
+   // is. The 0x02 delimiter marks it as the last record.
+   let mut record = Vec::with_capacity(plaintext.len() + 1 + TAG_LEN);
+   record.extend_from_slice(plaintext);
+   record.push(0x02);
+
+   let key = UnboundKey::new(&AES_128_GCM, &cek).map_err(|_| CryptoError::Encrypt)?;
+   LessSafeKey::new(key)
+      .seal_in_place_append_tag(
+         Nonce::assume_unique_for_key(nonce),
+         Aad::empty(),
+         &mut record,
+      )
+      .map_err(|_| CryptoError::Encrypt)?;
+
+   // Header: salt, record size, key ID length and the key ID, which carries
+   // our ephemeral public key
+   let mut body = Vec::with_capacity(HEADER_LEN + record.len());
+   body.extend_from_slice(salt);
+   body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+   body.push(PUBLIC_KEY_LEN as u8);
+   body.extend_from_slice(as_public);
+   body.extend_from_slice(&record);
+
+   Ok(body)
+}
+
+struct OkmLen(usize);
+
+impl hkdf::KeyType for OkmLen {
+   fn len(&self) -> usize {
+      self.0
+   }
+}
+
+fn expand<const N: usize>(prk: &hkdf::Prk, info: &[u8]) -> Result<[u8; N], CryptoError> {
+   let mut out = [0u8; N];
+   prk.expand(&[info], OkmLen(N))
+      .and_then(|okm| okm.fill(&mut out))
+      .map_err(|_| CryptoError::Encrypt)?;
+   Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn decode(value: &str) -> Vec<u8> {
+      BASE64URL_NOPAD.decode(value.as_bytes()).unwrap()
+   }
+
+   /// The example from RFC 8291 section 5
+   #[test]
+   fn rfc8291_example() {
+      let keys = ClientKeys::from_base64(
+         "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+         "BTBZMqHH6r4Tts7J_aSIgg",
+      )
+      .unwrap();
+      let as_public = decode(
+         "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8",
+      );
+      let ecdh_secret = decode("kyrL1jIIOHEzg3sM2ZWRHDRB62YACZhhSlknJ672kSs");
+      let salt = decode("DGv6ra1nlYgDCS1FRnbzlw");
+
+      let body = encrypt_with(
+         &keys,
+         &as_public.try_into().unwrap(),
+         &ecdh_secret,
+         &salt.try_into().unwrap(),
+         b"When I grow up, I want to be a watermelon",
+      )
+      .unwrap();
+
+      assert_eq!(
+         BASE64URL_NOPAD.encode(&body),
+         "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+      );
+   }
+
+   #[test]
+   fn rejects_oversized_payloads() {
+      let keys = ClientKeys {
+         p256dh: [4; PUBLIC_KEY_LEN],
+         auth:   [0; AUTH_SECRET_LEN],
+      };
+      let plaintext = vec![0; MAX_PLAINTEXT_LEN + 1];
+
+      let result = encrypt_with(
+         &keys,
+         &[4; PUBLIC_KEY_LEN],
+         &[0; 32],
+         &[0; SALT_LEN],
+         &plaintext,
+      );
+      assert!(matches!(result, Err(CryptoError::Format(_))));
+
+      let body = encrypt_with(
+         &keys,
+         &[4; PUBLIC_KEY_LEN],
+         &[0; 32],
+         &[0; SALT_LEN],
+         &plaintext[1..],
+      )
+      .unwrap();
+      assert_eq!(body.len(), RECORD_SIZE as usize);
+   }
+
+   #[test]
+   fn client_keys_from_base64() {
+      let p256dh =
+         "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4";
+      let auth = "BTBZMqHH6r4Tts7J_aSIgg";
+
+      // Padding is tolerated
+      assert!(ClientKeys::from_base64(&format!("{p256dh}="), &format!("{auth}==")).is_ok());
+
+      // Wrong lengths
+      assert!(ClientKeys::from_base64(&p256dh[..86], auth).is_err());
+      assert!(ClientKeys::from_base64(&format!("{p256dh}AAAA"), auth).is_err());
+      assert!(ClientKeys::from_base64(p256dh, &auth[..20]).is_err());
+      assert!(ClientKeys::from_base64(p256dh, &format!("{auth}AAAA")).is_err());
+
+      // The same point in compressed form: the prefix and the X coordinate
+      let mut compressed = decode(p256dh)[..33].to_vec();
+      compressed[0] = 0x02;
+      assert!(ClientKeys::from_base64(&BASE64URL_NOPAD.encode(&compressed), auth).is_err());
+
+      // An uncompressed-length value with a compressed prefix
+      let mut prefixed = decode(p256dh);
+      prefixed[0] = 0x03;
+      assert!(ClientKeys::from_base64(&BASE64URL_NOPAD.encode(&prefixed), auth).is_err());
+
+      assert!(ClientKeys::from_base64("not base64!", auth).is_err());
+   }
+}
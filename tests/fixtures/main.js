@@ -0,0 +1 @@
+(self.webpackChunk_twitter_responsive_web=self.webpackChunk_twitter_responsive_web||[]).push([["main"],{12345:e=>{e.exports={queryId:"Y-4nWuqrAwaEDpHtfJmK5A",operationName:"NotificationsTimeline",operationType:"query",metadata:{featureSwitches:["rweb_video_screen_enabled","responsive_web_graphql_timeline_navigation_enabled","longform_notetweets_consumption_enabled","creator_subscriptions_tweet_preview_api_enabled"],fieldToggles:["withArticleRichContentState"]}}},23456:e=>{e.exports={queryId:"lQ3aN5nQc7oVyTe3Ekh0BQ",operationName:"CreateTweet",operationType:"mutation",metadata:{featureSwitches:[],fieldToggles:[]}}},34567:e=>{e.exports={queryId:"xJjzOyyjUN0Kfu9TzCq2Hw",operationName:"UserByScreenName",operationType:"query",metadata:{featureSwitches:["hidden_profile_subscriptions_enabled"]}}},45678:e=>{e.exports={queryId:"",operationName:"Broken",operationType:"query",metadata:{featureSwitches:[]}}},56789:e=>{e.exports={queryId:"AbC123",operationType:"query"}}}]);